@@ -0,0 +1,360 @@
+//! BLAKE3 digests and Bao outboards, for downloads verified as they stream.
+//!
+//! Every blob gets a BLAKE3 digest next to its sha256, hashed by the stager
+//! in the same pass, and a Bao outboard: the inside of the BLAKE3 tree over
+//! its 1 KiB chunks (the length as 8 little-endian bytes, then each parent
+//! node's two chaining values in pre-order). The outboard is stored under
+//! `bao/<sha256>`, sealed under the blob's data key when the blob is
+//! encrypted. From the outboard and the content the server streams Bao's
+//! combined encoding ([`DocStore::stream_bao`]), which any Bao decoder, or
+//! [`BaoDecoder`], checks against the BLAKE3 digest chunk by chunk.
+//! [`DocStore::backfill`] adds both to blobs stored before they existed.
+
+use std::io::{self, BufReader, Read};
+
+use anyhow::{Context, Result};
+use blake3::hazmat::{self, ChainingValue, HasherExt, Mode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{crypt, BlobMeta, BlobReader, DocMeta, DocStore, Hash32, StoreInner, WrappedKey};
+
+/// BLAKE3 chunk size, the unit Bao verifies.
+pub const BAO_CHUNK_BYTES: u64 = 1024;
+const PARENT_BYTES: u64 = 64;
+const HEADER_BYTES: u64 = 8;
+const BAO_PREFIX: &str = "bao/";
+
+/// Blob key of a blob's outboard.
+pub fn bao_key(sha256: &Hash32) -> String {
+    format!("{BAO_PREFIX}{}", hex::encode(sha256))
+}
+
+/// Blob whose outboard a blob key names, if it is one.
+pub(crate) fn key_bao(key: &str) -> Option<Hash32> {
+    hex::decode(key.strip_prefix(BAO_PREFIX)?).ok()?.try_into().ok()
+}
+
+/// Size of the outboard of `size` bytes.
+pub fn outboard_len(size: u64) -> u64 {
+    HEADER_BYTES + PARENT_BYTES * (size.div_ceil(BAO_CHUNK_BYTES).max(1) - 1)
+}
+
+/// Size of the combined encoding of `size` bytes: the content with the
+/// outboard's nodes interleaved.
+pub fn encoded_len(size: u64) -> u64 {
+    size + outboard_len(size)
+}
+
+/// Outboard of the `size` bytes read from `reader`, and their BLAKE3 digest.
+pub(crate) fn outboard(reader: impl Read, size: u64) -> io::Result<(Hash32, Vec<u8>)> {
+    let mut reader = BufReader::new(reader);
+    let mut out = Vec::with_capacity(outboard_len(size) as usize);
+    out.extend_from_slice(&size.to_le_bytes());
+    let mut buf = [0u8; BAO_CHUNK_BYTES as usize];
+    let hash = if size <= BAO_CHUNK_BYTES {
+        let chunk = &mut buf[..size as usize];
+        reader.read_exact(chunk)?;
+        blake3::hash(chunk)
+    } else {
+        let (left, right) = parent(&mut reader, 0, size, &mut out, &mut buf)?;
+        hazmat::merge_subtrees_root(&left, &right, Mode::Hash)
+    };
+    Ok((*hash.as_bytes(), out))
+}
+
+/// Write the parent node over the `len > 1 KiB` bytes at `offset`, then
+/// the nodes below it. Returns its children.
+fn parent<R: Read>(
+    reader: &mut R,
+    offset: u64,
+    len: u64,
+    out: &mut Vec<u8>,
+    buf: &mut [u8; BAO_CHUNK_BYTES as usize],
+) -> io::Result<(ChainingValue, ChainingValue)> {
+    let at = out.len();
+    out.extend_from_slice(&[0; PARENT_BYTES as usize]);
+    let left_len = hazmat::left_subtree_len(len);
+    let left = subtree(reader, offset, left_len, out, buf)?;
+    let right = subtree(reader, offset + left_len, len - left_len, out, buf)?;
+    out[at..at + 32].copy_from_slice(&left);
+    out[at + 32..at + 64].copy_from_slice(&right);
+    Ok((left, right))
+}
+
+fn subtree<R: Read>(
+    reader: &mut R,
+    offset: u64,
+    len: u64,
+    out: &mut Vec<u8>,
+    buf: &mut [u8; BAO_CHUNK_BYTES as usize],
+) -> io::Result<ChainingValue> {
+    if len <= BAO_CHUNK_BYTES {
+        let chunk = &mut buf[..len as usize];
+        reader.read_exact(chunk)?;
+        return Ok(blake3::Hasher::new().set_input_offset(offset).update(chunk).finalize_non_root());
+    }
+    let (left, right) = parent(reader, offset, len, out, buf)?;
+    Ok(hazmat::merge_subtrees_non_root(&left, &right, Mode::Hash))
+}
+
+/// Bao's combined encoding of a blob, built from its outboard and content:
+/// the length, then the tree in pre-order with each chunk in place of its
+/// leaf.
+struct BaoEncoder<O, R> {
+    outboard: O,
+    content: R,
+    /// Lengths of the subtrees still to emit, the next one last.
+    pending: Vec<u64>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<O: Read, R: Read> BaoEncoder<O, R> {
+    fn new(mut outboard: O, content: R, size: u64) -> io::Result<Self> {
+        let mut header = [0u8; HEADER_BYTES as usize];
+        outboard.read_exact(&mut header)?;
+        if u64::from_le_bytes(header) != size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "outboard is for a different length"));
+        }
+        Ok(Self { outboard, content, pending: vec![size], out: header.to_vec(), pos: 0 })
+    }
+
+    fn next_piece(&mut self) -> io::Result<bool> {
+        let Some(len) = self.pending.pop() else { return Ok(false) };
+        if len <= BAO_CHUNK_BYTES {
+            self.out.resize(len as usize, 0);
+            self.content.read_exact(&mut self.out)?;
+        } else {
+            self.out.resize(PARENT_BYTES as usize, 0);
+            self.outboard.read_exact(&mut self.out)?;
+            let left = hazmat::left_subtree_len(len);
+            self.pending.extend([len - left, left]);
+        }
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<O: Read, R: Read> Read for BaoEncoder<O, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if !self.next_piece()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Reads a combined encoding, yielding the content only once each chunk
+/// and every node above it check out against the BLAKE3 digest. Anything
+/// else fails with [`io::ErrorKind::InvalidData`].
+pub struct BaoDecoder<R> {
+    inner: R,
+    /// Subtrees still to read as (length, expected chaining value, is root),
+    /// the next one last. Empty until the length is read.
+    pending: Vec<(u64, Hash32, bool)>,
+    hash: Hash32,
+    started: bool,
+    offset: u64,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> BaoDecoder<R> {
+    pub fn new(inner: R, blake3: &Hash32) -> Self {
+        Self { inner, pending: Vec::new(), hash: *blake3, started: false, offset: 0, out: Vec::new(), pos: 0 }
+    }
+
+    fn next_piece(&mut self) -> io::Result<bool> {
+        if !self.started {
+            let mut header = [0u8; HEADER_BYTES as usize];
+            self.inner.read_exact(&mut header)?;
+            self.pending.push((u64::from_le_bytes(header), self.hash, true));
+            self.started = true;
+        }
+        let Some((len, expected, root)) = self.pending.pop() else { return Ok(false) };
+        let bad = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{what} does not match the BLAKE3 tree"));
+        if len <= BAO_CHUNK_BYTES {
+            self.out.resize(len as usize, 0);
+            self.inner.read_exact(&mut self.out)?;
+            let actual = match root {
+                true => *blake3::hash(&self.out).as_bytes(),
+                false => blake3::Hasher::new().set_input_offset(self.offset).update(&self.out).finalize_non_root(),
+            };
+            if actual != expected {
+                return Err(bad(&format!("chunk at {}", self.offset)));
+            }
+            self.offset += len;
+            self.pos = 0;
+            return Ok(true);
+        }
+        let mut node = [0u8; PARENT_BYTES as usize];
+        self.inner.read_exact(&mut node)?;
+        let (left, right): (ChainingValue, ChainingValue) = (node[..32].try_into().unwrap(), node[32..].try_into().unwrap());
+        let actual = match root {
+            true => *hazmat::merge_subtrees_root(&left, &right, Mode::Hash).as_bytes(),
+            false => hazmat::merge_subtrees_non_root(&left, &right, Mode::Hash),
+        };
+        if actual != expected {
+            return Err(bad(&format!("node above {}", self.offset)));
+        }
+        let left_len = hazmat::left_subtree_len(len);
+        self.pending.extend([(len - left_len, right, false), (left_len, left, false)]);
+        self.out.clear();
+        self.pos = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for BaoDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if !self.next_piece()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Passes bytes through while hashing them with sha256.
+struct Sha256Reader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for Sha256Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Outcome of [`DocStore::backfill`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct BackfillReport {
+    pub blobs_checked: usize,
+    /// Blobs given a BLAKE3 digest and an outboard.
+    pub backfilled: usize,
+    pub errors: Vec<String>,
+}
+
+impl StoreInner {
+    /// Store a blob's outboard, sealed under its data key if it has one.
+    pub(crate) fn put_outboard(&self, sha256: &Hash32, encryption: Option<&WrappedKey>, outboard: &[u8]) -> Result<()> {
+        let key = bao_key(sha256);
+        match encryption {
+            Some(wrapped) => {
+                let dek = self.data_key(sha256, wrapped)?;
+                self.put_sealed_at(&key, outboard, outboard.len() as u64, &dek)
+            }
+            None => {
+                self.blobs.put(&key, &mut &outboard[..])?;
+                Ok(())
+            }
+        }
+    }
+
+    /// A blob's outboard as stored, decrypted. `None` if it has none.
+    fn open_outboard(&self, blob: &BlobMeta) -> Result<Option<Box<dyn Read + Send>>> {
+        let key = bao_key(&blob.sha256);
+        match &blob.encoding.encryption {
+            Some(wrapped) => {
+                let dek = self.data_key(&blob.sha256, wrapped)?;
+                self.open_sealed_at(&key, &dek, outboard_len(blob.size_bytes))
+            }
+            None => self.blobs.stream(&key),
+        }
+    }
+
+    /// Bytes a blob's outboard takes up in the backend.
+    pub(crate) fn stored_outboard_len(blob: &BlobMeta) -> u64 {
+        let len = outboard_len(blob.size_bytes);
+        match blob.encoding.encryption {
+            Some(_) => crypt::sealed_len(len),
+            None => len,
+        }
+    }
+
+    /// Give a blob its BLAKE3 digest and outboard, reading its bytes once
+    /// and checking them against the sha256. With `force` the outboard is
+    /// rewritten even if the blob has one.
+    pub(crate) fn backfill_blob(&self, sha256: &Hash32, force: bool, report: &mut BackfillReport) -> Result<()> {
+        let _guard = self.lock_blob(sha256);
+        let Some(blob) = self.blob_meta(sha256)? else { return Ok(()) };
+        report.blobs_checked += 1;
+        if !force && blob.blake3.is_some() && self.blobs.exists(&bao_key(sha256))? {
+            return Ok(());
+        }
+        let sha_hex = hex::encode(sha256);
+        if blob.quarantined_at_unix_ms.is_some() {
+            anyhow::bail!("blob {sha_hex} is quarantined; run scrub first");
+        }
+        let content = self.open_blob(&blob)?.with_context(|| format!("blob {sha_hex} is missing"))?;
+        let mut reader = Sha256Reader { inner: content, hasher: Sha256::new() };
+        let (blake3, outboard) = outboard(&mut reader, blob.size_bytes)?;
+        if Hash32::from(reader.hasher.finalize()) != blob.sha256 {
+            anyhow::bail!("blob {sha_hex} no longer matches its sha256; run scrub first");
+        }
+        self.put_outboard(sha256, blob.encoding.encryption.as_ref(), &outboard)?;
+        self.blake3_index.insert(blake3, sha256)?;
+        self.update_blob(sha256, |b| b.blake3 = Some(blake3))?;
+        report.backfilled += 1;
+        Ok(())
+    }
+}
+
+impl DocStore {
+    /// Bao's combined encoding of a document's content, for a client to
+    /// decode against `meta.blake3`, verifying every chunk as it arrives.
+    /// [`encoded_len`] bytes long. `None` if the blob has no outboard yet,
+    /// see [`DocStore::backfill`].
+    pub async fn stream_bao(&self, meta: &DocMeta) -> Result<Option<BlobReader>> {
+        let (sha256, id_hex) = (meta.sha256, meta.id_hex.clone());
+        let reader = self
+            .blocking(move |s| {
+                let blob = s.blob_meta(&sha256)?.with_context(|| format!("blob for {id_hex} is not indexed"))?;
+                if blob.blake3.is_none() {
+                    return Ok(None);
+                }
+                let Some(outboard) = s.open_outboard(&blob)? else { return Ok(None) };
+                let content = s.open_blob(&blob)?.with_context(|| format!("blob for {id_hex} is missing"))?;
+                let encoder = BaoEncoder::new(outboard, content, blob.size_bytes)?;
+                Ok(Some(Box::new(encoder) as Box<dyn Read + Send>))
+            })
+            .await?;
+        Ok(reader.map(BlobReader::spawn))
+    }
+
+    /// Compute the BLAKE3 digest and Bao outboard of every blob stored
+    /// before they existed. Each blob is read once and checked against its
+    /// sha256; blobs that fail are listed in the report and left as they
+    /// were. Safe to run again.
+    pub async fn backfill(&self) -> Result<BackfillReport> {
+        self.blocking(|s| {
+            let mut report = BackfillReport::default();
+            let shas = s
+                .blob_index
+                .iter()
+                .keys()
+                .map(|k| Ok(k?.as_ref().try_into()?))
+                .collect::<Result<Vec<Hash32>>>()?;
+            for sha256 in shas {
+                if let Err(e) = s.backfill_blob(&sha256, false, &mut report) {
+                    report.errors.push(format!("{}: {e:#}", hex::encode(sha256)));
+                }
+            }
+            Ok(report)
+        })
+        .await
+    }
+}
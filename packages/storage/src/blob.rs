@@ -0,0 +1,646 @@
+//! Pluggable blob backends.
+//!
+//! `DocStore` keeps metadata in sled and hands the document bytes to a
+//! [`BlobStore`]. Keys are relative, `/`-separated names chosen by the store
+//! (e.g. the hex sha256); backends only move bytes around.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs,
+    io::{self, Cursor, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tempfile::NamedTempFile;
+
+/// Storage backend for content-addressed document bytes.
+pub trait BlobStore: Send + Sync + fmt::Debug {
+    /// Write everything from `reader` under `key`, replacing any existing blob.
+    /// Returns the number of bytes written.
+    fn put(&self, key: &str, reader: &mut dyn Read) -> Result<u64>;
+
+    /// Move a staged local file into the store under `key`.
+    /// Backends on the local filesystem can rename instead of copying.
+    fn put_file(&self, key: &str, file: NamedTempFile) -> Result<u64> {
+        let mut reader = file.reopen().context("reopening staged blob")?;
+        self.put(key, &mut reader)
+    }
+
+    /// Read a whole blob into memory.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let Some(mut reader) = self.stream(key)? else { return Ok(None) };
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        Ok(Some(out))
+    }
+
+    /// Open a blob for streaming reads.
+    fn stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>>;
+
+    /// Open `len` bytes of a blob starting at `offset` for streaming reads.
+    /// The default reads and discards everything before `offset`.
+    fn stream_range(&self, key: &str, offset: u64, len: u64) -> Result<Option<Box<dyn Read + Send>>> {
+        let Some(mut reader) = self.stream(key)? else { return Ok(None) };
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        Ok(Some(Box::new(reader.take(len))))
+    }
+
+    /// Remove a blob. Returns `false` if it did not exist.
+    fn delete(&self, key: &str) -> Result<bool>;
+
+    /// Check whether a blob exists.
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Every blob in the store with its size. Used by consistency checks.
+    fn list(&self) -> Result<Vec<BlobEntry>>;
+}
+
+/// Async reader over blob bytes that a blocking-pool task reads ahead a few
+/// chunks at a time, so downloads never hold a whole blob in memory. The
+/// task stops once the reader is dropped.
+pub struct BlobReader {
+    chunks: tokio::sync::mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl BlobReader {
+    /// Chunks read ahead of the consumer.
+    const READ_AHEAD: usize = 4;
+    const CHUNK_BYTES: usize = 64 * 1024;
+
+    /// Pump `reader` from tokio's blocking pool.
+    pub fn spawn(mut reader: Box<dyn Read + Send>) -> Self {
+        let (tx, chunks) = tokio::sync::mpsc::channel(Self::READ_AHEAD);
+        tokio::task::spawn_blocking(move || loop {
+            let mut buf = vec![0u8; Self::CHUNK_BYTES];
+            let chunk = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Ok(buf)
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if tx.blocking_send(chunk).is_err() || failed {
+                break;
+            }
+        });
+        Self { chunks, chunk: Vec::new(), pos: 0 }
+    }
+}
+
+impl fmt::Debug for BlobReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlobReader").finish_non_exhaustive()
+    }
+}
+
+impl tokio::io::AsyncRead for BlobReader {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        while self.pos == self.chunk.len() {
+            match std::task::ready!(self.chunks.poll_recv(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return std::task::Poll::Ready(Err(e)),
+                None => return std::task::Poll::Ready(Ok(())),
+            }
+        }
+        let n = buf.remaining().min(self.chunk.len() - self.pos);
+        buf.put_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+/// A stored blob as reported by [`BlobStore::list`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobEntry {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Reject keys that could escape the backend's namespace.
+fn check_key(key: &str) -> Result<()> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && key.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-'))
+        });
+    if !valid {
+        bail!("invalid blob key {key:?}");
+    }
+    Ok(())
+}
+
+/// Blobs stored as plain files below a directory.
+#[derive(Clone, Debug)]
+pub struct FsBlobStore {
+    dir: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Open (and create if needed) a blob directory.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).with_context(|| format!("creating {dir:?}"))?;
+        Ok(Self { dir })
+    }
+
+    /// Directory holding the blobs.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.dir.join(key))
+    }
+
+    fn parent_dir(&self, path: &Path) -> Result<PathBuf> {
+        let parent = path.parent().unwrap_or(&self.dir).to_path_buf();
+        fs::create_dir_all(&parent)?;
+        Ok(parent)
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, key: &str, reader: &mut dyn Read) -> Result<u64> {
+        let path = self.path(key)?;
+        let mut temp = NamedTempFile::new_in(self.parent_dir(&path)?)?;
+        let n = io::copy(reader, &mut temp)?;
+        temp.as_file().sync_all()?;
+        temp.persist(&path)?;
+        Ok(n)
+    }
+
+    fn put_file(&self, key: &str, file: NamedTempFile) -> Result<u64> {
+        let path = self.path(key)?;
+        self.parent_dir(&path)?;
+        let n = file.as_file().metadata()?.len();
+        match file.persist(&path) {
+            Ok(_) => Ok(n),
+            // Staging dir lives on another filesystem; fall back to a copy.
+            Err(e) => self.put(key, &mut e.file.reopen()?),
+        }
+    }
+
+    fn stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        match fs::File::open(self.path(key)?) {
+            Ok(f) => Ok(Some(Box::new(f))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("opening blob {key}")),
+        }
+    }
+
+    fn stream_range(&self, key: &str, offset: u64, len: u64) -> Result<Option<Box<dyn Read + Send>>> {
+        let mut file = match fs::File::open(self.path(key)?) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("opening blob {key}")),
+        };
+        io::Seek::seek(&mut file, io::SeekFrom::Start(offset))?;
+        Ok(Some(Box::new(file.take(len))))
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("removing blob {key}")),
+        }
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key)?.is_file())
+    }
+
+    fn list(&self) -> Result<Vec<BlobEntry>> {
+        let mut out = Vec::new();
+        let mut dirs = vec![self.dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).with_context(|| format!("reading {dir:?}"))? {
+                let entry = entry?;
+                // skip in-flight `put` temp files
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                } else if let Ok(rel) = entry.path().strip_prefix(&self.dir) {
+                    let key = rel.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+                    out.push(BlobEntry { key, size: meta.len() });
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Blobs kept in process memory. Intended for tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemBlobStore {
+    blobs: Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>,
+}
+
+impl MemBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of blobs currently held.
+    pub fn len(&self) -> usize {
+        self.blobs.read().expect("blob map poisoned").len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl BlobStore for MemBlobStore {
+    fn put(&self, key: &str, reader: &mut dyn Read) -> Result<u64> {
+        check_key(key)?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let n = data.len() as u64;
+        self.blobs
+            .write()
+            .expect("blob map poisoned")
+            .insert(key.to_string(), Arc::new(data));
+        Ok(n)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        let blobs = self.blobs.read().expect("blob map poisoned");
+        Ok(blobs.get(key).map(|b| b.as_ref().clone()))
+    }
+
+    fn stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        check_key(key)?;
+        let blobs = self.blobs.read().expect("blob map poisoned");
+        Ok(blobs
+            .get(key)
+            .cloned()
+            .map(|b| Box::new(Cursor::new(ArcBytes(b))) as Box<dyn Read + Send>))
+    }
+
+    fn stream_range(&self, key: &str, offset: u64, len: u64) -> Result<Option<Box<dyn Read + Send>>> {
+        check_key(key)?;
+        let blobs = self.blobs.read().expect("blob map poisoned");
+        Ok(blobs.get(key).cloned().map(|b| {
+            let mut cursor = Cursor::new(ArcBytes(b));
+            cursor.set_position(offset);
+            Box::new(cursor.take(len)) as Box<dyn Read + Send>
+        }))
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        check_key(key)?;
+        Ok(self.blobs.write().expect("blob map poisoned").remove(key).is_some())
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        check_key(key)?;
+        Ok(self.blobs.read().expect("blob map poisoned").contains_key(key))
+    }
+
+    fn list(&self) -> Result<Vec<BlobEntry>> {
+        let blobs = self.blobs.read().expect("blob map poisoned");
+        Ok(blobs
+            .iter()
+            .map(|(key, data)| BlobEntry { key: key.clone(), size: data.len() as u64 })
+            .collect())
+    }
+}
+
+/// Shared byte buffer usable with `Cursor` without copying.
+struct ArcBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for ArcBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Another backend seen through a key prefix, so several stores can share
+/// one bucket or directory without seeing each other's blobs.
+#[derive(Debug)]
+pub struct PrefixedBlobStore {
+    inner: Arc<dyn BlobStore>,
+    /// Ends in `/`.
+    prefix: String,
+}
+
+impl PrefixedBlobStore {
+    pub fn new(inner: Arc<dyn BlobStore>, prefix: &str) -> Result<Self> {
+        check_key(prefix)?;
+        Ok(Self { inner, prefix: format!("{prefix}/") })
+    }
+
+    fn key(&self, key: &str) -> Result<String> {
+        check_key(key)?;
+        Ok(format!("{}{key}", self.prefix))
+    }
+}
+
+impl BlobStore for PrefixedBlobStore {
+    fn put(&self, key: &str, reader: &mut dyn Read) -> Result<u64> {
+        self.inner.put(&self.key(key)?, reader)
+    }
+
+    fn put_file(&self, key: &str, file: NamedTempFile) -> Result<u64> {
+        self.inner.put_file(&self.key(key)?, file)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.inner.get(&self.key(key)?)
+    }
+
+    fn stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        self.inner.stream(&self.key(key)?)
+    }
+
+    fn stream_range(&self, key: &str, offset: u64, len: u64) -> Result<Option<Box<dyn Read + Send>>> {
+        self.inner.stream_range(&self.key(key)?, offset, len)
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        self.inner.delete(&self.key(key)?)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.inner.exists(&self.key(key)?)
+    }
+
+    fn list(&self) -> Result<Vec<BlobEntry>> {
+        Ok(self
+            .inner
+            .list()?
+            .into_iter()
+            .filter_map(|b| Some(BlobEntry { key: b.key.strip_prefix(&self.prefix)?.to_string(), size: b.size }))
+            .collect())
+    }
+}
+
+/// Connection settings for an S3-compatible object store (AWS, MinIO, ...).
+/// `Debug` leaves out the credentials.
+#[derive(Clone, PartialEq, Eq)]
+pub struct S3Config {
+    /// Endpoint URL, e.g. `http://127.0.0.1:9000` for a local MinIO.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    /// Prefix prepended to every key, e.g. `blobs/`.
+    pub prefix: String,
+    /// Use path-style URLs (`<endpoint>/<bucket>/<key>`), required by MinIO.
+    pub path_style: bool,
+}
+
+impl fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("path_style", &self.path_style)
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3Config {
+    /// Read settings from `S3_ENDPOINT`, `S3_REGION`, `S3_BUCKET`,
+    /// `S3_ACCESS_KEY_ID`, `S3_SECRET_ACCESS_KEY`, `S3_PREFIX` and `S3_PATH_STYLE`.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Ok(Self {
+            endpoint: var("S3_ENDPOINT").context("S3_ENDPOINT is not set")?,
+            region: var("S3_REGION").unwrap_or_else(|| "us-east-1".into()),
+            bucket: var("S3_BUCKET").context("S3_BUCKET is not set")?,
+            access_key: var("S3_ACCESS_KEY_ID"),
+            secret_key: var("S3_SECRET_ACCESS_KEY"),
+            prefix: var("S3_PREFIX").unwrap_or_default(),
+            path_style: var("S3_PATH_STYLE").is_none_or(|v| v != "false" && v != "0"),
+        })
+    }
+}
+
+/// Blobs stored as objects in an S3-compatible bucket.
+#[cfg(feature = "s3")]
+pub struct S3BlobStore {
+    bucket: Box<s3::Bucket>,
+    prefix: String,
+}
+
+#[cfg(feature = "s3")]
+impl fmt::Debug for S3BlobStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3BlobStore")
+            .field("bucket", &self.bucket.name)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "s3")]
+impl S3BlobStore {
+    pub fn new(config: &S3Config) -> Result<Self> {
+        let region = s3::Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        let credentials = s3::creds::Credentials::new(
+            config.access_key.as_deref(),
+            config.secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )
+        .context("loading S3 credentials")?;
+        let mut bucket = s3::Bucket::new(&config.bucket, region, credentials)?;
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+        Ok(Self { bucket, prefix: config.prefix.clone() })
+    }
+
+    fn object(&self, key: &str) -> Result<String> {
+        check_key(key)?;
+        Ok(format!("{}{key}", self.prefix))
+    }
+}
+
+/// Counts the bytes that pass through a reader.
+#[cfg(feature = "s3")]
+struct CountingReader<'a> {
+    inner: &'a mut dyn Read,
+    count: u64,
+}
+
+#[cfg(feature = "s3")]
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_not_found(e: &s3::error::S3Error) -> bool {
+    matches!(e, s3::error::S3Error::HttpFailWithBody(404, _))
+}
+
+#[cfg(feature = "s3")]
+impl BlobStore for S3BlobStore {
+    fn put(&self, key: &str, reader: &mut dyn Read) -> Result<u64> {
+        let object = self.object(key)?;
+        let mut counted = CountingReader { inner: reader, count: 0 };
+        self.bucket
+            .put_object_stream(&mut counted, &object)
+            .with_context(|| format!("uploading {object}"))?;
+        Ok(counted.count)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let object = self.object(key)?;
+        match self.bucket.get_object(&object) {
+            Ok(resp) => Ok(Some(resp.bytes().to_vec())),
+            Err(e) if s3_not_found(&e) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("fetching {object}")),
+        }
+    }
+
+    fn stream(&self, key: &str) -> Result<Option<Box<dyn Read + Send>>> {
+        // The sync client has no streaming body reader; spool to a temp file
+        // so large objects never sit in memory.
+        let object = self.object(key)?;
+        let mut spool = tempfile::tempfile()?;
+        match self.bucket.get_object_to_writer(&object, &mut spool) {
+            Ok(_) => {
+                io::Seek::rewind(&mut spool)?;
+                Ok(Some(Box::new(spool)))
+            }
+            Err(e) if s3_not_found(&e) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("fetching {object}")),
+        }
+    }
+
+    fn stream_range(&self, key: &str, offset: u64, len: u64) -> Result<Option<Box<dyn Read + Send>>> {
+        if len == 0 {
+            return Ok(self.exists(key)?.then(|| Box::new(io::empty()) as Box<dyn Read + Send>));
+        }
+        let object = self.object(key)?;
+        let mut spool = tempfile::tempfile()?;
+        match self.bucket.get_object_range_to_writer(&object, offset, Some(offset + len - 1), &mut spool) {
+            Ok(_) => {
+                io::Seek::rewind(&mut spool)?;
+                Ok(Some(Box::new(spool)))
+            }
+            Err(e) if s3_not_found(&e) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("fetching {object}")),
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<bool> {
+        if !self.exists(key)? {
+            return Ok(false);
+        }
+        let object = self.object(key)?;
+        self.bucket
+            .delete_object(&object)
+            .with_context(|| format!("deleting {object}"))?;
+        Ok(true)
+    }
+
+    fn exists(&self, key: &str) -> Result<bool> {
+        let object = self.object(key)?;
+        self.bucket
+            .object_exists(&object)
+            .with_context(|| format!("checking {object}"))
+    }
+
+    fn list(&self) -> Result<Vec<BlobEntry>> {
+        let pages = self
+            .bucket
+            .list(self.prefix.clone(), None)
+            .with_context(|| format!("listing {}", self.prefix))?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .filter_map(|obj| {
+                let key = obj.key.strip_prefix(&self.prefix)?.to_string();
+                Some(BlobEntry { key, size: obj.size })
+            })
+            .collect())
+    }
+}
+
+/// Which blob backend a store should use.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum BlobBackend {
+    /// Files under `<root>/blobs`.
+    #[default]
+    Fs,
+    /// Process memory; contents are lost on exit.
+    Memory,
+    /// An S3-compatible bucket.
+    S3(S3Config),
+}
+
+impl BlobBackend {
+    /// Resolve a backend by name (`fs`, `memory` or `s3`).
+    /// S3 settings are read from the environment, see [`S3Config::from_env`].
+    pub fn from_kind(kind: &str) -> Result<Self> {
+        match kind {
+            "fs" => Ok(Self::Fs),
+            "memory" => Ok(Self::Memory),
+            "s3" => Ok(Self::S3(S3Config::from_env()?)),
+            other => bail!("unknown blob backend {other:?} (expected fs, memory or s3)"),
+        }
+    }
+
+    /// Resolve the backend from `STORE_BACKEND` (default `fs`).
+    pub fn from_env() -> Result<Self> {
+        Self::from_kind(&std::env::var("STORE_BACKEND").unwrap_or_else(|_| "fs".into()))
+    }
+
+    /// Instantiate the backend for a store rooted at `root`.
+    pub fn open(&self, root: &Path) -> Result<Arc<dyn BlobStore>> {
+        match self {
+            Self::Fs => {
+                // stores that only held PDFs kept their blobs under pdfs/
+                let (dir, legacy) = (root.join("blobs"), root.join("pdfs"));
+                if legacy.is_dir() && !dir.exists() {
+                    std::fs::rename(&legacy, &dir).context("moving pdfs/ to blobs/")?;
+                }
+                Ok(Arc::new(FsBlobStore::new(dir)?))
+            }
+            Self::Memory => Ok(Arc::new(MemBlobStore::new())),
+            #[cfg(feature = "s3")]
+            Self::S3(config) => Ok(Arc::new(S3BlobStore::new(config)?)),
+            #[cfg(not(feature = "s3"))]
+            Self::S3(_) => bail!("store was built without the `s3` feature"),
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! Content-defined chunking of blobs.
+//!
+//! With [`StoreConfig::layout`](crate::StoreConfig::layout) set to
+//! [`StorageLayout::Chunked`], new blobs are cut with FastCDC into chunks of
+//! 16-256 KiB whose boundaries follow the content, so an edit only changes
+//! the chunks around it. Each chunk is stored once under
+//! `chunks/<sha256>` and reference-counted in the `chunks` tree; the blob's
+//! own key holds a manifest listing its chunks in order. Revisions and
+//! unrelated documents that share content share those chunks. Chunks are
+//! compressed one by one when compression is on, and checked against their
+//! sha256 whenever they are read.
+//!
+//! Chunked blobs are not encrypted at rest: per-blob data keys would give
+//! identical content different ciphertext and defeat deduplication, so a
+//! store cannot combine the chunked layout with a KEK.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read},
+    str::FromStr,
+    sync::{Arc, MutexGuard},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{blob_key, BlobEncoding, BlobMeta, BlobStore, Codec, DocStore, Hash32, StoreError, StoreInner, BLOB_LOCK_STRIPES};
+
+pub const MIN_CHUNK_BYTES: u32 = 16 * 1024;
+pub const AVG_CHUNK_BYTES: u32 = 64 * 1024;
+pub const MAX_CHUNK_BYTES: u32 = 256 * 1024;
+const MANIFEST_MAGIC: &[u8; 5] = b"SCDC\x01";
+/// sha256 and little-endian u32 length.
+const ENTRY_BYTES: u64 = 36;
+const CHUNK_PREFIX: &str = "chunks/";
+
+/// How new blobs are laid out in the blob backend.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageLayout {
+    /// One object per blob.
+    #[default]
+    Whole,
+    /// A manifest per blob over deduplicated chunks.
+    Chunked,
+}
+
+impl FromStr for StorageLayout {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s {
+            "whole" => Ok(Self::Whole),
+            "chunked" => Ok(Self::Chunked),
+            _ => Err(StoreError::InvalidQuery(format!("unknown storage layout {s:?}"))),
+        }
+    }
+}
+
+impl fmt::Display for StorageLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Whole => "whole",
+            Self::Chunked => "chunked",
+        })
+    }
+}
+
+/// Blob key of a chunk.
+pub fn chunk_key(hash: &Hash32) -> String {
+    format!("{CHUNK_PREFIX}{}", hex::encode(hash))
+}
+
+/// Chunk named by a blob key, if it is one.
+pub(crate) fn key_chunk(key: &str) -> Option<Hash32> {
+    hex::decode(key.strip_prefix(CHUNK_PREFIX)?).ok()?.try_into().ok()
+}
+
+/// Size of the manifest of a blob cut into `chunks` chunks.
+pub(crate) fn manifest_len(chunks: u64) -> u64 {
+    MANIFEST_MAGIC.len() as u64 + chunks * ENTRY_BYTES
+}
+
+/// One chunk of a manifest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ChunkRef {
+    pub(crate) hash: Hash32,
+    pub(crate) len: u32,
+}
+
+fn encode_manifest(chunks: &[ChunkRef]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(manifest_len(chunks.len() as u64) as usize);
+    out.extend_from_slice(MANIFEST_MAGIC);
+    for chunk in chunks {
+        out.extend_from_slice(&chunk.hash);
+        out.extend_from_slice(&chunk.len.to_le_bytes());
+    }
+    out
+}
+
+/// Parse a manifest; `None` if the bytes are not one.
+pub(crate) fn decode_manifest(bytes: &[u8]) -> Option<Vec<ChunkRef>> {
+    let body = bytes.strip_prefix(MANIFEST_MAGIC)?;
+    if !(body.len() as u64).is_multiple_of(ENTRY_BYTES) {
+        return None;
+    }
+    let entries = body.chunks_exact(ENTRY_BYTES as usize);
+    Some(entries.map(|e| ChunkRef { hash: e[..32].try_into().unwrap(), len: u32::from_le_bytes(e[32..].try_into().unwrap()) }).collect())
+}
+
+/// A stored chunk, in the `chunks` tree under its sha256.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ChunkMeta {
+    /// Manifest entries pointing at the chunk, across all blobs.
+    pub(crate) refs: u64,
+    pub(crate) size_bytes: u32,
+    /// Bytes in the backend, after compression.
+    pub(crate) stored_bytes: u64,
+    #[serde(default, skip_serializing_if = "Codec::is_none")]
+    pub(crate) codec: Codec,
+}
+
+/// Streams a chunked blob's plaintext, one verified chunk at a time.
+struct ChunkReader {
+    blobs: Arc<dyn BlobStore>,
+    /// Chunks still to read, with their codec (`None` if not indexed).
+    pending: VecDeque<(ChunkRef, Option<Codec>)>,
+    /// Bytes to drop from the first chunk.
+    skip: usize,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChunkReader {
+    fn load(&mut self, chunk: ChunkRef, codec: Option<Codec>) -> io::Result<()> {
+        let name = hex::encode(chunk.hash);
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("chunk {name} is {what}"));
+        let Some(codec) = codec else { return Err(invalid("not indexed")) };
+        let stored = self.blobs.get(&chunk_key(&chunk.hash)).map_err(io::Error::other)?.ok_or_else(|| invalid("missing"))?;
+        let bytes = match codec {
+            Codec::None => stored,
+            Codec::Zstd => zstd::decode_all(stored.as_slice()).map_err(|_| invalid("corrupt"))?,
+        };
+        if bytes.len() != chunk.len as usize || <[u8; 32]>::from(Sha256::digest(&bytes)) != chunk.hash {
+            return Err(invalid("corrupt"));
+        }
+        self.pos = std::mem::take(&mut self.skip).min(bytes.len());
+        self.buf = bytes;
+        Ok(())
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let Some((chunk, codec)) = self.pending.pop_front() else { return Ok(0) };
+            self.load(chunk, codec)?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Space used and saved across the store, from [`DocStore::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StoreStats {
+    /// Document records, trashed ones included.
+    pub records: u64,
+    pub blobs: u64,
+    pub chunked_blobs: u64,
+    /// Distinct chunks in the chunk store.
+    pub chunks: u64,
+    /// Bytes of every record's content, as if each was stored on its own.
+    pub logical_bytes: u64,
+    /// Bytes the blob backend holds: whole blobs, manifests and chunks.
+    pub stored_bytes: u64,
+    /// Bytes the chunked blobs' manifests reference, and the distinct chunk
+    /// bytes behind them (before compression).
+    pub chunk_referenced_bytes: u64,
+    pub chunk_unique_bytes: u64,
+    /// Bytes of Bao outboards, on top of `stored_bytes`.
+    pub outboard_bytes: u64,
+    /// `logical_bytes / stored_bytes`: everything deduplication (of whole
+    /// blobs and chunks) and compression save.
+    pub dedup_ratio: f64,
+    /// `chunk_referenced_bytes / chunk_unique_bytes`: what sharing chunks
+    /// across versions and documents saves.
+    pub chunk_dedup_ratio: f64,
+}
+
+fn ratio(a: u64, b: u64) -> f64 {
+    if b == 0 { 1.0 } else { a as f64 / b as f64 }
+}
+
+impl StoreInner {
+    /// Hold while changing a chunk's refcount or bytes. Taken after the blob
+    /// lock, never before it.
+    fn lock_chunk(&self, hash: &Hash32) -> MutexGuard<'_, ()> {
+        let stripe = hash[0] as usize % BLOB_LOCK_STRIPES;
+        self.chunk_locks[stripe].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn chunk_meta(&self, hash: &Hash32) -> Result<Option<ChunkMeta>> {
+        let Some(val) = self.chunk_index.get(hash)? else { return Ok(None) };
+        Ok(Some(serde_json::from_slice(&val)?))
+    }
+
+    /// Count one more reference to a chunk, writing its bytes if the store
+    /// does not have them yet, or always when `overwrite` (to replace
+    /// corrupted bytes).
+    fn add_chunk(&self, hash: &Hash32, data: &[u8], overwrite: bool) -> Result<()> {
+        let _guard = self.lock_chunk(hash);
+        let key = chunk_key(hash);
+        let existing = self.chunk_meta(hash)?;
+        let write = overwrite || existing.is_none() || !self.blobs.exists(&key)?;
+        let mut meta = existing.unwrap_or(ChunkMeta { refs: 0, size_bytes: data.len() as u32, stored_bytes: 0, codec: Codec::None });
+        if write {
+            let packed = match self.config.compression {
+                Codec::Zstd => Some(zstd::encode_all(data, self.config.compression_level)?).filter(|p| p.len() < data.len()),
+                Codec::None => None,
+            };
+            meta.codec = if packed.is_some() { Codec::Zstd } else { Codec::None };
+            let bytes = packed.as_deref().unwrap_or(data);
+            meta.stored_bytes = self.blobs.put(&key, &mut &bytes[..])?;
+        }
+        meta.refs += 1;
+        self.chunk_index.insert(hash, serde_json::to_vec(&meta)?)?;
+        Ok(())
+    }
+
+    /// Drop one reference to a chunk, removing it with its last.
+    fn release_chunk(&self, hash: &Hash32) -> Result<()> {
+        let _guard = self.lock_chunk(hash);
+        let Some(mut meta) = self.chunk_meta(hash)? else { return Ok(()) };
+        meta.refs = meta.refs.saturating_sub(1);
+        if meta.refs > 0 {
+            self.chunk_index.insert(hash, serde_json::to_vec(&meta)?)?;
+            return Ok(());
+        }
+        self.chunk_index.remove(hash)?;
+        self.kv.flush()?;
+        self.blobs.delete(&chunk_key(hash))?;
+        Ok(())
+    }
+
+    /// Release every chunk a manifest lists.
+    pub(crate) fn release_manifest(&self, manifest: &[u8]) -> Result<()> {
+        for chunk in decode_manifest(manifest).unwrap_or_default() {
+            self.release_chunk(&chunk.hash)?;
+        }
+        self.kv.flush()?;
+        Ok(())
+    }
+
+    /// Cut verified plaintext into chunks, store the ones that are new and
+    /// write the blob's manifest. `overwrite` rewrites chunks that already
+    /// exist, for restoring corrupted ones.
+    pub(crate) fn write_chunked(&self, sha256: &Hash32, plain: NamedTempFile, overwrite: bool) -> Result<BlobEncoding> {
+        let mut chunks = Vec::new();
+        let cutter = fastcdc::v2020::StreamCDC::new(plain.reopen()?, MIN_CHUNK_BYTES, AVG_CHUNK_BYTES, MAX_CHUNK_BYTES);
+        let written = (|| {
+            for chunk in cutter {
+                let chunk = chunk.map_err(|e| anyhow::anyhow!("{e}"))?;
+                let hash: Hash32 = Sha256::digest(&chunk.data).into();
+                self.add_chunk(&hash, &chunk.data, overwrite)?;
+                chunks.push(ChunkRef { hash, len: chunk.length as u32 });
+            }
+            // refcounts must be durable before a manifest points at them
+            self.kv.flush()?;
+            self.blobs.put(&blob_key(sha256), &mut &encode_manifest(&chunks)[..])
+        })();
+        if let Err(e) = written {
+            self.release_manifest(&encode_manifest(&chunks))?;
+            return Err(e);
+        }
+        Ok(BlobEncoding { chunks: Some(chunks.len() as u64), ..Default::default() })
+    }
+
+    /// Stream `len` plaintext bytes of a chunked blob from `offset`, reading
+    /// only the chunks that cover them. `None` if the manifest is missing.
+    pub(crate) fn open_chunked(&self, blob: &BlobMeta, offset: u64, len: u64) -> Result<Option<Box<dyn Read + Send>>> {
+        let Some(manifest) = self.blobs.get(&blob_key(&blob.sha256))? else { return Ok(None) };
+        let Some(chunks) = decode_manifest(&manifest) else {
+            let e = io::Error::new(io::ErrorKind::InvalidData, format!("manifest of {} is corrupt", hex::encode(blob.sha256)));
+            return Ok(Some(Box::new(FailingReader(Some(e)))));
+        };
+        let (mut pending, mut skip, mut start) = (VecDeque::new(), 0, 0u64);
+        for chunk in chunks {
+            let end = start + chunk.len as u64;
+            if end > offset && start < offset.saturating_add(len) {
+                if pending.is_empty() {
+                    skip = (offset - start) as usize;
+                }
+                pending.push_back((chunk, self.chunk_meta(&chunk.hash)?.map(|m| m.codec)));
+            }
+            start = end;
+        }
+        let reader = ChunkReader { blobs: self.blobs.clone(), pending, skip, buf: Vec::new(), pos: 0 };
+        Ok(Some(Box::new(reader.take(len))))
+    }
+
+    /// Remove the bytes of an unreferenced chunk, unless it was indexed
+    /// again in the meantime.
+    pub(crate) fn remove_orphan_chunk(&self, hash: &Hash32) -> Result<()> {
+        let _guard = self.lock_chunk(hash);
+        if self.chunk_meta(hash)?.is_none() {
+            self.blobs.delete(&chunk_key(hash))?;
+        }
+        Ok(())
+    }
+
+    /// Raise a chunk's refcount to what the manifests hold, if it still has
+    /// the `recorded` count fsck saw. Never lowers it: a write in progress
+    /// counts its chunks before its manifest exists.
+    pub(crate) fn fix_chunk_refcount(&self, hash: &Hash32, recorded: u64, actual: u64) -> Result<bool> {
+        let _guard = self.lock_chunk(hash);
+        let Some(mut meta) = self.chunk_meta(hash)? else { return Ok(false) };
+        if meta.refs != recorded || actual < recorded {
+            return Ok(false);
+        }
+        meta.refs = actual;
+        self.chunk_index.insert(hash, serde_json::to_vec(&meta)?)?;
+        self.kv.flush()?;
+        Ok(true)
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        for item in self.blob_index.iter() {
+            let blob: BlobMeta = serde_json::from_slice(&item?.1)?;
+            stats.blobs += 1;
+            stats.records += blob.refs;
+            stats.logical_bytes += blob.refs * blob.size_bytes;
+            stats.stored_bytes += blob.stored_size();
+            if blob.blake3.is_some() {
+                stats.outboard_bytes += Self::stored_outboard_len(&blob);
+            }
+            if blob.encoding.chunks.is_some() {
+                stats.chunked_blobs += 1;
+                stats.chunk_referenced_bytes += blob.size_bytes;
+            }
+        }
+        for item in self.chunk_index.iter() {
+            let meta: ChunkMeta = serde_json::from_slice(&item?.1)?;
+            stats.chunks += 1;
+            stats.chunk_unique_bytes += meta.size_bytes as u64;
+            stats.stored_bytes += meta.stored_bytes;
+        }
+        stats.dedup_ratio = ratio(stats.logical_bytes, stats.stored_bytes);
+        stats.chunk_dedup_ratio = ratio(stats.chunk_referenced_bytes, stats.chunk_unique_bytes);
+        Ok(stats)
+    }
+}
+
+/// Fails its first read with the error it holds.
+struct FailingReader(Option<io::Error>);
+
+impl Read for FailingReader {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        match self.0.take() {
+            Some(e) => Err(e),
+            None => Ok(0),
+        }
+    }
+}
+
+impl DocStore {
+    /// Record, blob and chunk counts, bytes stored against bytes uploaded,
+    /// and the resulting deduplication ratios.
+    pub async fn stats(&self) -> Result<StoreStats> {
+        self.blocking(|s| s.stats().context("computing store stats")).await
+    }
+}
@@ -0,0 +1,200 @@
+//! Optional compression of blobs at rest.
+//!
+//! With [`StoreConfig::compression`](crate::StoreConfig::compression) set to
+//! [`Codec::Zstd`], new blobs are compressed before they are sealed (if a KEK
+//! is configured) and written. The codec is recorded per blob in its
+//! [`BlobEncoding`], so compressed and raw blobs live side by side, and a blob
+//! that does not get smaller is kept raw. Content ids stay the sha256 of the
+//! original bytes. [`DocStore::recompress`] converts an existing store.
+
+use std::{
+    fmt,
+    io::{self, Read},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{BlobEncoding, BlobMeta, DocStore, StoreError, StoreInner};
+
+/// Default zstd level: fast, and most of the gain on scanned documents.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How a blob's bytes are compressed at rest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    /// Stored as uploaded.
+    #[default]
+    None,
+    /// One zstd frame.
+    Zstd,
+}
+
+impl Codec {
+    pub fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+}
+
+impl FromStr for Codec {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s {
+            "none" | "off" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(StoreError::InvalidQuery(format!("unknown codec {s:?}"))),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+/// Whether `head` starts like a zstd frame.
+pub(crate) fn is_zstd(head: &[u8]) -> bool {
+    head.starts_with(&ZSTD_MAGIC)
+}
+
+/// Decompresses a zstd stream, reporting a corrupt frame as
+/// [`io::ErrorKind::InvalidData`] like a sealed blob that fails
+/// authentication.
+pub(crate) struct Decompress<R: io::BufRead>(zstd::stream::read::Decoder<'static, R>);
+
+impl<R: Read> Decompress<io::BufReader<R>> {
+    pub(crate) fn new(inner: R) -> io::Result<Self> {
+        Ok(Self(zstd::stream::read::Decoder::new(inner)?))
+    }
+}
+
+impl<R: io::BufRead> Read for Decompress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::Other => io::Error::new(io::ErrorKind::InvalidData, format!("compressed blob is corrupt: {e}")),
+            _ => e,
+        })
+    }
+}
+
+/// Outcome of [`DocStore::recompress`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RecompressReport {
+    pub codec: Codec,
+    pub blobs_checked: usize,
+    /// Blobs rewritten with `codec`.
+    pub recompressed: usize,
+    /// Blobs left as they were: already in `codec`, chunked or quarantined.
+    pub unchanged: usize,
+    /// Blobs kept raw because compressing them saved nothing.
+    pub incompressible: usize,
+    /// Stored size of the rewritten blobs before and after.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub errors: Vec<String>,
+}
+
+impl RecompressReport {
+    /// Bytes freed in the backend (negative if blobs grew, e.g. when
+    /// decompressing).
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+impl StoreInner {
+    /// Compress the staged plaintext at `plain` into the staging dir.
+    pub(crate) fn compress_temp(&self, plain: &NamedTempFile) -> Result<(NamedTempFile, u64)> {
+        let mut out = tempfile::Builder::new().prefix("zstd-").tempfile_in(self.root.join("tmp"))?;
+        zstd::stream::copy_encode(plain.reopen()?, out.as_file_mut(), self.config.compression_level)
+            .context("compressing blob")?;
+        let len = out.as_file().metadata()?.len();
+        Ok((out, len))
+    }
+
+    /// Rewrite one blob with `codec`, keeping its data key. Holds the blob
+    /// lock; the bytes are decoded and checked against the sha256 first.
+    fn recompress_blob(&self, sha256: &crate::Hash32, codec: Codec, report: &mut RecompressReport) -> Result<()> {
+        let _guard = self.lock_blob(sha256);
+        let Some(blob) = self.blob_meta(sha256)? else { return Ok(()) };
+        report.blobs_checked += 1;
+        // chunks are compressed one by one as they are written
+        if blob.encoding.codec == codec || blob.quarantined_at_unix_ms.is_some() || blob.encoding.chunks.is_some() {
+            report.unchanged += 1;
+            return Ok(());
+        }
+        let before = blob.stored_size();
+        let plain = self.decode_to_temp(&blob)?;
+        let dek = blob.encoding.encryption.as_ref().map(|w| self.data_key(sha256, w)).transpose()?;
+        let (codec_used, compressed_size) = self.encode_blob(sha256, plain, blob.size_bytes, codec, dek.as_ref())?;
+        let encoding = BlobEncoding { encryption: blob.encoding.encryption.clone(), codec: codec_used, compressed_size, chunks: None };
+        self.update_blob(sha256, |b| b.encoding = encoding.clone())?;
+        let after = BlobMeta { encoding, ..blob }.stored_size();
+        if codec_used == codec {
+            report.recompressed += 1;
+        } else {
+            report.incompressible += 1;
+        }
+        report.bytes_before += before;
+        report.bytes_after += after;
+        Ok(())
+    }
+
+    /// Copy a blob's plaintext into the staging dir, checking it still
+    /// hashes to its sha256.
+    fn decode_to_temp(&self, blob: &BlobMeta) -> Result<NamedTempFile> {
+        let sha_hex = hex::encode(blob.sha256);
+        let mut reader = self.open_blob(blob)?.with_context(|| format!("blob {sha_hex} is missing"))?;
+        let mut plain = tempfile::Builder::new().prefix("decoded-").tempfile_in(self.root.join("tmp"))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            io::Write::write_all(plain.as_file_mut(), &buf[..n])?;
+        }
+        if <[u8; 32]>::from(hasher.finalize()) != blob.sha256 {
+            anyhow::bail!("blob {sha_hex} no longer matches its sha256; run scrub first");
+        }
+        Ok(plain)
+    }
+}
+
+impl DocStore {
+    /// Rewrite every blob stored with another codec in `codec`, e.g. to
+    /// compress a store that predates compression. Each blob is decoded and
+    /// verified, re-encoded under its existing data key and then re-indexed;
+    /// blobs that fail are listed in the report and left as they were. Safe
+    /// to run again.
+    pub async fn recompress(&self, codec: Codec) -> Result<RecompressReport> {
+        self.blocking(move |s| {
+            let mut report = RecompressReport { codec, ..Default::default() };
+            let shas = s
+                .blob_index
+                .iter()
+                .keys()
+                .map(|k| Ok(k?.as_ref().try_into()?))
+                .collect::<Result<Vec<crate::Hash32>>>()?;
+            for sha256 in shas {
+                if let Err(e) = s.recompress_blob(&sha256, codec, &mut report) {
+                    report.errors.push(format!("{}: {e:#}", hex::encode(sha256)));
+                }
+            }
+            Ok(report)
+        })
+        .await
+    }
+}
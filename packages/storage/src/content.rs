@@ -0,0 +1,308 @@
+//! Content types the store accepts, recognised by their magic bytes.
+//!
+//! The declared MIME type of an upload is never trusted: each upload is
+//! sniffed against [`REGISTRY`], checked against the deployment's
+//! allow-list ([`crate::StoreConfig::allowed_types`]) and then run through
+//! the type's validator. Formats without a signature (CSV, DXF, SVG) cannot
+//! be recognised this way and are not registered.
+//!
+//! Checking a staged upload never loads it: signatures and validators only
+//! see a [`Sample`] of its first and last bytes, and markers are searched
+//! for chunk by chunk.
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::StoreError;
+
+/// MIME type of PDFs, which get the deep checks in [`crate::pdf`].
+pub const PDF: &str = "application/pdf";
+
+/// Bytes kept from the start of an upload for sniffing.
+pub(crate) const SNIFF_BYTES: usize = 128;
+
+const ZIP: &[u8] = b"PK\x03\x04";
+const OLE: &[u8] = b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1";
+/// How far from the end a zip's end-of-central-directory record may start.
+const ZIP_EOCD_WINDOW: usize = 22 + 0xFFFF;
+/// Bytes kept from the end of an upload for validation.
+const TAIL_BYTES: usize = ZIP_EOCD_WINDOW;
+/// Read size of the marker search.
+const SCAN_CHUNK: usize = 64 * 1024;
+
+/// Byte strings that must all appear at the given offsets.
+pub type Magic = &'static [(usize, &'static [u8])];
+
+/// A registered content type.
+#[derive(Debug)]
+pub struct ContentType {
+    pub mime: &'static str,
+    /// Usual file extension, without the dot.
+    pub extension: &'static str,
+    /// Alternative signatures; one must match.
+    pub magic: &'static [Magic],
+    /// Must appear somewhere in the file, to tell apart containers that
+    /// share a signature (e.g. spreadsheets and documents inside zips).
+    pub marker: Option<&'static [u8]>,
+    /// Structural check run on the file's ends; `Err` holds the reason.
+    pub validate: fn(&Sample) -> Result<(), String>,
+}
+
+/// What a validator sees of a file: its first 128 bytes, enough of its end
+/// to hold a zip's end-of-central-directory record, and its length. Head and
+/// tail overlap for small files.
+#[derive(Debug)]
+pub struct Sample<'a> {
+    pub head: &'a [u8],
+    pub tail: &'a [u8],
+    pub len: u64,
+}
+
+/// Every type the store knows how to recognise, most specific first.
+pub static REGISTRY: &[ContentType] = &[
+    ContentType { mime: PDF, extension: "pdf", magic: &[&[(0, b"%PDF-")]], marker: None, validate: accept },
+    ContentType {
+        mime: "image/png",
+        extension: "png",
+        magic: &[&[(0, b"\x89PNG\r\n\x1a\n")]],
+        marker: None,
+        validate: validate_png,
+    },
+    ContentType { mime: "image/jpeg", extension: "jpg", magic: &[&[(0, b"\xFF\xD8\xFF")]], marker: None, validate: validate_jpeg },
+    ContentType {
+        mime: "image/gif",
+        extension: "gif",
+        magic: &[&[(0, b"GIF87a")], &[(0, b"GIF89a")]],
+        marker: None,
+        validate: validate_gif,
+    },
+    ContentType {
+        mime: "image/webp",
+        extension: "webp",
+        magic: &[&[(0, b"RIFF"), (8, b"WEBP")]],
+        marker: None,
+        validate: validate_riff,
+    },
+    ContentType {
+        mime: "image/tiff",
+        extension: "tif",
+        magic: &[&[(0, b"II*\0")], &[(0, b"MM\0*")]],
+        marker: None,
+        validate: validate_tiff,
+    },
+    ContentType { mime: "image/vnd.dwg", extension: "dwg", magic: &[&[(0, b"AC10")]], marker: None, validate: validate_dwg },
+    ContentType {
+        mime: "application/vnd.oasis.opendocument.spreadsheet",
+        extension: "ods",
+        magic: &[&[(0, ZIP), (30, b"mimetypeapplication/vnd.oasis.opendocument.spreadsheet")]],
+        marker: None,
+        validate: validate_zip,
+    },
+    ContentType {
+        mime: "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        extension: "xlsx",
+        magic: &[&[(0, ZIP)]],
+        marker: Some(b"xl/workbook.xml"),
+        validate: validate_zip,
+    },
+    ContentType {
+        mime: "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        extension: "docx",
+        magic: &[&[(0, ZIP)]],
+        marker: Some(b"word/document.xml"),
+        validate: validate_zip,
+    },
+    ContentType {
+        mime: "application/vnd.ms-excel",
+        extension: "xls",
+        magic: &[&[(0, OLE)]],
+        marker: Some(b"W\0o\0r\0k\0b\0o\0o\0k\0"),
+        validate: accept,
+    },
+];
+
+impl ContentType {
+    /// Registered type for a MIME type.
+    pub fn by_mime(mime: &str) -> Option<&'static ContentType> {
+        REGISTRY.iter().find(|t| t.mime.eq_ignore_ascii_case(mime))
+    }
+
+    /// Whether `head` starts with one of this type's signatures.
+    pub fn matches(&self, head: &[u8]) -> bool {
+        self.magic.iter().any(|parts| parts.iter().all(|(at, bytes)| head.get(*at..at + bytes.len()) == Some(bytes)))
+    }
+
+    /// Whether an upload starting with `head` may still turn out to be this
+    /// type once more bytes arrive.
+    pub(crate) fn could_match(&self, head: &[u8]) -> bool {
+        self.magic.iter().any(|parts| {
+            parts.iter().all(|(at, bytes)| {
+                let seen = head.get(*at..).unwrap_or_default();
+                let n = seen.len().min(bytes.len());
+                seen[..n] == bytes[..n]
+            })
+        })
+    }
+
+    fn identifies(&self, bytes: &[u8]) -> bool {
+        self.matches(bytes) && self.marker.is_none_or(|m| bytes.windows(m.len()).any(|w| w == m))
+    }
+
+    fn identifies_file(&self, head: &[u8], file: &mut File) -> io::Result<bool> {
+        match self.marker {
+            _ if !self.matches(head) => Ok(false),
+            None => Ok(true),
+            Some(marker) => contains(file, marker),
+        }
+    }
+}
+
+/// The registered type of a whole file, if any.
+pub fn detect(bytes: &[u8]) -> Option<&'static ContentType> {
+    REGISTRY.iter().find(|t| t.identifies(bytes))
+}
+
+/// Registered types selected by an allow-list of MIME types. `type/*`
+/// selects every registered subtype; unknown entries are an error.
+pub fn allowed(patterns: &[String]) -> Result<Vec<&'static ContentType>> {
+    let mut out: Vec<&'static ContentType> = Vec::new();
+    for pattern in patterns {
+        let pattern = pattern.trim();
+        let selected: Vec<_> = match pattern.strip_suffix("/*") {
+            Some(top) => REGISTRY.iter().filter(|t| t.mime.split('/').next() == Some(top)).collect(),
+            None => ContentType::by_mime(pattern).into_iter().collect(),
+        };
+        if selected.is_empty() {
+            bail!("no registered content type matches {pattern:?}");
+        }
+        for kind in selected {
+            if !out.iter().any(|o| o.mime == kind.mime) {
+                out.push(kind);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Identify a complete upload at `path` among the `allowed` types and
+/// validate it, reading only its ends and, for types with a marker, one
+/// bounded pass over it.
+pub(crate) fn check(path: &Path, allowed: &[&'static ContentType]) -> Result<&'static ContentType> {
+    let mut file = File::open(path).context("opening staged upload")?;
+    let len = file.metadata()?.len();
+    let head = read_at(&mut file, 0, SNIFF_BYTES)?;
+    let tail = read_at(&mut file, len.saturating_sub(TAIL_BYTES as u64), TAIL_BYTES)?;
+    let Some(kind) = find(allowed.iter().copied(), &head, &mut file)? else {
+        let detected = find(REGISTRY.iter(), &head, &mut file)?;
+        bail!(StoreError::UnsupportedType { detected: detected.map(|t| t.mime.to_string()) });
+    };
+    if let Err(reason) = (kind.validate)(&Sample { head: &head, tail: &tail, len }) {
+        bail!(StoreError::InvalidContent { mime: kind.mime.to_string(), reason });
+    }
+    Ok(kind)
+}
+
+fn find(
+    types: impl IntoIterator<Item = &'static ContentType>,
+    head: &[u8],
+    file: &mut File,
+) -> io::Result<Option<&'static ContentType>> {
+    for kind in types {
+        if kind.identifies_file(head, file)? {
+            return Ok(Some(kind));
+        }
+    }
+    Ok(None)
+}
+
+/// Up to `len` bytes of `file` from `offset`.
+fn read_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len);
+    file.seek(SeekFrom::Start(offset))?;
+    file.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Whether `marker` appears anywhere in `file`, reading it in
+/// [`SCAN_CHUNK`]s that overlap by less than the marker.
+fn contains(file: &mut File, marker: &[u8]) -> io::Result<bool> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![0; SCAN_CHUNK + marker.len()];
+    let mut kept = 0;
+    loop {
+        let n = file.read(&mut buf[kept..])?;
+        if n == 0 {
+            return Ok(false);
+        }
+        let filled = kept + n;
+        if buf[..filled].windows(marker.len()).any(|w| w == marker) {
+            return Ok(true);
+        }
+        kept = filled.min(marker.len() - 1);
+        buf.copy_within(filled - kept..filled, 0);
+    }
+}
+
+fn accept(_: &Sample) -> Result<(), String> {
+    Ok(())
+}
+
+fn validate_png(s: &Sample) -> Result<(), String> {
+    if s.head.get(12..16) != Some(b"IHDR") {
+        return Err("first chunk is not IHDR".into());
+    }
+    if s.len < 20 || &s.tail[s.tail.len() - 8..s.tail.len() - 4] != b"IEND" {
+        return Err("missing IEND chunk".into());
+    }
+    Ok(())
+}
+
+fn validate_jpeg(s: &Sample) -> Result<(), String> {
+    // encoders sometimes pad after the end-of-image marker
+    let tail = &s.tail[s.tail.len().saturating_sub(1024)..];
+    tail.windows(2).any(|w| w == b"\xFF\xD9").then_some(()).ok_or_else(|| "missing end-of-image marker".into())
+}
+
+fn validate_gif(s: &Sample) -> Result<(), String> {
+    match s.tail.iter().rev().find(|b| **b != 0) {
+        Some(b';') if s.len > 13 => Ok(()),
+        _ => Err("missing trailer".into()),
+    }
+}
+
+fn validate_riff(s: &Sample) -> Result<(), String> {
+    let declared = u32::from_le_bytes(s.head[4..8].try_into().unwrap()) as u64;
+    // chunks are padded to an even length
+    match (declared + 8).abs_diff(s.len) {
+        0 | 1 => Ok(()),
+        _ => Err(format!("RIFF header declares {} bytes but the file has {}", declared + 8, s.len)),
+    }
+}
+
+fn validate_tiff(s: &Sample) -> Result<(), String> {
+    let offset: [u8; 4] = s.head.get(4..8).and_then(|b| b.try_into().ok()).ok_or("truncated header")?;
+    let offset = if s.head[0] == b'I' { u32::from_le_bytes(offset) } else { u32::from_be_bytes(offset) } as u64;
+    if offset < 8 || offset + 2 > s.len {
+        return Err(format!("first IFD offset {offset} is outside the file"));
+    }
+    Ok(())
+}
+
+fn validate_dwg(s: &Sample) -> Result<(), String> {
+    match s.head.get(4..6) {
+        Some(v) if v.iter().all(u8::is_ascii_digit) => Ok(()),
+        _ => Err("malformed version string".into()),
+    }
+}
+
+fn validate_zip(s: &Sample) -> Result<(), String> {
+    s.tail.windows(4)
+        .any(|w| w == b"PK\x05\x06")
+        .then_some(())
+        .ok_or_else(|| "missing zip central directory".into())
+}
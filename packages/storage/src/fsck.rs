@@ -0,0 +1,457 @@
+//! Consistency check between the sled index and the blob backend.
+//!
+//! Drift can come from a crash between writing a blob and indexing it, a
+//! blob removed behind the store's back, or staging files left in `tmp/`
+//! and `uploads/`.
+//! [`DocStore::check`] only reports; [`DocStore::repair`] also fixes what it
+//! safely can. Every repair re-checks its target under the blob lock, so it
+//! is safe to run against a live store.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sled::Transactional;
+
+use crate::{
+    bao, blob_key, chunk, compress, content, crypt, now_ms,
+    query::{cid_key, index_record},
+    ref_key, tenant, tx_result, version_key, BackfillReport, BlobEncoding, BlobMeta, Codec, DocStore, Hash32, StoreInner, Tombstone,
+};
+
+/// How a check runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FsckOptions {
+    /// Fix what can be fixed instead of only reporting it.
+    pub repair: bool,
+    /// Staging and upload files younger than this may belong to an upload in
+    /// progress and are left alone.
+    pub temp_min_age: Duration,
+}
+
+impl Default for FsckOptions {
+    fn default() -> Self {
+        Self { repair: false, temp_min_age: Duration::from_secs(60 * 60) }
+    }
+}
+
+/// A recorded value that disagrees with what is actually stored.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Mismatch {
+    pub sha256: String,
+    pub recorded: u64,
+    pub actual: u64,
+}
+
+/// Findings of a consistency check.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    pub blobs_checked: usize,
+    pub records_checked: usize,
+    /// Blob keys with no index entry.
+    pub orphan_blobs: Vec<String>,
+    /// sha256 of index entries whose blob is gone.
+    pub missing_blobs: Vec<String>,
+    /// Record ids pointing at a sha256 with no index entry.
+    pub dangling_records: Vec<String>,
+    /// Index reference counts that disagree with the records.
+    pub refcount_mismatches: Vec<Mismatch>,
+    /// Indexed sizes that disagree with the stored blob.
+    pub size_mismatches: Vec<Mismatch>,
+    /// sha256 of chunks a manifest lists that are unindexed, missing or not
+    /// the size indexed; the scrubber refetches the blobs using them.
+    pub missing_chunks: Vec<String>,
+    /// Chunk reference counts that disagree with the manifests.
+    pub chunk_refcount_mismatches: Vec<Mismatch>,
+    /// sha256 of blobs with a BLAKE3 digest whose Bao outboard is missing
+    /// or the wrong size; repair rebuilds it.
+    pub missing_outboards: Vec<String>,
+    /// Files left in `<root>/tmp`.
+    pub stray_temp_files: Vec<String>,
+    /// Files in `<root>/uploads` with no resumable upload.
+    pub stray_upload_files: Vec<String>,
+    /// Whether fixes were applied.
+    pub repaired: bool,
+    /// Problems a repair could not fix.
+    pub unrepaired: Vec<String>,
+}
+
+impl FsckReport {
+    /// True when nothing was found.
+    pub fn is_clean(&self) -> bool {
+        self.orphan_blobs.is_empty()
+            && self.missing_blobs.is_empty()
+            && self.dangling_records.is_empty()
+            && self.refcount_mismatches.is_empty()
+            && self.size_mismatches.is_empty()
+            && self.missing_chunks.is_empty()
+            && self.chunk_refcount_mismatches.is_empty()
+            && self.missing_outboards.is_empty()
+            && self.stray_temp_files.is_empty()
+            && self.stray_upload_files.is_empty()
+    }
+}
+
+/// sha256 named by a blob key, if it follows the store's layout.
+fn key_sha256(key: &str) -> Option<Hash32> {
+    hex::decode(key).ok()?.try_into().ok()
+}
+
+impl StoreInner {
+    fn fsck(&self, opts: &FsckOptions) -> Result<FsckReport> {
+        let mut report = FsckReport { repaired: opts.repair, ..Default::default() };
+
+        // tenants' blobs share the backend with the unscoped store
+        let stored: HashMap<String, u64> = self
+            .blobs
+            .list()?
+            .into_iter()
+            .filter(|b| !tenant::is_tenant_key(&b.key))
+            .map(|b| (b.key, b.size))
+            .collect();
+        report.blobs_checked = stored.len();
+
+        let mut by_blob: HashMap<Hash32, Vec<Hash32>> = HashMap::new();
+        for record in self.records() {
+            let record = record?;
+            report.records_checked += 1;
+            by_blob.entry(record.sha256).or_default().push(record.id);
+        }
+
+        let mut indexed = HashSet::new();
+        let mut chunk_refs: HashMap<Hash32, u64> = HashMap::new();
+        for item in self.blob_index.iter() {
+            let (_, val) = item?;
+            let blob: BlobMeta = serde_json::from_slice(&val)?;
+            let key = blob_key(&blob.sha256);
+            let sha_hex = hex::encode(blob.sha256);
+            let records = by_blob.remove(&blob.sha256).unwrap_or_default();
+            let refs = records.len() as u64;
+            indexed.insert(key.clone());
+            if blob.encoding.chunks.is_some() && stored.contains_key(&key) {
+                let manifest = self.blobs.get(&key)?.unwrap_or_default();
+                for chunk in chunk::decode_manifest(&manifest).unwrap_or_default() {
+                    *chunk_refs.entry(chunk.hash).or_default() += 1;
+                }
+            }
+
+            if blob.refs != refs {
+                report.refcount_mismatches.push(Mismatch { sha256: sha_hex.clone(), recorded: blob.refs, actual: refs });
+                if opts.repair {
+                    self.fix_refcount(&blob.sha256, refs)?;
+                }
+            }
+            let bao_key = bao::bao_key(&blob.sha256);
+            if stored.contains_key(&bao_key) {
+                indexed.insert(bao_key.clone());
+            }
+            let outboard_ok = stored.get(&bao_key) == Some(&Self::stored_outboard_len(&blob));
+            // a missing or quarantined blob is reported below
+            if blob.blake3.is_some() && !outboard_ok && stored.contains_key(&key) && blob.quarantined_at_unix_ms.is_none() {
+                report.missing_outboards.push(sha_hex.clone());
+                if opts.repair {
+                    if let Err(e) = self.backfill_blob(&blob.sha256, true, &mut BackfillReport::default()) {
+                        report.unrepaired.push(format!("{sha_hex}: outboard not rebuilt: {e:#}"));
+                    }
+                }
+            }
+            match stored.get(&key) {
+                None if blob.quarantined_at_unix_ms.is_some() => {
+                    // the scrubber owns it until good bytes are refetched
+                    if opts.repair {
+                        report.unrepaired.push(format!("{sha_hex}: quarantined by the scrubber"));
+                    }
+                    report.missing_blobs.push(sha_hex);
+                }
+                None => {
+                    if opts.repair {
+                        self.trash_missing(&blob.sha256, &records)?;
+                        report.unrepaired.push(format!("{sha_hex}: blob missing, records moved to the trash"));
+                    }
+                    report.missing_blobs.push(sha_hex);
+                }
+                Some(&size) if size != blob.stored_size() => {
+                    report.size_mismatches.push(Mismatch { sha256: sha_hex.clone(), recorded: blob.stored_size(), actual: size });
+                    if opts.repair && !self.fix_size(&blob.sha256, size)? {
+                        report.unrepaired.push(format!("{sha_hex}: stored bytes do not match their sha256"));
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (sha256, records) in by_blob {
+            let key = blob_key(&sha256);
+            report.dangling_records.extend(records.iter().map(hex::encode));
+            if opts.repair {
+                if stored.contains_key(&key) && self.blob_is_sealed(&key)? {
+                    // its data key was only kept in the lost index entry
+                    report.unrepaired.push(format!("{}: encrypted blob lost its data key", hex::encode(sha256)));
+                    indexed.insert(key);
+                } else if self.rebuild_index(&sha256, &records, stored.get(&key).copied())? {
+                    indexed.insert(key);
+                } else {
+                    report.unrepaired.push(format!("{}: blob is gone, dropped its records", hex::encode(sha256)));
+                }
+            }
+        }
+
+        for item in self.chunk_index.iter() {
+            let (hash, val) = item?;
+            let hash: Hash32 = hash.as_ref().try_into()?;
+            let meta: chunk::ChunkMeta = serde_json::from_slice(&val)?;
+            let (key, sha_hex) = (chunk::chunk_key(&hash), hex::encode(hash));
+            indexed.insert(key.clone());
+            let actual = chunk_refs.remove(&hash).unwrap_or_default();
+            if meta.refs != actual {
+                report.chunk_refcount_mismatches.push(Mismatch { sha256: sha_hex.clone(), recorded: meta.refs, actual });
+                if opts.repair && !self.fix_chunk_refcount(&hash, meta.refs, actual)? {
+                    report.unrepaired.push(format!("{sha_hex}: chunk counts more references than manifests, kept"));
+                }
+            }
+            if stored.get(&key) != Some(&meta.stored_bytes) {
+                report.missing_chunks.push(sha_hex);
+            }
+        }
+        report.missing_chunks.extend(chunk_refs.keys().map(hex::encode));
+        report.missing_chunks.sort();
+        if opts.repair && !report.missing_chunks.is_empty() {
+            report.unrepaired.push(format!("{} chunks missing or damaged; run scrub to refetch the blobs using them", report.missing_chunks.len()));
+        }
+
+        for key in stored.keys().filter(|k| !indexed.contains(*k)) {
+            report.orphan_blobs.push(key.clone());
+            if opts.repair {
+                self.remove_orphan(key)?;
+            }
+        }
+        report.orphan_blobs.sort();
+
+        report.stray_temp_files = self.stray_files("tmp", opts, |_| Ok(false))?;
+        report.stray_upload_files = self.stray_files("uploads", opts, |name| Ok(self.uploads.contains_key(name)?))?;
+        Ok(report)
+    }
+
+    fn fix_refcount(&self, sha256: &Hash32, refs: u64) -> Result<()> {
+        let _guard = self.lock_blob(sha256);
+        let Some(mut blob) = self.blob_meta(sha256)? else { return Ok(()) };
+        let actual = self.blob_refs.scan_prefix(sha256).count() as u64;
+        if actual != refs {
+            // changed since the scan; leave it for the next run
+            return Ok(());
+        }
+        if refs == 0 {
+            self.blob_index.remove(sha256)?;
+            for cid in blob.cids() {
+                self.cid_index.remove(cid_key(cid, sha256))?;
+            }
+            self.kv.flush()?;
+            self.forget_blob(&blob)?;
+            self.delete_blob(&blob)?;
+        } else {
+            blob.refs = refs;
+            self.blob_index.insert(sha256, serde_json::to_vec(&blob)?)?;
+            self.kv.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Records whose bytes are gone cannot be served; move them to the trash
+    /// so they stay visible and can be restored once the blob is back.
+    fn trash_missing(&self, sha256: &Hash32, records: &[Hash32]) -> Result<()> {
+        let _guard = self.lock_blob(sha256);
+        if self.blobs.exists(&blob_key(sha256))? {
+            return Ok(());
+        }
+        for id in records {
+            let tombstone = Tombstone {
+                deleted_by: Some("fsck".into()),
+                deleted_at_unix_ms: now_ms(),
+                reason: Some("blob missing".into()),
+            };
+            self.trash(*id, tombstone)?;
+        }
+        Ok(())
+    }
+
+    /// Re-hash a blob and record its real size and codec if the content is
+    /// intact.
+    fn fix_size(&self, sha256: &Hash32, stored: u64) -> Result<bool> {
+        let _guard = self.lock_blob(sha256);
+        let Some(mut blob) = self.blob_meta(sha256)? else { return Ok(false) };
+        if blob.encoding.chunks.is_some() {
+            // a manifest of the wrong size is damaged; the scrubber refetches it
+            return Ok(false);
+        }
+        let encoded = match blob.encoding.encryption {
+            // sealed chunks only authenticate the size their layout implies
+            Some(_) => match crypt::plain_len(stored) {
+                Some(size) => size,
+                None => return Ok(false),
+            },
+            None => stored,
+        };
+        // a recompress interrupted before its index update leaves the other codec
+        blob.encoding.codec = Codec::None;
+        blob.encoding.compressed_size = None;
+        blob.size_bytes = encoded;
+        let mut head = Vec::new();
+        let Some(reader) = self.open_blob_range(&blob, 0, 4)? else { return Ok(false) };
+        if io::Read::read_to_end(&mut io::Read::take(reader, 4), &mut head).is_err() {
+            return Ok(false);
+        }
+        if compress::is_zstd(&head) {
+            // decode the whole frame and count what comes out
+            blob.encoding.codec = Codec::Zstd;
+            blob.encoding.compressed_size = Some(encoded);
+            blob.size_bytes = u64::MAX;
+        }
+        let Some(mut reader) = self.open_blob(&blob)? else { return Ok(false) };
+        let mut hasher = Sha256::new();
+        let size = match io::copy(&mut reader, &mut hasher) {
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        if <[u8; 32]>::from(hasher.finalize()) != *sha256 {
+            return Ok(false);
+        }
+        blob.size_bytes = size;
+        self.blob_index.insert(sha256, serde_json::to_vec(&blob)?)?;
+        self.kv.flush()?;
+        Ok(true)
+    }
+
+    /// Recreate the index entry for records whose blob lost it, or drop the
+    /// records when the bytes are gone too. Returns whether the blob was kept.
+    fn rebuild_index(&self, sha256: &Hash32, records: &[Hash32], size: Option<u64>) -> Result<bool> {
+        let _guard = self.lock_blob(sha256);
+        if self.blob_meta(sha256)?.is_some() {
+            return Ok(true);
+        }
+        if let Some(stored) = size {
+            let mut bytes = self.blobs.get(&blob_key(sha256))?.unwrap_or_default();
+            let (mut size_bytes, mut encoding) = (stored, BlobEncoding::default());
+            let manifest = chunk::decode_manifest(&bytes);
+            if let Some(chunks) = &manifest {
+                // its chunks are still counted for it
+                encoding = BlobEncoding { chunks: Some(chunks.len() as u64), ..Default::default() };
+                size_bytes = chunks.iter().map(|c| c.len as u64).sum();
+            } else if compress::is_zstd(&bytes) {
+                if let Ok(plain) = zstd::decode_all(bytes.as_slice()) {
+                    encoding = BlobEncoding { codec: Codec::Zstd, compressed_size: Some(stored), ..Default::default() };
+                    (size_bytes, bytes) = (plain.len() as u64, plain);
+                }
+            }
+            // reindex recomputes the Merkle root, backfill the BLAKE3 digest
+            let mut blob = BlobMeta {
+                sha256: *sha256,
+                blake3: None,
+                merkle_root: None,
+                size_bytes,
+                content_type: String::new(),
+                cid: None,
+                encrypted_cid: None,
+                refs: records.len() as u64,
+                created_at_unix_ms: now_ms(),
+                last_verified_at_unix_ms: None,
+                quarantined_at_unix_ms: None,
+                pdf: None,
+                encoding,
+            };
+            if let Some(first) = manifest.as_ref().and_then(|chunks| chunks.first()) {
+                // sniff the first chunk
+                bytes.clear();
+                if let Some(mut reader) = self.open_blob_range(&blob, 0, first.len as u64)? {
+                    let _ = io::Read::read_to_end(&mut reader, &mut bytes);
+                }
+            }
+            blob.content_type = content::detect(&bytes).map_or("application/octet-stream", |t| t.mime).to_string();
+            self.blob_index.insert(sha256, serde_json::to_vec(&blob)?)?;
+            self.kv.flush()?;
+            return Ok(true);
+        }
+        for id in records {
+            let Some(record) = self.record(id)? else { continue };
+            let [i0, i1, i2, i3, i4] = &self.indexes;
+            let trees = (&self.docs, &self.blob_refs, &self.versions, i0, i1, i2, i3, i4);
+            tx_result(trees.transaction(|(docs, refs, versions, i0, i1, i2, i3, i4)| {
+                docs.remove(&record.id)?;
+                refs.remove(ref_key(sha256, &record.id))?;
+                versions.remove(version_key(&record.lineage(), record.version))?;
+                index_record([i0, i1, i2, i3, i4], &record, false)?;
+                Ok::<_, crate::TxError>(())
+            }))?;
+            #[cfg(feature = "search")]
+            self.search_remove(&record.id);
+        }
+        self.kv.flush()?;
+        Ok(false)
+    }
+
+    fn remove_orphan(&self, key: &str) -> Result<()> {
+        if let Some(hash) = chunk::key_chunk(key) {
+            return self.remove_orphan_chunk(&hash);
+        }
+        match key_sha256(key).or_else(|| bao::key_bao(key)) {
+            Some(sha256) => {
+                let _guard = self.lock_blob(&sha256);
+                if self.blob_meta(&sha256)?.is_none() {
+                    self.blobs.delete(key)?;
+                }
+            }
+            None => {
+                self.blobs.delete(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Files in `<root>/<dir>` older than `opts.temp_min_age` that `owned`
+    /// does not claim, removed on repair.
+    fn stray_files(&self, dir: &str, opts: &FsckOptions, owned: impl Fn(&str) -> Result<bool>) -> Result<Vec<String>> {
+        let dir = self.root.join(dir);
+        let mut out = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("reading {dir:?}"))? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if age < opts.temp_min_age || owned(&name)? {
+                continue;
+            }
+            out.push(name);
+            if opts.repair {
+                match fs::remove_file(entry.path()) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).with_context(|| format!("removing {:?}", entry.path())),
+                }
+            }
+        }
+        out.sort();
+        Ok(out)
+    }
+}
+
+impl DocStore {
+    /// Report drift between the index, the blob backend, `tmp/` and
+    /// `uploads/` without changing anything.
+    pub async fn check(&self) -> Result<FsckReport> {
+        self.fsck(FsckOptions::default()).await
+    }
+
+    /// Like [`Self::check`], but also fix what was found.
+    pub async fn repair(&self) -> Result<FsckReport> {
+        self.fsck(FsckOptions { repair: true, ..Default::default() }).await
+    }
+
+    pub async fn fsck(&self, opts: FsckOptions) -> Result<FsckReport> {
+        self.blocking(move |s| s.fsck(&opts)).await
+    }
+}
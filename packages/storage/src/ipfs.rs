@@ -0,0 +1,94 @@
+//! Minimal client for the Kubo (go-ipfs) HTTP RPC API.
+//!
+//! Only the calls the store needs are implemented. Unlike
+//! `ipfs-api-backend-hyper`, the futures here are `Send`, so they can be
+//! awaited directly from axum handlers and spawned tasks.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Default Kubo RPC endpoint.
+pub const DEFAULT_IPFS_URL: &str = "http://127.0.0.1:5001";
+
+#[derive(Clone, Debug)]
+pub struct IpfsClient {
+    base: String,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct AddResponse {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+impl IpfsClient {
+    /// Create a client for the RPC API at `url` (defaults to [`DEFAULT_IPFS_URL`]).
+    pub fn new(url: Option<&str>) -> Result<Self> {
+        let base = url.unwrap_or(DEFAULT_IPFS_URL).trim_end_matches('/').to_string();
+        reqwest::Url::parse(&base).context("invalid IPFS url")?;
+        Ok(Self { base, http: reqwest::Client::new() })
+    }
+
+    /// Add and pin a local file, streaming it from disk. Returns the CID.
+    pub async fn add_file(&self, path: &Path) -> Result<String> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .with_context(|| format!("opening {path:?}"))?;
+        let len = file.metadata().await?.len();
+        let part = reqwest::multipart::Part::stream_with_length(file, len).file_name("blob");
+        self.add(part).await
+    }
+
+    /// Add and pin an in-memory buffer. Returns the CID.
+    pub async fn add_bytes(&self, data: Vec<u8>) -> Result<String> {
+        self.add(reqwest::multipart::Part::bytes(data).file_name("blob")).await
+    }
+
+    /// Fetch the content behind `cid` into `out`, failing once more than
+    /// `limit` bytes arrive. Returns the number of bytes written.
+    pub async fn cat_to<W: AsyncWrite + Unpin>(&self, cid: &str, out: &mut W, limit: u64) -> Result<u64> {
+        let mut resp = self
+            .http
+            .post(format!("{}/api/v0/cat", self.base))
+            .query(&[("arg", cid)])
+            .send()
+            .await
+            .context("IPFS cat request failed")?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            bail!("IPFS cat {cid} failed with {status}: {body}");
+        }
+        let mut total = 0u64;
+        while let Some(chunk) = resp.chunk().await? {
+            total += chunk.len() as u64;
+            if total > limit {
+                bail!("IPFS content {cid} exceeds {limit} bytes");
+            }
+            out.write_all(&chunk).await?;
+        }
+        out.flush().await?;
+        Ok(total)
+    }
+
+    async fn add(&self, part: reqwest::multipart::Part) -> Result<String> {
+        let form = reqwest::multipart::Form::new().part("file", part);
+        let resp = self
+            .http
+            .post(format!("{}/api/v0/add?pin=true", self.base))
+            .multipart(form)
+            .send()
+            .await
+            .context("IPFS add request failed")?;
+        let status = resp.status();
+        let body = resp.text().await?;
+        if !status.is_success() {
+            bail!("IPFS add failed with {status}: {body}");
+        }
+        let added: AddResponse = serde_json::from_str(&body).context("unexpected IPFS add response")?;
+        Ok(added.hash)
+    }
+}
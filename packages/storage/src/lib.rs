@@ -10,232 +10,2102 @@ use {
     anyhow::{bail, Context, Result},
     serde::{Deserialize, Serialize},
     sha2::{Digest, Sha256},
-    sled::Db,
+    sled::{Db, Transactional},
     std::{
         fs,
         io::Write,
         path::{Path, PathBuf},
+        sync::{Arc, Mutex, MutexGuard},
         time::{SystemTime, UNIX_EPOCH},
     },
+    tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
 };
 
+#[cfg(feature = "std")]
+pub mod blob;
+#[cfg(feature = "std")]
+pub use blob::{BlobBackend, BlobEntry, BlobReader, BlobStore, FsBlobStore, MemBlobStore, PrefixedBlobStore, S3Config};
+#[cfg(all(feature = "std", feature = "s3"))]
+pub use blob::S3BlobStore;
+#[cfg(all(feature = "std", feature = "ipfs"))]
+pub mod ipfs;
+#[cfg(feature = "std")]
+pub mod content;
+#[cfg(feature = "std")]
+pub use content::ContentType;
+#[cfg(feature = "std")]
+pub mod fsck;
+#[cfg(feature = "std")]
+pub use fsck::{FsckOptions, FsckReport, Mismatch};
+#[cfg(feature = "std")]
+pub mod scrub;
+#[cfg(feature = "std")]
+pub use scrub::{ScrubOptions, ScrubReport, DEFAULT_SCRUB_BYTES_PER_SEC};
+#[cfg(feature = "std")]
+pub mod pdf;
+#[cfg(feature = "std")]
+pub use pdf::{ActiveContentPolicy, PdfValidation};
+#[cfg(all(feature = "std", feature = "search"))]
+pub mod search;
+#[cfg(all(feature = "std", feature = "search"))]
+pub use search::{SearchHit, DEFAULT_SEARCH_LIMIT};
+#[cfg(feature = "std")]
+pub mod upload;
+#[cfg(feature = "std")]
+pub use upload::{Checksum, ChecksumAlgorithm, Upload};
+#[cfg(feature = "std")]
+pub mod crypt;
+#[cfg(feature = "std")]
+pub use crypt::{Kek, RotateReport, WrappedKey};
+#[cfg(feature = "std")]
+pub mod compress;
+#[cfg(feature = "std")]
+pub use compress::{Codec, RecompressReport, DEFAULT_ZSTD_LEVEL};
+#[cfg(feature = "std")]
+pub mod chunk;
+#[cfg(feature = "std")]
+pub use chunk::{StorageLayout, StoreStats};
+pub mod merkle;
+pub use merkle::{verify_range, MerkleProof, MERKLE_CHUNK_BYTES};
+pub mod multihash;
+pub use multihash::ContentId;
+#[cfg(feature = "std")]
+pub mod bao;
+#[cfg(feature = "std")]
+pub use bao::{BackfillReport, BaoDecoder};
+#[cfg(feature = "std")]
+pub mod tenant;
+#[cfg(feature = "std")]
+pub use tenant::Tenants;
+#[cfg(feature = "std")]
+pub mod query;
+#[cfg(feature = "std")]
+pub use query::{DocPage, DocQuery, ReindexReport, SortOrder, DEFAULT_QUERY_LIMIT, MAX_QUERY_LIMIT};
+
 /// 32-byte SHA-256 digest
 pub type Hash32 = [u8; 32];
 
-/// Metadata tracked for each stored PDF
+/// Metadata tracked for each stored PDF: one document record joined with
+/// the content blob it points to.
 #[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct DocMeta {
-    /// Hex-encoded primary key (sha256)
+    /// Hex-encoded record id (primary key). Records migrated from stores
+    /// that predate document records keep their sha256 as id.
     pub id_hex: String,
     pub filename: String,
+    /// MIME type the uploader declared.
     pub mime: String,
+    /// MIME type sniffed from the stored bytes; what downloads are served as.
+    #[cfg_attr(feature = "std", serde(default = "pdf_mime"))]
+    pub content_type: String,
     pub size_bytes: u64,
     pub sha256: Hash32,
+    /// BLAKE3 digest of the content, see [`bao`]. `None` until a backfill
+    /// for older documents.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub blake3: Option<Hash32>,
+    /// Root of the Merkle tree over the content, for checking range reads
+    /// with [`verify_range`]. `None` until a reindex for older documents.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub merkle_root: Option<Hash32>,
     pub created_at_unix_ms: u64,
     /// Optional IPFS CID (or other content address)
     pub cid: Option<String>,
+    /// CID of the ciphertext pinned instead of the plaintext; see
+    /// [`StoreConfig::encrypt_pins`].
+    #[cfg_attr(feature = "std", serde(default))]
+    pub encrypted_cid: Option<String>,
+    #[cfg_attr(feature = "std", serde(default))]
+    pub uploader: Option<String>,
+    #[cfg_attr(feature = "std", serde(default))]
+    pub tags: Vec<String>,
+    /// Owner of the property the document belongs to.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub owner: Option<String>,
+    /// Property the document belongs to.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub property_id: Option<String>,
+    /// Hex id of the logical document this record is a version of: the
+    /// record id of its first version.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub document_id: String,
+    /// 1-based position in the document's version history.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub version: u32,
+    /// Hex record id of the version this one replaces.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub supersedes: Option<String>,
+    /// Content hash of the version this one replaces.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub supersedes_sha256: Option<Hash32>,
+    /// Hash returned when the record was published on chain.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub anchor: Option<String>,
+    /// Set while the record is in the trash.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub deleted: Option<Tombstone>,
+    /// When the scrubber last verified the stored bytes.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub last_verified_at_unix_ms: Option<u64>,
+    /// Set while the bytes are quarantined as corrupt.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub quarantined_at_unix_ms: Option<u64>,
+    /// What the PDF says about itself; `None` if it could not be parsed.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub pdf: Option<PdfInfo>,
+    /// Set when the stored bytes are a sanitized derivative of the upload.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub sanitized: Option<Sanitized>,
+}
+
+/// Who moved a record to the trash, when and why.
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Tombstone {
+    pub deleted_by: Option<String>,
+    pub deleted_at_unix_ms: u64,
+    pub reason: Option<String>,
+}
+
+/// Structural metadata read from the PDF at ingest.
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PdfInfo {
+    /// PDF version from the header, or the catalog when that is newer.
+    pub version: String,
+    pub page_count: u32,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+    pub producer: Option<String>,
+    /// Info dictionary dates as RFC 3339, or verbatim if malformed.
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    /// Raw XMP packet from the catalog's `/Metadata` stream.
+    pub xmp: Option<String>,
+    pub encrypted: bool,
+    pub linearized: bool,
+    /// Number of AcroForm fields.
+    pub form_fields: u32,
+    /// Structural defects the file was accepted with.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub defects: Vec<PdfDefect>,
+    /// Kinds of active content found in the file.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub active_content: Vec<ActiveContent>,
+    /// Set when the file was too large to parse at ingest: only `version`
+    /// is known, and neither validation nor the active content policy ran.
+    #[cfg_attr(feature = "std", serde(default))]
+    pub uninspected: bool,
+}
+
+/// Content that acts when a PDF is opened or clicked.
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "std", serde(rename_all = "snake_case"))]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ActiveContent {
+    /// `/JS` scripts, `/JavaScript` actions or a document-level script tree.
+    JavaScript,
+    /// `/Launch` actions that start other programs.
+    Launch,
+    /// A catalog `/OpenAction` or `/AA` run when the document opens.
+    OpenAction,
+    /// Embedded files and file attachments.
+    EmbeddedFile,
+    /// XFA form data.
+    Xfa,
+    /// `/URI`, `/SubmitForm` or remote go-to actions.
+    Uri,
+}
+
+impl fmt::Display for ActiveContent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::JavaScript => "JavaScript",
+            Self::Launch => "launch action",
+            Self::OpenAction => "open action",
+            Self::EmbeddedFile => "embedded file",
+            Self::Xfa => "XFA form",
+            Self::Uri => "remote URI",
+        })
+    }
+}
+
+/// Provenance of a sanitized derivative: what was uploaded and what was
+/// taken out. The record's `sha256` is the derivative's.
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Sanitized {
+    pub original_sha256: Hash32,
+    pub original_size_bytes: u64,
+    pub removed: Vec<ActiveContent>,
+}
+
+/// Structural problem found while validating a PDF.
+#[cfg_attr(feature = "std", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "std", serde(tag = "kind", rename_all = "snake_case"))]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum PdfDefect {
+    /// No `%%EOF` marker near the end of the file.
+    MissingEof,
+    /// No `startxref` pointing at the cross-reference section.
+    MissingStartxref,
+    /// The xref table or stream cannot be read.
+    BrokenXref { detail: String },
+    /// Neither a `trailer` dictionary nor an xref stream is present.
+    MissingTrailer,
+    /// Objects listed in the cross-reference section that fail to parse.
+    BadObjects { count: u32 },
+    /// No document catalog could be found.
+    NoCatalog,
+    /// The catalog's page tree cannot be loaded or has no pages.
+    PageTree { detail: String },
+    /// The page tree's `/Count` disagrees with the pages it contains.
+    PageCountMismatch { declared: u32, actual: u32 },
+}
+
+impl PdfDefect {
+    /// Whether the file can still be read once its cross-reference section
+    /// is rebuilt. Files without a catalog or page tree cannot.
+    pub fn repairable(&self) -> bool {
+        !matches!(self, Self::NoCatalog | Self::PageTree { .. })
+    }
+}
+
+impl fmt::Display for PdfDefect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingEof => f.write_str("missing %%EOF marker"),
+            Self::MissingStartxref => f.write_str("missing startxref"),
+            Self::BrokenXref { detail } => write!(f, "unreadable cross-reference section: {detail}"),
+            Self::MissingTrailer => f.write_str("missing trailer"),
+            Self::BadObjects { count } => write!(f, "{count} object(s) fail to parse"),
+            Self::NoCatalog => f.write_str("no document catalog"),
+            Self::PageTree { detail } => write!(f, "broken page tree: {detail}"),
+            Self::PageCountMismatch { declared, actual } => {
+                write!(f, "page tree declares {declared} page(s) but has {actual}")
+            }
+        }
+    }
+}
+
+/// Stores written before content sniffing held nothing but PDFs.
+#[cfg(feature = "std")]
+fn pdf_mime() -> String {
+    content::PDF.into()
+}
+
+/// Content blob shared by every record with the same bytes. Stored in the
+/// `blobs` tree keyed by sha256.
+#[cfg(feature = "std")]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct BlobMeta {
+    pub sha256: Hash32,
+    /// BLAKE3 digest of the bytes, indexed in `idx_blake3`; the blob's Bao
+    /// outboard is stored under [`bao::bao_key`].
+    #[serde(default)]
+    pub blake3: Option<Hash32>,
+    /// Root of the Merkle tree over the bytes, see [`merkle`]. Leaf hashes
+    /// are kept in the `merkle` tree.
+    #[serde(default)]
+    pub merkle_root: Option<Hash32>,
+    pub size_bytes: u64,
+    /// Detected MIME type, see [`content::REGISTRY`].
+    #[serde(default = "pdf_mime")]
+    pub content_type: String,
+    pub cid: Option<String>,
+    /// CID of the sealed copy pinned to IPFS, under the blob's data key.
+    #[serde(default)]
+    pub encrypted_cid: Option<String>,
+    /// Number of document records pointing at this blob.
+    pub refs: u64,
+    pub created_at_unix_ms: u64,
+    /// When the scrubber last confirmed the bytes still hash to `sha256`.
+    #[serde(default)]
+    pub last_verified_at_unix_ms: Option<u64>,
+    /// Set while corrupted bytes sit in quarantine with nothing to serve.
+    #[serde(default)]
+    pub quarantined_at_unix_ms: Option<u64>,
+    #[serde(default)]
+    pub pdf: Option<PdfInfo>,
+    #[serde(flatten)]
+    pub encoding: BlobEncoding,
+}
+
+#[cfg(feature = "std")]
+impl BlobMeta {
+    /// CIDs the blob is pinned under, each indexed in `idx_cid`.
+    pub(crate) fn cids(&self) -> impl Iterator<Item = &String> {
+        self.cid.iter().chain(&self.encrypted_cid)
+    }
+
+    /// Bytes the blob takes up in the backend.
+    pub(crate) fn stored_size(&self) -> u64 {
+        if let Some(chunks) = self.encoding.chunks {
+            return chunk::manifest_len(chunks);
+        }
+        let encoded = self.encoded_size();
+        match &self.encoding.encryption {
+            Some(_) => crypt::sealed_len(encoded),
+            None => encoded,
+        }
+    }
+
+    /// Bytes after compression, before sealing.
+    pub(crate) fn encoded_size(&self) -> u64 {
+        self.encoding.compressed_size.unwrap_or(self.size_bytes)
+    }
+}
+
+/// How a blob's bytes are transformed at rest.
+#[cfg(feature = "std")]
+#[derive(Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub struct BlobEncoding {
+    /// Data key the bytes are sealed with, see [`crypt`]. `None` for blobs
+    /// stored in plaintext.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<WrappedKey>,
+    /// Compression applied before sealing, see [`compress`].
+    #[serde(default, skip_serializing_if = "Codec::is_none")]
+    pub codec: Codec,
+    /// Size of the compressed bytes; `None` when `codec` is `none`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed_size: Option<u64>,
+    /// Number of chunks when the blob is stored as a manifest over the chunk
+    /// store, see [`chunk`]. Chunks carry their own codec.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<u64>,
+}
+
+/// One upload of a blob, with its own name and provenance. Stored in the
+/// `docs` tree keyed by record id.
+#[cfg(feature = "std")]
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct DocRecord {
+    pub id: Hash32,
+    pub sha256: Hash32,
+    pub filename: String,
+    pub mime: String,
+    pub uploader: Option<String>,
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub property_id: Option<String>,
+    pub created_at_unix_ms: u64,
+    /// First version's record id; `None` on records written before
+    /// versioning, which are their own first version.
+    #[serde(default)]
+    pub lineage: Option<Hash32>,
+    #[serde(default = "first_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub supersedes: Option<Hash32>,
+    #[serde(default)]
+    pub supersedes_sha256: Option<Hash32>,
+    #[serde(default)]
+    pub anchor: Option<String>,
+    #[serde(default)]
+    pub deleted: Option<Tombstone>,
+    #[serde(default)]
+    pub sanitized: Option<Sanitized>,
+}
+
+#[cfg(feature = "std")]
+fn first_version() -> u32 {
+    1
+}
+
+#[cfg(feature = "std")]
+impl DocRecord {
+    /// Id of the logical document this record belongs to.
+    pub fn lineage(&self) -> Hash32 {
+        self.lineage.unwrap_or(self.id)
+    }
+
+    /// Public view of this record.
+    pub fn to_meta(&self, blob: &BlobMeta) -> DocMeta {
+        DocMeta {
+            id_hex: hex::encode(self.id),
+            filename: self.filename.clone(),
+            mime: self.mime.clone(),
+            content_type: blob.content_type.clone(),
+            size_bytes: blob.size_bytes,
+            sha256: self.sha256,
+            blake3: blob.blake3,
+            merkle_root: blob.merkle_root,
+            created_at_unix_ms: self.created_at_unix_ms,
+            cid: blob.cid.clone(),
+            encrypted_cid: blob.encrypted_cid.clone(),
+            last_verified_at_unix_ms: blob.last_verified_at_unix_ms,
+            quarantined_at_unix_ms: blob.quarantined_at_unix_ms,
+            pdf: blob.pdf.clone(),
+            uploader: self.uploader.clone(),
+            tags: self.tags.clone(),
+            owner: self.owner.clone(),
+            property_id: self.property_id.clone(),
+            document_id: hex::encode(self.lineage()),
+            version: self.version,
+            supersedes: self.supersedes.map(hex::encode),
+            supersedes_sha256: self.supersedes_sha256,
+            anchor: self.anchor.clone(),
+            deleted: self.deleted.clone(),
+            sanitized: self.sanitized.clone(),
+        }
+    }
+}
+
+/// Caller-supplied details for a new document record.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DocInput {
+    pub filename: String,
+    pub mime: String,
+    pub uploader: Option<String>,
+    pub tags: Vec<String>,
+    pub owner: Option<String>,
+    pub property_id: Option<String>,
+    /// Hex record id of the version this upload replaces. It must be the
+    /// latest version of its document.
+    pub supersedes: Option<String>,
+    /// sha256 the client says it sent; a different upload is rejected with
+    /// [`StoreError::DigestMismatch`] before anything is stored or pinned.
+    pub expected_sha256: Option<Hash32>,
+}
+
+#[cfg(feature = "std")]
+impl DocInput {
+    pub fn new(filename: impl Into<String>, mime: impl Into<String>) -> Self {
+        Self { filename: filename.into(), mime: mime.into(), ..Default::default() }
+    }
+
+    /// Filename and MIME type derived from a local path.
+    pub fn from_path(path: &Path) -> Self {
+        let filename = path
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "unknown.pdf".into());
+        let mime = mime_guess::from_path(path)
+            .first_or_octet_stream()
+            .essence_str()
+            .to_string();
+        Self::new(filename, mime)
+    }
+}
+
+/// Errors callers may want to tell apart, e.g. to pick an HTTP status.
+/// They travel inside `anyhow::Error`; use `downcast_ref::<StoreError>()`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreError {
+    /// Input is not one of the content types this store accepts;
+    /// `detected` names its type if the registry recognised it.
+    UnsupportedType { detected: Option<String> },
+    /// Input has the magic bytes of an accepted type but fails its validator.
+    InvalidContent { mime: String, reason: String },
+    /// Input is larger than the configured upload limit.
+    TooLarge { limit: u64 },
+    /// A referenced document record does not exist.
+    NotFound { id: String },
+    /// The version named in `supersedes` already has a successor.
+    AlreadySuperseded { id: String, by: String },
+    /// The record is in the trash.
+    Deleted { id: String },
+    /// The operation needs a record that is in the trash.
+    NotDeleted { id: String },
+    /// The record stayed in the trash past the retention period.
+    RetentionExpired { id: String },
+    /// A query filter, sort order or cursor is malformed.
+    InvalidQuery(String),
+    /// The PDF structure has defects the validation policy does not accept.
+    InvalidPdf { defects: Vec<PdfDefect> },
+    /// The PDF carries active content and the policy is to reject it.
+    ActiveContent { found: Vec<ActiveContent> },
+    /// No resumable upload has this id.
+    UnknownUpload { id: String },
+    /// A chunk was sent for an offset other than the upload's current one.
+    UploadOffsetMismatch { expected: u64 },
+    /// A chunk did not match its checksum and was discarded.
+    ChecksumMismatch { algorithm: String },
+    /// Another request is appending to or completing the upload.
+    UploadLocked { id: String },
+    /// The upload has not received all its bytes yet.
+    UploadIncomplete { offset: u64, length: u64 },
+    /// The upload's sha256 differs from the digest the client sent.
+    DigestMismatch { expected: String, actual: String },
+    /// A tenant name that cannot be used as a namespace.
+    InvalidTenant { name: String },
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedType { detected: Some(mime) } => write!(f, "content type {mime} is not accepted"),
+            Self::UnsupportedType { detected: None } => f.write_str("unrecognised content type"),
+            Self::InvalidContent { mime, reason } => write!(f, "invalid {mime} file: {reason}"),
+            Self::TooLarge { limit } => write!(f, "upload exceeds the {limit} byte limit"),
+            Self::NotFound { id } => write!(f, "document {id} not found"),
+            Self::AlreadySuperseded { id, by } => write!(f, "document {id} is already superseded by {by}"),
+            Self::Deleted { id } => write!(f, "document {id} is in the trash"),
+            Self::NotDeleted { id } => write!(f, "document {id} is not in the trash"),
+            Self::RetentionExpired { id } => write!(f, "document {id} is past its retention period"),
+            Self::InvalidQuery(msg) => write!(f, "invalid query: {msg}"),
+            Self::InvalidPdf { defects } => {
+                f.write_str("invalid PDF")?;
+                for (i, defect) in defects.iter().enumerate() {
+                    write!(f, "{}{defect}", if i == 0 { ": " } else { "; " })?;
+                }
+                Ok(())
+            }
+            Self::ActiveContent { found } => {
+                f.write_str("PDF contains active content")?;
+                for (i, kind) in found.iter().enumerate() {
+                    write!(f, "{}{kind}", if i == 0 { ": " } else { ", " })?;
+                }
+                Ok(())
+            }
+            Self::UnknownUpload { id } => write!(f, "upload {id} not found"),
+            Self::UploadOffsetMismatch { expected } => write!(f, "upload is at offset {expected}"),
+            Self::ChecksumMismatch { algorithm } => write!(f, "chunk does not match its {algorithm} checksum"),
+            Self::UploadLocked { id } => write!(f, "upload {id} is busy"),
+            Self::UploadIncomplete { offset, length } => write!(f, "upload has {offset} of {length} bytes"),
+            Self::DigestMismatch { expected, actual } => {
+                write!(f, "upload has sha256 {actual} but its digest header says {expected}")
+            }
+            Self::InvalidTenant { name } => {
+                write!(f, "invalid tenant name {name:?}: use up to 64 lowercase letters, digits, '-' or '_'")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StoreError {}
+
+/// Default cap on a single upload: 256 MiB.
+#[cfg(feature = "std")]
+pub const DEFAULT_MAX_UPLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Default cap on a PDF loaded for parsing: 64 MiB.
+#[cfg(feature = "std")]
+pub const DEFAULT_MAX_PDF_INSPECT_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Default time an idle resumable upload is kept: one day.
+#[cfg(feature = "std")]
+pub const DEFAULT_UPLOAD_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
+/// Settings a store is opened with.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoreConfig {
+    pub backend: BlobBackend,
+    /// Uploads larger than this are rejected while they stream in.
+    pub max_upload_bytes: u64,
+    /// PDFs are read into memory to be parsed; larger ones are stored
+    /// unparsed and marked [`PdfInfo::uninspected`].
+    pub max_pdf_inspect_bytes: u64,
+    /// Resumable uploads nothing was appended to for this long are
+    /// discarded; `0` keeps them until they finish or are terminated.
+    pub upload_expiry_secs: u64,
+    /// How long deleted records stay restorable before they are purged.
+    pub retention_secs: u64,
+    /// Never purge records anchored on chain automatically; they must be
+    /// purged one by one with [`DocStore::purge`].
+    pub explicit_purge_anchored: bool,
+    /// Which structural PDF defects ingest rejects.
+    pub pdf_validation: PdfValidation,
+    /// What ingest does with PDFs carrying active content.
+    pub active_content: ActiveContentPolicy,
+    /// MIME types uploads may have (`type/*` allowed); see [`content::allowed`].
+    pub allowed_types: Vec<String>,
+    /// Seal new blobs under this key-encryption key; see [`crypt`].
+    pub kek: Option<Kek>,
+    /// Pin a sealed copy to IPFS instead of the plaintext. Needs `kek`.
+    pub encrypt_pins: bool,
+    /// Compress new blobs at rest with this codec; see [`compress`].
+    pub compression: Codec,
+    /// zstd level used when `compression` is `zstd`.
+    pub compression_level: i32,
+    /// Store new blobs whole or as deduplicated chunks; see [`chunk`].
+    pub layout: StorageLayout,
 }
 
+/// Default trash retention: 30 days.
+#[cfg(feature = "std")]
+pub const DEFAULT_RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+
+#[cfg(feature = "std")]
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: BlobBackend::Fs,
+            max_upload_bytes: DEFAULT_MAX_UPLOAD_BYTES,
+            max_pdf_inspect_bytes: DEFAULT_MAX_PDF_INSPECT_BYTES,
+            upload_expiry_secs: DEFAULT_UPLOAD_EXPIRY_SECS,
+            retention_secs: DEFAULT_RETENTION_SECS,
+            explicit_purge_anchored: false,
+            pdf_validation: PdfValidation::Strict,
+            active_content: ActiveContentPolicy::Flag,
+            allowed_types: vec![content::PDF.into()],
+            kek: None,
+            encrypt_pins: false,
+            compression: Codec::None,
+            compression_level: DEFAULT_ZSTD_LEVEL,
+            layout: StorageLayout::Whole,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> Result<T>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(v) => v.parse().with_context(|| format!("invalid {name} {v:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(feature = "std")]
+impl StoreConfig {
+    /// Read settings from `STORE_BACKEND` (see [`BlobBackend::from_env`]),
+    /// `MAX_UPLOAD_BYTES`, `RETENTION_SECS`, `EXPLICIT_PURGE_ANCHORED`,
+    /// `PDF_VALIDATION` (`strict`, `repairable` or `off`),
+    /// `ACTIVE_CONTENT` (`flag`, `reject` or `sanitize`),
+    /// `ALLOWED_TYPES` (comma-separated MIME types), the KEK
+    /// (see [`Kek::from_env`]), `IPFS_ENCRYPT`, `COMPRESSION` (`none` or
+    /// `zstd`), `ZSTD_LEVEL` and `STORAGE_LAYOUT` (`whole` or `chunked`).
+    pub fn from_env() -> Result<Self> {
+        let defaults = Self::default();
+        Ok(Self {
+            backend: BlobBackend::from_env()?,
+            max_upload_bytes: env_or("MAX_UPLOAD_BYTES", defaults.max_upload_bytes)?,
+            max_pdf_inspect_bytes: env_or("MAX_PDF_INSPECT_BYTES", defaults.max_pdf_inspect_bytes)?,
+            upload_expiry_secs: env_or("UPLOAD_EXPIRY_SECS", defaults.upload_expiry_secs)?,
+            retention_secs: env_or("RETENTION_SECS", defaults.retention_secs)?,
+            explicit_purge_anchored: env_or("EXPLICIT_PURGE_ANCHORED", defaults.explicit_purge_anchored)?,
+            pdf_validation: env_or("PDF_VALIDATION", defaults.pdf_validation)?,
+            active_content: env_or("ACTIVE_CONTENT", defaults.active_content)?,
+            allowed_types: match std::env::var("ALLOWED_TYPES") {
+                Ok(v) => v.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect(),
+                Err(_) => defaults.allowed_types,
+            },
+            kek: Kek::from_env()?,
+            encrypt_pins: env_or("IPFS_ENCRYPT", defaults.encrypt_pins)?,
+            compression: env_or("COMPRESSION", defaults.compression)?,
+            compression_level: env_or("ZSTD_LEVEL", defaults.compression_level)?,
+            layout: env_or("STORAGE_LAYOUT", defaults.layout)?,
+        })
+    }
+}
+
+/// Content-addressed document store.
+///
+/// Cloning is cheap: all clones share the same database and blob backend.
+/// Every data operation is async; sled and blob I/O run on tokio's blocking
+/// pool so callers never stall a runtime worker.
 #[cfg(feature = "std")]
 #[derive(Clone)]
 pub struct DocStore {
+    inner: Arc<StoreInner>,
+}
+
+#[cfg(feature = "std")]
+struct StoreInner {
     root: PathBuf,
+    /// Namespace of the trees and blobs, see [`tenant`].
+    tenant: Option<String>,
     kv: Db,
+    /// sha256 -> [`BlobMeta`]
+    blob_index: sled::Tree,
+    /// record id -> [`DocRecord`]
+    docs: sled::Tree,
+    /// sha256 ++ record id -> ()
+    blob_refs: sled::Tree,
+    /// document id ++ version -> record id
+    versions: sled::Tree,
+    blob_locks: Vec<Mutex<()>>,
+    /// chunk sha256 -> [`chunk::ChunkMeta`]
+    chunk_index: sled::Tree,
+    chunk_locks: Vec<Mutex<()>>,
+    /// sha256 -> Merkle leaf hashes, 32 bytes each
+    merkle: sled::Tree,
+    /// BLAKE3 digest -> sha256
+    blake3_index: sled::Tree,
+    /// value ++ 0 ++ created_at ++ record id -> (), one tree per [`query::Index`]
+    indexes: [sled::Tree; 5],
+    /// cid ++ 0 ++ sha256 -> ()
+    cid_index: sled::Tree,
+    /// Full-text index under `<root>/search`
+    #[cfg(feature = "search")]
+    text_index: search::SearchIndex,
+    /// "last" -> most recent [`ScrubReport`]
+    scrub_state: sled::Tree,
+    /// Serialises scrub passes.
+    scrub_lock: tokio::sync::Mutex<()>,
+    /// Store-wide markers, e.g. which blob layout is in use.
+    meta: sled::Tree,
+    /// upload id -> [`Upload`]; bytes under `<root>/uploads`
+    uploads: sled::Tree,
+    /// Uploads a request is currently appending to or completing.
+    upload_locks: Mutex<std::collections::HashSet<String>>,
+    blobs: Arc<dyn BlobStore>,
+    config: StoreConfig,
+    /// Resolved from `config.allowed_types`.
+    allowed: Vec<&'static ContentType>,
 }
 
 #[cfg(feature = "std")]
 impl fmt::Debug for DocStore {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("DocStore")
-            .field("root", &self.root)
+            .field("root", &self.inner.root)
+            .field("tenant", &self.inner.tenant)
+            .field("blobs", &self.inner.blobs)
             .finish_non_exhaustive()
     }
 }
 
+/// Blob key for a document's content: its hex sha256, whatever the type.
 #[cfg(feature = "std")]
-impl DocStore {
-    /// Open or create a database at the given root directory.
-    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
-        let root = root.as_ref().to_path_buf();
-        fs::create_dir_all(root.join("pdfs")).context("creating pdfs dir")?;
-        let kv = sled::open(root.join("kv"))?;
-        Ok(Self { root, kv })
+pub fn blob_key(sha256: &Hash32) -> String {
+    hex::encode(sha256)
+}
+
+/// Key blobs had while the store only held PDFs.
+#[cfg(feature = "std")]
+fn legacy_blob_key(sha256: &Hash32) -> String {
+    format!("{}.pdf", hex::encode(sha256))
+}
+
+/// `meta` key set once blobs use [`blob_key`].
+#[cfg(feature = "std")]
+const LAYOUT_KEY: &[u8] = b"blob_layout_v2";
+
+/// Upload hashed and magic-checked into the staging area, not yet stored.
+#[cfg(feature = "std")]
+struct Staged {
+    temp: tempfile::NamedTempFile,
+    sha256: Hash32,
+    /// BLAKE3 digest of `temp`.
+    blake3: Hash32,
+    size_bytes: u64,
+    /// Merkle leaf hashes of `temp`, see [`merkle`].
+    leaves: Vec<Hash32>,
+    /// Filled in by [`StoreInner::inspect_staged`].
+    inspected: pdf::Inspected,
+    /// Set when `temp` now holds a sanitized derivative of the upload.
+    sanitized: Option<Sanitized>,
+    /// Detected by [`StoreInner::inspect_staged`].
+    content_type: &'static ContentType,
+    /// Set once a sealed copy of `temp` was pinned.
+    sealed: Option<crypt::SealedPin>,
+}
+
+/// What ingest learned about a blob's bytes.
+#[cfg(feature = "std")]
+struct BlobFacts {
+    size_bytes: u64,
+    sha256: Hash32,
+    blake3: Hash32,
+    merkle_root: Hash32,
+    content_type: &'static str,
+    pdf: Option<PdfInfo>,
+    /// Set when this ingest wrote the bytes.
+    written: Option<BlobEncoding>,
+    /// CID of a sealed copy this ingest pinned, and the data key it is under.
+    encrypted_cid: Option<(String, WrappedKey)>,
+}
+
+/// Hashes chunks on their way into a uniquely named staging file under
+/// `<root>/tmp`, so every input (path, reader or async stream) is read exactly
+/// once. The caller writes each chunk to `temp` after [`Stager::absorb`]
+/// accepts it. The temp file is removed whenever the stager or the resulting
+/// [`Staged`] is dropped without being committed, including when an upload
+/// future is cancelled.
+#[cfg(feature = "std")]
+struct Stager<'a> {
+    hasher: Sha256,
+    blake3: blake3::Hasher,
+    merkle: merkle::MerkleBuilder,
+    temp: tempfile::NamedTempFile,
+    total: u64,
+    head: Vec<u8>,
+    limit: u64,
+    allowed: &'a [&'static ContentType],
+}
+
+#[cfg(feature = "std")]
+impl<'a> Stager<'a> {
+    fn new(dir: &Path, limit: u64, allowed: &'a [&'static ContentType]) -> Result<Self> {
+        Ok(Self {
+            hasher: Sha256::new(),
+            blake3: blake3::Hasher::new(),
+            merkle: Default::default(),
+            temp: tempfile::Builder::new().prefix("upload-").tempfile_in(dir)?,
+            total: 0,
+            head: Vec::new(),
+            limit,
+            allowed,
+        })
     }
 
-    /// Get the root directory path.
-    pub fn root(&self) -> &Path {
-        &self.root
+    /// Hash a chunk, failing fast once the first bytes rule out every
+    /// allowed content type, or on an oversized upload.
+    fn absorb(&mut self, chunk: &[u8]) -> Result<()> {
+        self.total += chunk.len() as u64;
+        if self.total > self.limit {
+            return Err(StoreError::TooLarge { limit: self.limit }.into());
+        }
+        if self.head.len() < content::SNIFF_BYTES {
+            let take = (content::SNIFF_BYTES - self.head.len()).min(chunk.len());
+            self.head.extend_from_slice(&chunk[..take]);
+            if !self.allowed.iter().any(|t| t.could_match(&self.head)) {
+                let detected = content::REGISTRY.iter().find(|t| t.matches(&self.head));
+                return Err(StoreError::UnsupportedType { detected: detected.map(|t| t.mime.to_string()) }.into());
+            }
+        }
+        self.hasher.update(chunk);
+        self.blake3.update(chunk);
+        self.merkle.update(chunk);
+        Ok(())
     }
 
-    fn write_blob_and_index(
+    /// The full type check waits for [`StoreInner::inspect_staged`], which
+    /// sees the whole file.
+    fn finish(self) -> Result<Staged> {
+        Ok(Staged {
+            temp: self.temp,
+            sha256: self.hasher.finalize().into(),
+            blake3: *self.blake3.finalize().as_bytes(),
+            size_bytes: self.total,
+            leaves: self.merkle.finish(),
+            inspected: Default::default(),
+            sanitized: None,
+            // replaced once inspect_staged has seen the whole file
+            content_type: &content::REGISTRY[0],
+            sealed: None,
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(feature = "std")]
+fn new_record_id() -> Result<Hash32> {
+    let mut id = [0u8; 32];
+    getrandom::getrandom(&mut id).map_err(|e| anyhow::anyhow!("generating record id: {e}"))?;
+    Ok(id)
+}
+
+/// Key in the `blob_refs` tree: blob sha256 followed by record id.
+#[cfg(feature = "std")]
+fn ref_key(sha256: &Hash32, id: &Hash32) -> Vec<u8> {
+    [sha256.as_slice(), id.as_slice()].concat()
+}
+
+/// Key in the `versions` tree: document id followed by big-endian version,
+/// so a prefix scan yields the history in order.
+#[cfg(feature = "std")]
+fn version_key(lineage: &Hash32, version: u32) -> Vec<u8> {
+    [lineage.as_slice(), &version.to_be_bytes()].concat()
+}
+
+#[cfg(feature = "std")]
+type TxError = sled::transaction::ConflictableTransactionError<anyhow::Error>;
+
+#[cfg(feature = "std")]
+fn abort<E: Into<anyhow::Error>>(e: E) -> TxError {
+    TxError::Abort(e.into())
+}
+
+#[cfg(feature = "std")]
+fn tx_result<T>(r: sled::transaction::TransactionResult<T, anyhow::Error>) -> Result<T> {
+    r.map_err(|e| match e {
+        sled::transaction::TransactionError::Abort(e) => e,
+        sled::transaction::TransactionError::Storage(e) => e.into(),
+    })
+}
+
+/// Number of lock stripes serialising blob creation and removal per sha256.
+#[cfg(feature = "std")]
+pub(crate) const BLOB_LOCK_STRIPES: usize = 64;
+
+#[cfg(feature = "std")]
+fn parse_id(id_hex: &str) -> Result<Hash32> {
+    let bytes = hex::decode(id_hex)?;
+    if bytes.len() != 32 {
+        bail!("expected 32-byte id, got {}", bytes.len());
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+/// A bare hex record id or sha256, or a [`ContentId`] naming either digest.
+#[cfg(feature = "std")]
+fn parse_key(id: &str) -> Result<ContentId> {
+    match id.len() {
+        64 => Ok(ContentId::Sha256(parse_id(id)?)),
+        _ => Ok(id.parse()?),
+    }
+}
+
+#[cfg(feature = "std")]
+impl StoreInner {
+    /// Record id or sha256 to resolve a lookup by, mapping a BLAKE3 digest
+    /// through `idx_blake3`.
+    fn lookup(&self, key: &ContentId) -> Result<Option<Hash32>> {
+        match key {
+            ContentId::Sha256(key) => Ok(Some(*key)),
+            ContentId::Blake3(blake3) => match self.blake3_index.get(blake3)? {
+                Some(sha256) => Ok(Some(sha256.as_ref().try_into().context("corrupt idx_blake3 entry")?)),
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Id of the record `key` names, resolved like [`StoreInner::resolve`].
+    fn record_id(&self, key: &ContentId) -> Result<Option<Hash32>> {
+        match self.lookup(key)? {
+            Some(key) => Ok(self.resolve(&key)?.map(|r| r.id)),
+            None => Ok(None),
+        }
+    }
+
+    /// Hold while creating or removing the blob for `sha256`, so a new record
+    /// never points at bytes a concurrent delete is about to remove.
+    fn lock_blob(&self, sha256: &Hash32) -> MutexGuard<'_, ()> {
+        let stripe = sha256[0] as usize % BLOB_LOCK_STRIPES;
+        self.blob_locks[stripe].lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn blob_meta(&self, sha256: &Hash32) -> Result<Option<BlobMeta>> {
+        let Some(val) = self.blob_index.get(sha256)? else { return Ok(None) };
+        Ok(Some(serde_json::from_slice(&val)?))
+    }
+
+    /// Record named by `input.supersedes`, if any.
+    fn predecessor(&self, input: &DocInput) -> Result<Option<DocRecord>> {
+        let Some(id_hex) = input.supersedes.as_deref() else { return Ok(None) };
+        let not_found = || StoreError::NotFound { id: id_hex.to_string() };
+        let id = parse_id(id_hex).map_err(|_| not_found())?;
+        let record = self.record(&id)?.ok_or_else(not_found)?;
+        if record.deleted.is_some() {
+            return Err(StoreError::Deleted { id: id_hex.to_string() }.into());
+        }
+        Ok(Some(record))
+    }
+
+    /// Fail early if `input.supersedes` names a missing or already replaced
+    /// version. `insert_record` re-checks atomically.
+    fn check_supersedes(&self, input: &DocInput) -> Result<()> {
+        let Some(pred) = self.predecessor(input)? else { return Ok(()) };
+        if let Some(next) = self.versions.get(version_key(&pred.lineage(), pred.version + 1))? {
+            return Err(StoreError::AlreadySuperseded { id: hex::encode(pred.id), by: hex::encode(next) }.into());
+        }
+        Ok(())
+    }
+
+    /// Add a document record for a committed blob, bumping its reference count
+    /// and appending it to its document's version history.
+    fn insert_record(
         &self,
-        input_path: &Path,
-        size_bytes: u64,
-        sha256_bytes: [u8; 32],
+        input: &DocInput,
+        facts: BlobFacts,
         cid: Option<String>,
+        sanitized: Option<Sanitized>,
     ) -> Result<DocMeta> {
-        let id_hex = hex::encode(sha256_bytes);
-        let filename = input_path
-            .file_name()
-            .map(|s| s.to_string_lossy().into_owned())
-            .unwrap_or_else(|| "unknown.pdf".into());
-        let mime = mime_guess::from_path(input_path)
-            .first_or_octet_stream()
-            .essence_str()
-            .to_string();
-        // Persist file as content-addressed blob (renamed from temp by caller)
-        let _out_path = self.root.join("pdfs").join(format!("{id_hex}.pdf"));
-        let created_at_unix_ms = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
-        let meta = DocMeta {
-            id_hex: id_hex.clone(),
-            filename,
+        let BlobFacts { size_bytes, sha256, blake3, merkle_root, content_type, pdf, written, encrypted_cid } = facts;
+        // a missing or generic declared type is replaced by the sniffed one
+        let mime = match input.mime.as_str() {
+            "" | "application/octet-stream" => content_type.to_string(),
+            declared => declared.to_string(),
+        };
+        let pred = self.predecessor(input)?;
+        let id = new_record_id()?;
+        let record = DocRecord {
+            id,
+            sha256,
+            filename: input.filename.clone(),
             mime,
-            size_bytes,
-            sha256: sha256_bytes,
-            created_at_unix_ms,
-            cid,
+            uploader: input.uploader.clone(),
+            tags: input.tags.clone(),
+            owner: input.owner.clone(),
+            property_id: input.property_id.clone(),
+            created_at_unix_ms: now_ms(),
+            lineage: Some(pred.as_ref().map_or(id, DocRecord::lineage)),
+            version: pred.as_ref().map_or(1, |p| p.version + 1),
+            supersedes: pred.as_ref().map(|p| p.id),
+            supersedes_sha256: pred.as_ref().map(|p| p.sha256),
+            anchor: None,
+            deleted: None,
+            sanitized,
         };
-        let key = sha256_bytes;
-        let val = serde_json::to_vec(&meta)?;
-        self.kv.insert(key, val)?;
+        let record_val = serde_json::to_vec(&record)?;
+        let [i0, i1, i2, i3, i4] = &self.indexes;
+        let trees = (&self.blob_index, &self.docs, &self.blob_refs, &self.versions, i0, i1, i2, i3, i4, &self.cid_index);
+        let blob = tx_result(trees.transaction(|(blobs, docs, refs, versions, i0, i1, i2, i3, i4, cids)| {
+            if let Some(pred) = &pred {
+                if docs.get(pred.id)?.is_none() {
+                    return Err(abort(StoreError::NotFound { id: hex::encode(pred.id) }));
+                }
+                let lineage = pred.lineage();
+                if let Some(next) = versions.get(version_key(&lineage, pred.version + 1))? {
+                    return Err(abort(StoreError::AlreadySuperseded {
+                        id: hex::encode(pred.id),
+                        by: hex::encode(next),
+                    }));
+                }
+                let pred_key = version_key(&lineage, pred.version);
+                if versions.get(&pred_key)?.is_none() {
+                    // written before versioning
+                    versions.insert(pred_key, &pred.id)?;
+                }
+            }
+            versions.insert(version_key(&record.lineage(), record.version), &record.id)?;
+            let mut blob = match blobs.get(sha256)? {
+                Some(val) => serde_json::from_slice::<BlobMeta>(&val).map_err(abort)?,
+                None => BlobMeta {
+                    sha256,
+                    blake3: None,
+                    merkle_root: None,
+                    size_bytes,
+                    content_type: content_type.to_string(),
+                    cid: None,
+                    encrypted_cid: None,
+                    refs: 0,
+                    created_at_unix_ms: record.created_at_unix_ms,
+                    last_verified_at_unix_ms: None,
+                    quarantined_at_unix_ms: None,
+                    pdf: None,
+                    encoding: BlobEncoding::default(),
+                },
+            };
+            blob.refs += 1;
+            if let Some(encoding) = &written {
+                blob.encoding = encoding.clone();
+            }
+            // the caller just wrote or found intact bytes
+            blob.quarantined_at_unix_ms = None;
+            if blob.pdf.is_none() {
+                blob.pdf = pdf.clone();
+            }
+            blob.blake3.get_or_insert(blake3);
+            blob.merkle_root.get_or_insert(merkle_root);
+            if blob.cid.is_none() {
+                if let Some(cid) = &cid {
+                    cids.insert(query::cid_key(cid, &sha256), &[])?;
+                }
+                blob.cid = cid.clone();
+            }
+            // a copy sealed under another data key (a concurrent ingest won
+            // the race) could not be opened with this blob's
+            match &encrypted_cid {
+                Some((cid, wrapped)) if blob.encrypted_cid.is_none() && blob.encoding.encryption.as_ref() == Some(wrapped) => {
+                    cids.insert(query::cid_key(cid, &sha256), &[])?;
+                    blob.encrypted_cid = Some(cid.clone());
+                }
+                _ => {}
+            }
+            blobs.insert(&sha256, serde_json::to_vec(&blob).map_err(abort)?)?;
+            docs.insert(&record.id, record_val.as_slice())?;
+            refs.insert(ref_key(&sha256, &record.id), &[])?;
+            query::index_record([i0, i1, i2, i3, i4], &record, true)?;
+            Ok(blob)
+        }))?;
         self.kv.flush()?;
-        Ok(meta)
+        Ok(record.to_meta(&blob))
     }
 
-    /// Store a PDF from `input_path`, returning its metadata.
-    /// Copies the file into `<root>/pdfs/<sha256>.pdf` and indexes metadata in sled.
-    pub fn store_pdf<P: AsRef<Path>>(&self, input_path: P, cid: Option<String>) -> Result<DocMeta> {
-        use std::io::Read;
-        let input_path = input_path.as_ref();
-        let mut file = fs::File::open(input_path)
-            .with_context(|| format!("opening {input_path:?}"))?;
-        // Stream to a temp file while hashing
-        let mut hasher = Sha256::new();
-        let mut temp = tempfile::NamedTempFile::new_in(self.root.join("pdfs"))?;
+    /// Move blobs from `<sha256>.pdf` keys to bare [`blob_key`]s, once per
+    /// store. Returns how many were moved.
+    fn migrate_layout(&self) -> Result<usize> {
+        if self.meta.contains_key(LAYOUT_KEY)? {
+            return Ok(0);
+        }
+        let mut moved = 0;
+        for item in self.blob_index.iter() {
+            let blob: BlobMeta = serde_json::from_slice(&item?.1)?;
+            let (old, new) = (legacy_blob_key(&blob.sha256), blob_key(&blob.sha256));
+            let Some(mut reader) = self.blobs.stream(&old)? else { continue };
+            if !self.blobs.exists(&new)? {
+                self.blobs.put(&new, &mut reader)?;
+            }
+            drop(reader);
+            self.blobs.delete(&old)?;
+            moved += 1;
+        }
+        self.meta.insert(LAYOUT_KEY, &[])?;
+        self.kv.flush()?;
+        Ok(moved)
+    }
+
+    /// Fold entries written before document records existed (sha256 -> `DocMeta`
+    /// in the default tree) into the blob and record trees, keeping their ids.
+    /// Returns how many were migrated.
+    fn migrate_legacy(&self) -> Result<usize> {
+        let mut migrated = 0;
+        // the default tree predates tenants; it is the unscoped store's
+        if self.tenant.is_some() {
+            return Ok(0);
+        }
+        for item in self.kv.iter() {
+            let (key, val) = item?;
+            let legacy: DocMeta = serde_json::from_slice(&val)?;
+            let blob = BlobMeta {
+                sha256: legacy.sha256,
+                blake3: None,
+                merkle_root: None,
+                size_bytes: legacy.size_bytes,
+                content_type: pdf_mime(),
+                cid: legacy.cid.clone(),
+                encrypted_cid: None,
+                refs: 1,
+                created_at_unix_ms: legacy.created_at_unix_ms,
+                last_verified_at_unix_ms: None,
+                quarantined_at_unix_ms: None,
+                pdf: None,
+                encoding: BlobEncoding::default(),
+            };
+            let record = DocRecord {
+                id: legacy.sha256,
+                sha256: legacy.sha256,
+                filename: legacy.filename,
+                mime: legacy.mime,
+                uploader: legacy.uploader,
+                tags: legacy.tags,
+                owner: legacy.owner,
+                property_id: legacy.property_id,
+                created_at_unix_ms: legacy.created_at_unix_ms,
+                lineage: Some(legacy.sha256),
+                version: 1,
+                supersedes: None,
+                supersedes_sha256: None,
+                anchor: None,
+                deleted: None,
+                sanitized: None,
+            };
+            let (blob_val, record_val) = (serde_json::to_vec(&blob)?, serde_json::to_vec(&record)?);
+            let trees = (&*self.kv, &self.blob_index, &self.docs, &self.blob_refs, &self.versions);
+            tx_result(trees.transaction(
+                |(kv, blobs, docs, refs, versions)| {
+                    blobs.insert(&blob.sha256, blob_val.as_slice())?;
+                    docs.insert(&record.id, record_val.as_slice())?;
+                    refs.insert(ref_key(&blob.sha256, &record.id), &[])?;
+                    versions.insert(version_key(&record.id, 1), &record.id)?;
+                    kv.remove(&key)?;
+                    Ok(())
+                },
+            ))?;
+            migrated += 1;
+        }
+        self.kv.flush()?;
+        Ok(migrated)
+    }
+
+    /// Spool `reader` into the staging dir while hashing it, then validate it.
+    fn stage_reader<R: std::io::Read>(&self, mut reader: R) -> Result<Staged> {
+        let mut stager = Stager::new(&self.root.join("tmp"), self.config.max_upload_bytes, &self.allowed)?;
         let mut buf = [0u8; 8192];
-        let mut total: u64 = 0;
-        let mut first_chunk = Vec::new();
         loop {
-            let n = file.read(&mut buf)?;
+            let n = reader.read(&mut buf)?;
             if n == 0 { break; }
-            hasher.update(&buf[..n]);
-            temp.write_all(&buf[..n])?;
-            if total == 0 { first_chunk.extend_from_slice(&buf[..n.min(8)]); }
-            total += n as u64;
-        }
-        // Basic PDF magic check
-        if !(first_chunk.starts_with(b"%PDF-")) {
-            let _ = temp.close();
-            bail!("not a PDF file");
-        }
-        let sha256_bytes: [u8; 32] = hasher.finalize().into();
-        let id_hex = hex::encode(sha256_bytes);
-        let final_path = self.root.join("pdfs").join(format!("{id_hex}.pdf"));
-        if final_path.exists() {
+            stager.absorb(&buf[..n])?;
+            stager.temp.write_all(&buf[..n])?;
+        }
+        self.inspect_staged(stager.finish()?)
+    }
+
+    /// Move a staged upload into the blob store unless the content is already
+    /// there, then add a record for it. Holds the blob lock throughout.
+    fn store_staged(&self, staged: Staged, input: &DocInput, cid: Option<String>) -> Result<DocMeta> {
+        let (sha256, size_bytes) = (staged.sha256, staged.size_bytes);
+        let (inspected, sanitized, content_type) = (staged.inspected, staged.sanitized, staged.content_type);
+        let (sealed_temp, encrypted_cid) = match staged.sealed {
+            Some(pin) => (Some(pin.temp), Some((pin.cid, pin.wrapped))),
+            None => (None, None),
+        };
+        let guard = self.lock_blob(&sha256);
+        // built from the staged bytes before they move; a duplicate keeps
+        // the outboard it has
+        let outboard = match self.blobs.exists(&bao::bao_key(&sha256))? {
+            true => None,
+            false => {
+                let (blake3, outboard) = bao::outboard(staged.temp.reopen()?, size_bytes)?;
+                if blake3 != staged.blake3 {
+                    bail!("staged upload changed while it was being stored");
+                }
+                Some(outboard)
+            }
+        };
+        let key = blob_key(&sha256);
+        let existing = self.blob_meta(&sha256)?;
+        let written = if existing.is_some() && self.blobs.exists(&key)? {
             // duplicate; discard temp
-            let _ = temp.close();
+            let _ = staged.temp.close();
+            None
+        } else if let Some(existing) = &existing {
+            // indexed but its bytes are gone (quarantined): its outboard and
+            // encrypted pin are sealed under its data key, so keep that
+            Some(self.rewrite_blob(existing, staged.temp)?)
+        } else if let Some((_, wrapped)) = &encrypted_cid {
+            if let (Some(temp), Codec::None) = (sealed_temp, self.config.compression) {
+                // keep exactly what was pinned
+                self.blobs.put_file(&key, temp)?;
+                Some(BlobEncoding { encryption: Some(wrapped.clone()), ..Default::default() })
+            } else {
+                let dek = self.data_key(&sha256, wrapped)?;
+                let (codec, compressed_size) =
+                    self.encode_blob(&sha256, staged.temp, size_bytes, self.config.compression, Some(&dek))?;
+                Some(BlobEncoding { encryption: Some(wrapped.clone()), codec, compressed_size, chunks: None })
+            }
         } else {
-            temp.persist(&final_path)?;
+            Some(self.write_blob(&sha256, staged.temp, size_bytes)?)
+        };
+        if let Some(outboard) = outboard {
+            // sealed like the blob it describes
+            let encryption = match (&written, existing) {
+                (Some(encoding), _) => encoding.encryption.clone(),
+                (None, existing) => existing.and_then(|b| b.encoding.encryption),
+            };
+            self.put_outboard(&sha256, encryption.as_ref(), &outboard)?;
         }
-        self.write_blob_and_index(input_path, total, sha256_bytes, cid)
+        // same bytes, same leaves: rewriting them for a duplicate is harmless
+        self.put_merkle_leaves(&sha256, &staged.leaves)?;
+        self.blake3_index.insert(staged.blake3, &sha256)?;
+        let (blake3, merkle_root) = (staged.blake3, merkle::root(&staged.leaves));
+        let facts = BlobFacts {
+            size_bytes,
+            sha256,
+            blake3,
+            merkle_root,
+            content_type: content_type.mime,
+            pdf: inspected.info,
+            written,
+            encrypted_cid,
+        };
+        let meta = self.insert_record(input, facts, cid, sanitized)?;
+        drop(guard);
+        #[cfg(feature = "search")]
+        self.search_add(&meta.id_hex, &meta.filename, &inspected.pages);
+        Ok(meta)
     }
 
-    /// Store a PDF and pin its bytes to IPFS, saving the returned CID in metadata.
-    /// This is MANDATORY for full decentralization - always enabled.
-    pub fn store_pdf_with_ipfs<P: AsRef<Path>>(
+    /// Put verified plaintext into the blob store, compressed as configured
+    /// and sealed under a fresh data key when a KEK is configured. Returns
+    /// how the bytes were stored.
+    fn write_blob(&self, sha256: &Hash32, temp: tempfile::NamedTempFile, size: u64) -> Result<BlobEncoding> {
+        if self.config.layout == StorageLayout::Chunked {
+            return self.write_chunked(sha256, temp, false);
+        }
+        let key = self.new_data_key(sha256)?;
+        let dek = key.as_ref().map(|(dek, _)| dek);
+        let (codec, compressed_size) = self.encode_blob(sha256, temp, size, self.config.compression, dek)?;
+        Ok(BlobEncoding { encryption: key.map(|(_, wrapped)| wrapped), codec, compressed_size, chunks: None })
+    }
+
+    /// Put a `size` byte plaintext into the blob store: compressed with
+    /// `codec` unless that saves nothing, then sealed under `dek` if given.
+    /// Returns the codec used and the compressed size.
+    pub(crate) fn encode_blob(
         &self,
-        input_path: P,
-        ipfs_url: Option<&str>,
-    ) -> Result<DocMeta> {
-use ipfs_api_backend_hyper::{IpfsApi, IpfsClient};
-        use ipfs_api_prelude::TryFromUri;
-        use std::io::{Cursor, Read};
+        sha256: &Hash32,
+        plain: tempfile::NamedTempFile,
+        size: u64,
+        codec: Codec,
+        dek: Option<&crypt::DataKey>,
+    ) -> Result<(Codec, Option<u64>)> {
+        let (temp, len, codec) = match codec {
+            Codec::Zstd => match self.compress_temp(&plain)? {
+                (packed, len) if len < size => (packed, len, Codec::Zstd),
+                _ => (plain, size, Codec::None),
+            },
+            Codec::None => (plain, size, Codec::None),
+        };
+        match dek {
+            Some(dek) => self.put_sealed(sha256, temp.reopen()?, len, dek)?,
+            None => {
+                self.blobs.put_file(&blob_key(sha256), temp)?;
+            }
+        }
+        Ok((codec, (codec == Codec::Zstd).then_some(len)))
+    }
 
-        let input_path = input_path.as_ref();
-        // Read file fully and compute hash
-        let mut file = fs::File::open(input_path)
-            .with_context(|| format!("opening {input_path:?}"))?;
-        let mut hasher = Sha256::new();
-        let mut temp = tempfile::NamedTempFile::new_in(self.root.join("pdfs"))?;
-        let mut buf = [0u8; 8192];
-        let mut total: u64 = 0;
-        let mut first_chunk = Vec::new();
-        loop {
-            let n = file.read(&mut buf)?;
-            if n == 0 { break; }
-            hasher.update(&buf[..n]);
-            temp.write_all(&buf[..n])?;
-            if total == 0 { first_chunk.extend_from_slice(&buf[..n.min(8)]); }
-            total += n as u64;
-        }
-        if !(first_chunk.starts_with(b"%PDF-")) {
-            let _ = temp.close();
-            bail!("not a PDF file");
-        }
-        let sha256_bytes: [u8; 32] = hasher.finalize().into();
-
-        // Pin to IPFS
-        let cid = {
-            let client = match ipfs_url {
-                Some(u) => IpfsClient::from_str(u).context("invalid IPFS url")?,
-                None => IpfsClient::default(),
-            };
-            // Read file into memory for IPFS
-            let mut file_data = Vec::new();
-            std::fs::File::open(temp.path())?.read_to_end(&mut file_data)?;
-            
-            let rt = tokio::runtime::Runtime::new()?;
-            let add_resp = rt.block_on(client.add(Cursor::new(file_data)))?;
-            Some(add_resp.hash)
+    /// Put verified plaintext back for an indexed blob, under its data key
+    /// and codec, or as chunks, overwriting any corrupted ones. Returns the
+    /// new encoding, which the caller records.
+    pub(crate) fn rewrite_blob(&self, blob: &BlobMeta, temp: tempfile::NamedTempFile) -> Result<BlobEncoding> {
+        if blob.encoding.chunks.is_some() {
+            return self.write_chunked(&blob.sha256, temp, true);
+        }
+        let dek = blob.encoding.encryption.as_ref().map(|w| self.data_key(&blob.sha256, w)).transpose()?;
+        let (codec, compressed_size) = self.encode_blob(&blob.sha256, temp, blob.size_bytes, blob.encoding.codec, dek.as_ref())?;
+        Ok(BlobEncoding { encryption: blob.encoding.encryption.clone(), codec, compressed_size, chunks: None })
+    }
+
+    /// Drop what was derived from a blob's bytes once its index entry is
+    /// gone: Merkle leaves, BLAKE3 index entry and Bao outboard. Kept while
+    /// a blob is only quarantined.
+    pub(crate) fn forget_blob(&self, blob: &BlobMeta) -> Result<()> {
+        self.merkle.remove(blob.sha256)?;
+        if let Some(blake3) = blob.blake3 {
+            self.blake3_index.remove(blake3)?;
+        }
+        self.blobs.delete(&bao::bao_key(&blob.sha256))?;
+        Ok(())
+    }
+
+    /// Remove a blob's bytes from the backend, releasing its chunks if it is
+    /// chunked. The manifest goes first, so a crash can only leak chunks.
+    pub(crate) fn delete_blob(&self, blob: &BlobMeta) -> Result<()> {
+        let key = blob_key(&blob.sha256);
+        let manifest = match blob.encoding.chunks {
+            Some(_) => self.blobs.get(&key)?,
+            None => None,
         };
-        let id_hex = hex::encode(sha256_bytes);
-        let final_path = self.root.join("pdfs").join(format!("{id_hex}.pdf"));
-        if final_path.exists() { let _ = temp.close(); } else { temp.persist(&final_path)?; }
-        self.write_blob_and_index(input_path, total, sha256_bytes, cid)
+        self.blobs.delete(&key)?;
+        if let Some(manifest) = manifest {
+            self.release_manifest(&manifest)?;
+        }
+        Ok(())
     }
 
-    /// Fetch metadata by hex id.
-pub fn get_by_hex(&self, id_hex: &str) -> Result<Option<DocMeta>> {
-        let bytes = hex::decode(id_hex)?;
-        if bytes.len() != 32 {
-            bail!("expected 32-byte id, got {}", bytes.len());
+    /// Stream `len` bytes of a blob as stored, after decrypting but before
+    /// decompressing, from `offset`. `None` if the bytes are missing.
+    fn open_encoded(&self, blob: &BlobMeta, offset: u64, len: u64) -> Result<Option<Box<dyn std::io::Read + Send>>> {
+        match &blob.encoding.encryption {
+            Some(wrapped) => self.open_sealed(blob, wrapped, blob.encoded_size(), offset, len),
+            None => self.blobs.stream_range(&blob_key(&blob.sha256), offset, len),
         }
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&bytes);
-        let Some(val) = self.kv.get(key)? else { return Ok(None) };
-        let meta: DocMeta = serde_json::from_slice(&val)?;
-        Ok(Some(meta))
     }
 
-    /// List all stored PDF metadata.
-    pub fn list(&self) -> Result<Vec<DocMeta>> {
+    /// Stream `len` plaintext bytes of a blob from `offset`, decrypting and
+    /// decompressing as needed. A compressed blob is decoded from its start.
+    /// `None` if the bytes are missing.
+    pub(crate) fn open_blob_range(&self, blob: &BlobMeta, offset: u64, len: u64) -> Result<Option<Box<dyn std::io::Read + Send>>> {
+        if blob.encoding.chunks.is_some() {
+            return self.open_chunked(blob, offset, len);
+        }
+        match blob.encoding.codec {
+            Codec::None => self.open_encoded(blob, offset, len),
+            Codec::Zstd => {
+                let Some(packed) = self.open_encoded(blob, 0, blob.encoded_size())? else { return Ok(None) };
+                let mut reader = compress::Decompress::new(packed)?;
+                std::io::copy(&mut std::io::Read::take(std::io::Read::by_ref(&mut reader), offset), &mut std::io::sink())?;
+                Ok(Some(Box::new(std::io::Read::take(reader, len))))
+            }
+        }
+    }
+
+    /// Stream a blob's whole plaintext.
+    pub(crate) fn open_blob(&self, blob: &BlobMeta) -> Result<Option<Box<dyn std::io::Read + Send>>> {
+        match (&blob.encoding.encryption, blob.encoding.codec, blob.encoding.chunks) {
+            (None, Codec::None, None) => self.blobs.stream(&blob_key(&blob.sha256)),
+            _ => self.open_blob_range(blob, 0, blob.size_bytes),
+        }
+    }
+
+    /// Read a blob's whole plaintext into memory.
+    pub(crate) fn read_plain(&self, blob: &BlobMeta) -> Result<Option<Vec<u8>>> {
+        if blob.encoding == BlobEncoding::default() {
+            return self.blobs.get(&blob_key(&blob.sha256));
+        }
+        let Some(mut reader) = self.open_blob(blob)? else { return Ok(None) };
+        let mut bytes = Vec::with_capacity(blob.size_bytes as usize);
+        std::io::Read::read_to_end(&mut reader, &mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Check bytes fetched from IPFS against a blob's sha256, decrypting
+    /// them first if they are its sealed pin. Returns the plaintext.
+    #[cfg(feature = "ipfs")]
+    fn verify_fetched(&self, blob: &BlobMeta, fetched: tempfile::NamedTempFile) -> Result<Option<tempfile::NamedTempFile>> {
+        let plain = match (&blob.encrypted_cid, &blob.encoding.encryption) {
+            (Some(_), Some(wrapped)) => match self.unseal_file(blob, wrapped, fetched)? {
+                Some(plain) => plain,
+                None => return Ok(None),
+            },
+            (Some(_), None) => bail!("blob {} has a sealed pin but no data key", hex::encode(blob.sha256)),
+            (None, _) => fetched,
+        };
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut plain.reopen()?, &mut hasher)?;
+        Ok((Hash32::from(hasher.finalize()) == blob.sha256).then_some(plain))
+    }
+
+    fn record(&self, id: &Hash32) -> Result<Option<DocRecord>> {
+        let Some(val) = self.docs.get(id)? else { return Ok(None) };
+        Ok(Some(serde_json::from_slice(&val)?))
+    }
+
+    fn record_meta(&self, record: &DocRecord) -> Result<DocMeta> {
+        let blob = self
+            .blob_meta(&record.sha256)?
+            .with_context(|| format!("record {} points at a missing blob", hex::encode(record.id)))?;
+        Ok(record.to_meta(&blob))
+    }
+
+    /// Records pointing at a blob, oldest first.
+    fn records_for_blob(&self, sha256: &Hash32) -> Result<Vec<DocRecord>> {
         let mut out = Vec::new();
-        for item in self.kv.iter() {
+        for item in self.blob_refs.scan_prefix(sha256) {
+            let (key, _) = item?;
+            let mut id = [0u8; 32];
+            id.copy_from_slice(&key[32..]);
+            if let Some(record) = self.record(&id)? {
+                out.push(record);
+            }
+        }
+        out.sort_by_key(|r| (r.created_at_unix_ms, r.id));
+        Ok(out)
+    }
+
+    /// Look up a record by id, falling back to the oldest record for a blob
+    /// when `key` is a content hash.
+    fn resolve(&self, key: &Hash32) -> Result<Option<DocRecord>> {
+        match self.record(key)? {
+            Some(record) => Ok(Some(record)),
+            None => Ok(self.records_for_blob(key)?.into_iter().next()),
+        }
+    }
+
+    fn get(&self, key: Hash32) -> Result<Option<DocMeta>> {
+        self.resolve(&key)?.map(|r| self.record_meta(&r)).transpose()
+    }
+
+    /// Every version of the document `key` belongs to, oldest first.
+    fn history(&self, key: Hash32) -> Result<Vec<DocMeta>> {
+        let Some(record) = self.resolve(&key)? else { return Ok(Vec::new()) };
+        let mut out = Vec::new();
+        for item in self.versions.scan_prefix(record.lineage()) {
+            let (_, id) = item?;
+            let id: Hash32 = id.as_ref().try_into().context("corrupt version entry")?;
+            if let Some(version) = self.record(&id)? {
+                out.push(self.record_meta(&version)?);
+            }
+        }
+        if out.is_empty() {
+            // written before versioning
+            out.push(self.record_meta(&record)?);
+        }
+        Ok(out)
+    }
+
+    fn records(&self) -> impl Iterator<Item = Result<DocRecord>> + '_ {
+        self.docs.iter().map(|item| {
             let (_, v) = item?;
-            let meta: DocMeta = serde_json::from_slice(&v)?;
-            out.push(meta);
+            Ok(serde_json::from_slice(&v)?)
+        })
+    }
+
+    /// Live records, or the trash when `trashed` is set.
+    fn list(&self, trashed: bool) -> Result<Vec<DocMeta>> {
+        let mut out = Vec::new();
+        for record in self.records() {
+            let record = record?;
+            if record.deleted.is_some() == trashed {
+                out.push(self.record_meta(&record)?);
+            }
         }
         Ok(out)
     }
 
-    /// Remove a PDF and its metadata.
-    pub fn delete_by_hex(&self, id_hex: &str) -> Result<bool> {
-        let Some(_meta) = self.get_by_hex(id_hex)? else { return Ok(false) };
-        // Remove file
-        let path = self.root.join("pdfs").join(format!("{id_hex}.pdf"));
-        let _ = fs::remove_file(path);
-        // Remove kv
-        let bytes = hex::decode(id_hex)?;
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&bytes);
-        self.kv.remove(key)?;
+    /// Read-modify-write one record. `f` returns whether anything changed,
+    /// which is passed back with the updated record.
+    fn update_record<F>(&self, id: &Hash32, f: F) -> Result<Option<(DocRecord, bool)>>
+    where
+        F: Fn(&mut DocRecord) -> Result<bool, StoreError>,
+    {
+        let updated = tx_result(self.docs.transaction(|docs| {
+            let Some(val) = docs.get(id)? else { return Ok(None) };
+            let mut record: DocRecord = serde_json::from_slice(&val).map_err(abort)?;
+            let changed = f(&mut record).map_err(abort)?;
+            if changed {
+                docs.insert(id, serde_json::to_vec(&record).map_err(abort)?)?;
+            }
+            Ok(Some((record, changed)))
+        }))?;
+        self.kv.flush()?;
+        Ok(updated)
+    }
+
+    fn expired(&self, tombstone: &Tombstone, now: u64) -> bool {
+        now.saturating_sub(tombstone.deleted_at_unix_ms) > self.config.retention_secs.saturating_mul(1000)
+    }
+
+    /// Move a record to the trash.
+    fn trash(&self, id: Hash32, tombstone: Tombstone) -> Result<bool> {
+        let updated = self.update_record(&id, |record| {
+            if record.deleted.is_some() {
+                return Ok(false);
+            }
+            record.deleted = Some(tombstone.clone());
+            Ok(true)
+        })?;
+        let trashed = updated.is_some_and(|(_, changed)| changed);
+        #[cfg(feature = "search")]
+        if trashed {
+            self.search_remove(&id);
+        }
+        Ok(trashed)
+    }
+
+    /// Take a record back out of the trash while it is within retention.
+    fn restore(&self, id: Hash32) -> Result<Option<DocMeta>> {
+        let now = now_ms();
+        let restored = self.update_record(&id, |record| {
+            let id = hex::encode(record.id);
+            match &record.deleted {
+                None => Err(StoreError::NotDeleted { id }),
+                Some(t) if self.expired(t, now) => Err(StoreError::RetentionExpired { id }),
+                Some(_) => {
+                    record.deleted = None;
+                    Ok(true)
+                }
+            }
+        })?;
+        let Some((record, _)) = restored else { return Ok(None) };
+        #[cfg(feature = "search")]
+        self.search_add_stored(&record);
+        self.record_meta(&record).map(Some)
+    }
+
+    fn set_anchor(&self, id: Hash32, anchor: &str) -> Result<Option<DocMeta>> {
+        let updated = self.update_record(&id, |record| {
+            record.anchor = Some(anchor.to_string());
+            Ok(true)
+        })?;
+        updated.map(|(r, _)| self.record_meta(&r)).transpose()
+    }
+
+    /// Permanently remove a trashed record; the blob goes with its last record.
+    fn purge(&self, id: Hash32) -> Result<bool> {
+        let Some(record) = self.record(&id)? else { return Ok(false) };
+        if record.deleted.is_none() {
+            return Err(StoreError::NotDeleted { id: hex::encode(id) }.into());
+        }
+        let _guard = self.lock_blob(&record.sha256);
+        let blob = self.blob_meta(&record.sha256)?;
+        let [i0, i1, i2, i3, i4] = &self.indexes;
+        let trees = (&self.blob_index, &self.docs, &self.blob_refs, &self.versions, i0, i1, i2, i3, i4, &self.cid_index);
+        let remaining = tx_result(trees.transaction(|(blobs, docs, refs, versions, i0, i1, i2, i3, i4, cids)| {
+            // a concurrent purge may have removed it, or a restore taken it
+            // out of the trash, since it was read above
+            let Some(val) = docs.remove(&id)? else { return Ok(None) };
+            let record: DocRecord = serde_json::from_slice(&val).map_err(abort)?;
+            if record.deleted.is_none() {
+                return Err(abort(StoreError::NotDeleted { id: hex::encode(id) }));
+            }
+            refs.remove(ref_key(&record.sha256, &id))?;
+            versions.remove(version_key(&record.lineage(), record.version))?;
+            query::index_record([i0, i1, i2, i3, i4], &record, false)?;
+            let Some(val) = blobs.get(record.sha256)? else { return Ok(Some(0)) };
+            let mut blob: BlobMeta = serde_json::from_slice(&val).map_err(abort)?;
+            blob.refs = blob.refs.saturating_sub(1);
+            if blob.refs == 0 {
+                blobs.remove(&record.sha256)?;
+                for cid in blob.cids() {
+                    cids.remove(query::cid_key(cid, &record.sha256))?;
+                }
+            } else {
+                blobs.insert(&record.sha256, serde_json::to_vec(&blob).map_err(abort)?)?;
+            }
+            Ok(Some(blob.refs))
+        }))?;
         self.kv.flush()?;
+        let Some(remaining) = remaining else { return Ok(false) };
+        if let (0, Some(blob)) = (remaining, blob) {
+            self.forget_blob(&blob)?;
+            self.delete_blob(&blob)?;
+        }
         Ok(true)
     }
+
+    /// Purge every trashed record past retention, except anchored ones when
+    /// they need an explicit purge.
+    fn purge_expired(&self) -> Result<PurgeReport> {
+        let now = now_ms();
+        let mut report = PurgeReport::default();
+        let mut due = Vec::new();
+        for record in self.records() {
+            let record = record?;
+            let Some(tombstone) = &record.deleted else { continue };
+            if !self.expired(tombstone, now) {
+                continue;
+            }
+            if record.anchor.is_some() && self.config.explicit_purge_anchored {
+                report.kept_anchored += 1;
+            } else {
+                due.push(record.id);
+            }
+        }
+        for id in due {
+            match self.purge(id) {
+                Ok(purged) => report.purged += usize::from(purged),
+                // restored since the scan
+                Err(e) if matches!(e.downcast_ref(), Some(StoreError::NotDeleted { .. })) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Outcome of a retention sweep.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PurgeReport {
+    pub purged: usize,
+    /// Expired but anchored on chain, so left for an explicit purge.
+    pub kept_anchored: usize,
+}
+
+#[cfg(feature = "std")]
+impl DocStore {
+    /// Open or create a database at the given root directory.
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        Self::open_with_backend(root, &BlobBackend::Fs)
+    }
+
+    /// Open a database whose blobs live in the given backend.
+    pub fn open_with_backend<P: AsRef<Path>>(root: P, backend: &BlobBackend) -> Result<Self> {
+        Self::open_with_config(root, StoreConfig { backend: backend.clone(), ..Default::default() })
+    }
+
+    /// Open a database with explicit settings.
+    pub fn open_with_config<P: AsRef<Path>>(root: P, config: StoreConfig) -> Result<Self> {
+        let root = root.as_ref();
+        fs::create_dir_all(root).context("creating database dir")?;
+        let blobs = config.backend.open(root)?;
+        let kv = sled::open(root.join("kv"))?;
+        Self::open_inner(root, kv, None, blobs, config)
+    }
+
+    /// Open a database with an already constructed blob store.
+    pub fn open_with_blobs<P: AsRef<Path>>(root: P, blobs: Arc<dyn BlobStore>) -> Result<Self> {
+        let root = root.as_ref();
+        let kv = sled::open(root.join("kv"))?;
+        Self::open_inner(root, kv, None, blobs, StoreConfig::default())
+    }
+
+    /// Open the store at `root` over `kv`; a tenant's trees are named
+    /// under its namespace (see [`tenant`]).
+    pub(crate) fn open_inner(root: &Path, kv: Db, tenant: Option<&str>, blobs: Arc<dyn BlobStore>, config: StoreConfig) -> Result<Self> {
+        let root = root.to_path_buf();
+        fs::create_dir_all(root.join("tmp")).context("creating tmp dir")?;
+        fs::create_dir_all(root.join("uploads")).context("creating uploads dir")?;
+        #[cfg(feature = "search")]
+        let text_index = search::SearchIndex::open(&root.join("search"))?;
+        if config.encrypt_pins && config.kek.is_none() {
+            bail!("encrypted pinning needs a KEK (STORE_KEK_FILE or STORE_KEK)");
+        }
+        if config.layout == StorageLayout::Chunked && config.kek.is_some() {
+            bail!("the chunked layout cannot be combined with encryption at rest");
+        }
+        let tree = |name: &str| match tenant {
+            Some(tenant) => kv.open_tree(tenant::tree_name(tenant, name)),
+            None => kv.open_tree(name),
+        };
+        let inner = StoreInner {
+            root,
+            tenant: tenant.map(String::from),
+            blob_index: tree("blobs")?,
+            docs: tree("docs")?,
+            blob_refs: tree("blob_refs")?,
+            versions: tree("versions")?,
+            chunk_index: tree("chunks")?,
+            merkle: tree("merkle")?,
+            blake3_index: tree("idx_blake3")?,
+            indexes: [
+                tree(query::Index::Created.tree_name())?,
+                tree(query::Index::Filename.tree_name())?,
+                tree(query::Index::Mime.tree_name())?,
+                tree(query::Index::Owner.tree_name())?,
+                tree(query::Index::Property.tree_name())?,
+            ],
+            cid_index: tree("idx_cid")?,
+            #[cfg(feature = "search")]
+            text_index,
+            scrub_state: tree("scrub")?,
+            meta: tree("meta")?,
+            uploads: tree("uploads")?,
+            upload_locks: Mutex::new(Default::default()),
+            scrub_lock: tokio::sync::Mutex::new(()),
+            kv,
+            blob_locks: (0..BLOB_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            chunk_locks: (0..BLOB_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+            blobs,
+            allowed: content::allowed(&config.allowed_types)?,
+            config,
+        };
+        if inner.migrate_legacy()? > 0 || inner.indexes_missing() {
+            inner.reindex()?;
+        }
+        inner.migrate_layout()?;
+        Ok(Self { inner: Arc::new(inner) })
+    }
+
+    /// Settings the store was opened with.
+    pub fn config(&self) -> &StoreConfig {
+        &self.inner.config
+    }
+
+    /// Get the root directory path.
+    pub fn root(&self) -> &Path {
+        &self.inner.root
+    }
+
+    /// Tenant the store belongs to, if it was opened through [`Tenants`].
+    pub fn tenant(&self) -> Option<&str> {
+        self.inner.tenant.as_deref()
+    }
+
+    /// Backend holding the document bytes.
+    pub fn blobs(&self) -> &dyn BlobStore {
+        self.inner.blobs.as_ref()
+    }
+
+    /// Run blocking sled or blob work on tokio's blocking pool.
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&StoreInner) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || f(&inner))
+            .await
+            .context("store task panicked")?
+    }
+
+    /// Read the stored bytes of a document.
+    pub async fn read_blob(&self, meta: &DocMeta) -> Result<Vec<u8>> {
+        let (sha256, id_hex) = (meta.sha256, meta.id_hex.clone());
+        self.blocking(move |s| {
+            let blob = s.blob_meta(&sha256)?.with_context(|| format!("blob for {id_hex} is not indexed"))?;
+            s.read_plain(&blob)?.with_context(|| format!("blob for {id_hex} is missing"))
+        })
+        .await
+    }
+
+    /// Stream `range` of a document's stored bytes, read from the backend
+    /// as the caller consumes them.
+    pub async fn stream_blob(&self, meta: &DocMeta, range: std::ops::Range<u64>) -> Result<BlobReader> {
+        if range.start > range.end || range.end > meta.size_bytes {
+            let msg = format!("range {}..{} is outside the {} byte document", range.start, range.end, meta.size_bytes);
+            return Err(StoreError::InvalidQuery(msg).into());
+        }
+        let (sha256, id_hex) = (meta.sha256, meta.id_hex.clone());
+        let reader = self
+            .blocking(move |s| {
+                let blob = s.blob_meta(&sha256)?.with_context(|| format!("blob for {id_hex} is not indexed"))?;
+                s.open_blob_range(&blob, range.start, range.end - range.start)?
+                    .with_context(|| format!("blob for {id_hex} is missing"))
+            })
+            .await?;
+        Ok(BlobReader::spawn(reader))
+    }
+
+    /// Spool an async stream into the staging dir while hashing it, then validate it.
+    async fn stage_stream<R: AsyncRead + Unpin>(&self, mut reader: R) -> Result<Staged> {
+        let mut stager = Stager::new(&self.inner.root.join("tmp"), self.inner.config.max_upload_bytes, &self.inner.allowed)?;
+        let mut out = tokio::fs::File::from_std(stager.temp.as_file().try_clone()?);
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 { break; }
+            stager.absorb(&buf[..n])?;
+            out.write_all(&buf[..n]).await?;
+        }
+        out.flush().await?;
+        let staged = stager.finish()?;
+        self.blocking(move |s| s.inspect_staged(staged)).await
+    }
+
+    /// Spool an async stream like [`Self::stage_stream`] and check it against
+    /// the digest the client sent, if any. A sanitized upload is checked by
+    /// the hash it arrived with.
+    async fn stage_input<R: AsyncRead + Unpin>(&self, reader: R, input: &DocInput) -> Result<Staged> {
+        let staged = self.stage_stream(reader).await?;
+        let Some(expected) = input.expected_sha256 else { return Ok(staged) };
+        let actual = staged.sanitized.as_ref().map_or(staged.sha256, |s| s.original_sha256);
+        if actual != expected {
+            return Err(StoreError::DigestMismatch { expected: hex::encode(expected), actual: hex::encode(actual) }.into());
+        }
+        Ok(staged)
+    }
+
+    async fn store_staged(&self, staged: Staged, input: DocInput, cid: Option<String>) -> Result<DocMeta> {
+        self.blocking(move |s| s.store_staged(staged, &input, cid)).await
+    }
+
+    async fn check_supersedes(&self, input: DocInput) -> Result<DocInput> {
+        self.blocking(move |s| s.check_supersedes(&input).map(|_| input)).await
+    }
+
+    /// Store a PDF read from an async stream as a new document record,
+    /// hashing and persisting it in one pass. Identical bytes are stored once
+    /// and shared between records. Set `input.supersedes` to add it as the
+    /// next version of an existing document.
+    pub async fn store<R: AsyncRead + Unpin>(&self, reader: R, input: DocInput) -> Result<DocMeta> {
+        let input = self.check_supersedes(input).await?;
+        let staged = self.stage_input(reader, &input).await?;
+        self.store_staged(staged, input, None).await
+    }
+
+    /// Store a PDF read from a blocking `reader`, hashing and persisting it in one pass.
+    pub async fn store_reader<R>(&self, reader: R, filename: &str, mime: &str) -> Result<DocMeta>
+    where
+        R: std::io::Read + Send + 'static,
+    {
+        let staged = self.blocking(move |s| s.stage_reader(reader)).await?;
+        self.store_staged(staged, DocInput::new(filename, mime), None).await
+    }
+
+    /// Store a PDF read from an async stream, hashing and persisting it in one pass.
+    pub async fn store_stream<R: AsyncRead + Unpin>(&self, reader: R, filename: &str, mime: &str) -> Result<DocMeta> {
+        self.store(reader, DocInput::new(filename, mime)).await
+    }
+
+    /// Store a PDF from `input_path`, returning its metadata.
+    /// Filename and MIME type are taken from the path.
+    pub async fn store_pdf<P: AsRef<Path>>(&self, input_path: P, cid: Option<String>) -> Result<DocMeta> {
+        let input_path = input_path.as_ref();
+        let file = tokio::fs::File::open(input_path)
+            .await
+            .with_context(|| format!("opening {input_path:?}"))?;
+        let staged = self.stage_stream(file).await?;
+        self.store_staged(staged, DocInput::from_path(input_path), cid).await
+    }
+
+    /// Store a PDF and pin its bytes to IPFS, saving the returned CID in metadata.
+    /// This is MANDATORY for full decentralization - always enabled.
+    #[cfg(feature = "ipfs")]
+    pub async fn store_pdf_with_ipfs<P: AsRef<Path>>(
+        &self,
+        input_path: P,
+        ipfs_url: Option<&str>,
+    ) -> Result<DocMeta> {
+        let input_path = input_path.as_ref();
+        let file = tokio::fs::File::open(input_path)
+            .await
+            .with_context(|| format!("opening {input_path:?}"))?;
+        self.store_with_ipfs(file, DocInput::from_path(input_path), ipfs_url).await
+    }
+
+    /// Store a PDF read from an async stream and pin it to IPFS.
+    /// Nothing is stored if pinning fails.
+    #[cfg(feature = "ipfs")]
+    pub async fn store_with_ipfs<R: AsyncRead + Unpin>(
+        &self,
+        reader: R,
+        input: DocInput,
+        ipfs_url: Option<&str>,
+    ) -> Result<DocMeta> {
+        let client = ipfs::IpfsClient::new(ipfs_url)?;
+        let input = self.check_supersedes(input).await?;
+        let mut staged = self.stage_input(reader, &input).await?;
+        if !self.inner.config.encrypt_pins {
+            let cid = client.add_file(staged.temp.path()).await?;
+            return self.store_staged(staged, input, Some(cid)).await;
+        }
+        let (sha256, size, path) = (staged.sha256, staged.size_bytes, staged.temp.path().to_path_buf());
+        if let Some((temp, wrapped)) = self.blocking(move |s| s.seal_for_pin(&sha256, &path, size)).await? {
+            let cid = client.add_file(temp.path()).await?;
+            staged.sealed = Some(crypt::SealedPin { temp, wrapped, cid });
+        }
+        self.store_staged(staged, input, None).await
+    }
+
+    /// Fetch a document's bytes back from IPFS, decrypting a sealed pin, and
+    /// check them against its sha256.
+    #[cfg(feature = "ipfs")]
+    pub async fn fetch_from_ipfs(&self, meta: &DocMeta, ipfs_url: Option<&str>) -> Result<Vec<u8>> {
+        let blob = self.get_blob(&meta.sha256).await?.with_context(|| format!("blob for {} is not indexed", meta.id_hex))?;
+        if blob.cids().next().is_none() {
+            bail!("{} is not pinned to IPFS", meta.id_hex);
+        }
+        let temp = self
+            .fetch_ipfs(&blob, ipfs_url)
+            .await?
+            .with_context(|| format!("IPFS content for {} does not match its sha256", meta.id_hex))?;
+        Ok(tokio::fs::read(temp.path()).await?)
+    }
+
+    /// Fetch a blob's plaintext from IPFS into the staging dir, preferring its
+    /// sealed pin. `None` if it has no CID or the content does not check out.
+    #[cfg(feature = "ipfs")]
+    pub(crate) async fn fetch_ipfs(&self, blob: &BlobMeta, ipfs_url: Option<&str>) -> Result<Option<tempfile::NamedTempFile>> {
+        let (cid, limit) = match (&blob.encrypted_cid, &blob.cid) {
+            (Some(cid), _) => (cid, crypt::sealed_len(blob.size_bytes)),
+            (None, Some(cid)) => (cid, blob.size_bytes),
+            (None, None) => return Ok(None),
+        };
+        let client = ipfs::IpfsClient::new(ipfs_url)?;
+        let temp = tempfile::Builder::new().prefix("refetch-").tempfile_in(self.inner.root.join("tmp"))?;
+        let mut out = tokio::fs::File::from_std(temp.as_file().try_clone()?);
+        client.cat_to(cid, &mut out, limit).await?;
+        let blob = blob.clone();
+        self.blocking(move |s| s.verify_fetched(&blob, temp)).await
+    }
+
+    /// Content blob behind a sha256, with its reference count.
+    pub async fn get_blob(&self, sha256: &Hash32) -> Result<Option<BlobMeta>> {
+        let sha256 = *sha256;
+        self.blocking(move |s| s.blob_meta(&sha256)).await
+    }
+
+    /// Every record pointing at a blob, oldest first.
+    pub async fn records_for_blob(&self, sha256: &Hash32) -> Result<Vec<DocMeta>> {
+        let sha256 = *sha256;
+        self.blocking(move |s| {
+            s.records_for_blob(&sha256)?.iter().map(|r| s.record_meta(r)).collect()
+        })
+        .await
+    }
+
+    /// Fetch metadata by record id. A sha256, bare or as a [`ContentId`], or
+    /// a BLAKE3 [`ContentId`] resolves to the oldest record for that content.
+    pub async fn get_by_hex(&self, id_hex: &str) -> Result<Option<DocMeta>> {
+        let key = parse_key(id_hex)?;
+        self.blocking(move |s| match s.lookup(&key)? {
+            Some(key) => s.get(key),
+            None => Ok(None),
+        })
+        .await
+    }
+
+    /// Every version of the document that record `id_hex` belongs to, oldest
+    /// first. Takes the same ids as [`Self::get_by_hex`]. Empty if the id is
+    /// unknown.
+    pub async fn history(&self, id_hex: &str) -> Result<Vec<DocMeta>> {
+        let key = parse_key(id_hex)?;
+        self.blocking(move |s| match s.lookup(&key)? {
+            Some(key) => s.history(key),
+            None => Ok(Vec::new()),
+        })
+        .await
+    }
+
+    /// Current version of the document that record `id_hex` belongs to.
+    /// Trashed versions are skipped.
+    pub async fn latest(&self, id_hex: &str) -> Result<Option<DocMeta>> {
+        let history = self.history(id_hex).await?;
+        Ok(history.into_iter().rev().find(|m| m.deleted.is_none()))
+    }
+
+    /// Version of the document that was current at `unix_ms`, if it existed yet.
+    /// Trashed versions are skipped.
+    pub async fn at(&self, id_hex: &str, unix_ms: u64) -> Result<Option<DocMeta>> {
+        let history = self.history(id_hex).await?;
+        Ok(history.into_iter().rev().find(|m| m.deleted.is_none() && m.created_at_unix_ms <= unix_ms))
+    }
+
+    /// List all stored PDF metadata, excluding the trash. Prefer
+    /// [`Self::query`] for large stores.
+    pub async fn list(&self) -> Result<Vec<DocMeta>> {
+        self.blocking(|s| s.list(false)).await
+    }
+
+    /// List records in the trash.
+    pub async fn list_trash(&self) -> Result<Vec<DocMeta>> {
+        self.blocking(|s| s.list(true)).await
+    }
+
+    /// Move a document record to the trash. It stays restorable for
+    /// [`StoreConfig::retention_secs`]. Returns false if it is unknown or
+    /// already trashed. Takes the same ids as [`Self::get_by_hex`], so a
+    /// content id trashes the oldest record for that content.
+    pub async fn trash(&self, id_hex: &str, deleted_by: Option<String>, reason: Option<String>) -> Result<bool> {
+        let key = parse_key(id_hex)?;
+        let tombstone = Tombstone { deleted_by, deleted_at_unix_ms: now_ms(), reason };
+        self.blocking(move |s| match s.record_id(&key)? {
+            Some(id) => s.trash(id, tombstone),
+            None => Ok(false),
+        })
+        .await
+    }
+
+    /// Move a document record to the trash without recording who or why.
+    pub async fn delete_by_hex(&self, id_hex: &str) -> Result<bool> {
+        self.trash(id_hex, None, None).await
+    }
+
+    /// Take a record out of the trash. Fails once retention has passed.
+    /// Takes the same ids as [`Self::trash`].
+    pub async fn restore(&self, id_hex: &str) -> Result<Option<DocMeta>> {
+        let key = parse_key(id_hex)?;
+        self.blocking(move |s| match s.record_id(&key)? {
+            Some(id) => s.restore(id),
+            None => Ok(None),
+        })
+        .await
+    }
+
+    /// Permanently remove a trashed record, ignoring retention. The blob is
+    /// deleted with its last record. Takes the same ids as [`Self::trash`].
+    pub async fn purge(&self, id_hex: &str) -> Result<bool> {
+        let key = parse_key(id_hex)?;
+        self.blocking(move |s| match s.record_id(&key)? {
+            Some(id) => s.purge(id),
+            None => Ok(false),
+        })
+        .await
+    }
+
+    /// Purge trashed records past retention.
+    pub async fn purge_expired(&self) -> Result<PurgeReport> {
+        self.blocking(|s| s.purge_expired()).await
+    }
+
+    /// Run [`Self::purge_expired`] and [`Self::expire_uploads`] every `every`
+    /// until the runtime shuts down. Panics if `every` is zero; to disable
+    /// the job, do not spawn it.
+    pub fn spawn_purge_job(&self, every: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        assert!(!every.is_zero(), "purge interval must not be zero");
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(every);
+            loop {
+                ticker.tick().await;
+                match store.purge_expired().await {
+                    Ok(r) if r.purged > 0 || r.kept_anchored > 0 => {
+                        eprintln!("retention: purged {} record(s), kept {} anchored", r.purged, r.kept_anchored);
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("retention: purge failed: {e:#}"),
+                }
+                match store.expire_uploads().await {
+                    Ok(0) => {}
+                    Ok(n) => eprintln!("retention: discarded {n} expired upload(s)"),
+                    Err(e) => eprintln!("retention: upload expiry failed: {e:#}"),
+                }
+            }
+        })
+    }
+
+    /// Record the on-chain anchor returned by [`chain::publish_remark`].
+    /// Takes the same ids as [`Self::trash`].
+    pub async fn set_anchor(&self, id_hex: &str, anchor: &str) -> Result<Option<DocMeta>> {
+        let key = parse_key(id_hex)?;
+        let anchor = anchor.to_string();
+        self.blocking(move |s| match s.record_id(&key)? {
+            Some(id) => s.set_anchor(id, &anchor),
+            None => Ok(None),
+        })
+        .await
+    }
 }
 
     /// On-chain schema definitions
@@ -266,19 +2136,27 @@ pub mod chain {
     pub struct OnChainPayload {
         pub sha256_hex: String,
         pub cid: Option<String>,
+        /// CID of the ciphertext when only a sealed copy is pinned
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub encrypted_cid: Option<String>,
         pub size_bytes: u64,
         pub filename: String,
         pub timestamp: u64,
+        /// sha256 of the version this document replaces
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub supersedes_sha256_hex: Option<String>,
     }
 
     impl From<&DocMeta> for OnChainPayload {
         fn from(meta: &DocMeta) -> Self {
             Self {
-                sha256_hex: meta.id_hex.clone(),
+                sha256_hex: hex::encode(meta.sha256),
                 cid: meta.cid.clone(),
+                encrypted_cid: meta.encrypted_cid.clone(),
                 size_bytes: meta.size_bytes,
                 filename: meta.filename.clone(),
                 timestamp: meta.created_at_unix_ms,
+                supersedes_sha256_hex: meta.supersedes_sha256.map(hex::encode),
             }
         }
     }
@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use store::DocStore;
+use store::{BlobBackend, Codec, DocInput, DocQuery, DocStore, FsckOptions, Kek, ScrubOptions, StoreConfig, Tenants, MAX_QUERY_LIMIT};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -10,6 +10,14 @@ struct Cli {
     #[arg(short, long, default_value = "./.pdfdb")] 
     db: PathBuf,
 
+    /// Blob backend: fs, memory or s3 (S3 settings are read from S3_* env vars)
+    #[arg(long, env = "STORE_BACKEND", default_value = "fs")]
+    backend: String,
+
+    /// Work inside this tenant's namespace (as store-server does with TENANTS_FILE)
+    #[arg(long, env = "STORE_TENANT")]
+    tenant: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -25,59 +33,292 @@ enum Commands {
         #[arg(long, default_value = "ws://localhost:9944")] node_url: String,
         /// Dev seed (//Alice, //Bob, etc.)
         #[arg(long, default_value = "//Alice")] seed: String,
+        /// Who is uploading the document
+        #[arg(long)] uploader: Option<String>,
+        /// Tag to attach (repeatable)
+        #[arg(long = "tag")] tags: Vec<String>,
+        /// Record id of the version this document replaces
+        #[arg(long)] supersedes: Option<String>,
+        /// Owner of the property the document belongs to
+        #[arg(long)] owner: Option<String>,
+        /// Property the document belongs to
+        #[arg(long)] property_id: Option<String>,
     },
-    /// Get metadata by id (sha256 hex)
+    /// Get metadata by record id, sha256 hex or a sha256/BLAKE3 multihash (1220…, 1e20…)
     Get { id: String },
-    /// List all stored PDFs
-    List,
-    /// Delete by id
-    Delete { id: String },
+    /// List stored PDFs (TSV: id, filename, size), newest first
+    List {
+        /// Exact-match filter as key=value (repeatable): filename, mime, cid,
+        /// owner, property_id, created_after, created_before (unix ms)
+        #[arg(long = "filter")] filters: Vec<String>,
+        /// newest, oldest, filename_asc or filename_desc
+        #[arg(long, default_value = "newest")] sort: store::SortOrder,
+        /// Print one page of at most this many and its next cursor on stderr
+        #[arg(long)] limit: Option<usize>,
+        /// Continue from a previous page's cursor
+        #[arg(long, requires = "limit")] cursor: Option<String>,
+    },
+    /// Show the version history of a document (TSV: version, id, filename, created_at)
+    Versions {
+        id: String,
+        /// Print only the latest version as JSON
+        #[arg(long, conflicts_with = "at")] latest: bool,
+        /// Print the version current at this unix timestamp (ms) as JSON
+        #[arg(long)] at: Option<u64>,
+    },
+    /// Move a document to the trash
+    Delete {
+        id: String,
+        /// Who is deleting it
+        #[arg(long)] by: Option<String>,
+        /// Why it is being deleted
+        #[arg(long)] reason: Option<String>,
+    },
+    /// Restore a document from the trash
+    Restore { id: String },
+    /// Permanently remove a trashed document, or with --expired every
+    /// trashed document past retention and every expired upload
+    Purge {
+        #[arg(required_unless_present = "expired")] id: Option<String>,
+        #[arg(long, conflicts_with = "id")] expired: bool,
+    },
+    /// Re-hash every blob, quarantine corrupted ones and refetch them from
+    /// IPFS, printing a JSON report. Exits with status 1 if any stay bad.
+    Scrub {
+        /// Read-rate cap while hashing (0 = unlimited)
+        #[arg(long, default_value_t = store::DEFAULT_SCRUB_BYTES_PER_SEC)] max_bytes_per_sec: u64,
+        /// IPFS API URL to refetch from
+        #[arg(long, default_value = "http://127.0.0.1:5001")] ipfs_url: String,
+        /// Only quarantine; do not refetch from IPFS
+        #[arg(long)] no_refetch: bool,
+    },
+    /// List documents in the trash (TSV: id, filename, deleted_at, deleted_by, reason)
+    Trash,
+    /// Check the index against stored blobs and tmp/, printing a JSON report.
+    /// Exits with status 1 if problems remain.
+    Fsck {
+        /// Fix what can be fixed
+        #[arg(long)] repair: bool,
+        /// Leave staging files younger than this alone (uploads in progress)
+        #[arg(long, default_value_t = 3600)] temp_min_age_secs: u64,
+    },
+    /// Full-text search inside documents (TSV: id, page, filename, snippet)
+    #[cfg(feature = "search")]
+    Search {
+        query: String,
+        #[arg(long, default_value_t = store::DEFAULT_SEARCH_LIMIT)] limit: usize,
+    },
+    /// Rebuild the listing and full-text indexes from the stored documents
+    Reindex,
+    /// Print record, blob and chunk counts, bytes stored and the dedup ratio (JSON)
+    Stats,
+    /// Rewrap every data key from the configured KEK (STORE_KEK_FILE or
+    /// STORE_KEK) to a new one, printing a JSON report. Configure the new KEK
+    /// once it succeeds; if interrupted, run it again.
+    RotateKek {
+        /// File holding the new KEK (32 raw bytes or 64 hex characters)
+        #[arg(long, env = "STORE_NEW_KEK_FILE")] new_key_file: PathBuf,
+    },
+    /// Rewrite every blob with a codec (zstd or none), printing a JSON report
+    /// and the space saved. Safe to interrupt and run again.
+    Recompress {
+        #[arg(long, default_value = "zstd")] codec: Codec,
+    },
+    /// Compute BLAKE3 digests and Bao outboards for blobs stored before
+    /// them, printing a JSON report. Safe to interrupt and run again.
+    Backfill,
     /// Export minimal on-chain JSON ({ sha256, cid, size_bytes })
     Export { id: String },
 }
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     let cli = Cli::parse();
-    let db = DocStore::open(&cli.db).context("opening database")?;
+    let config = StoreConfig { backend: BlobBackend::from_kind(&cli.backend)?, ..StoreConfig::from_env()? };
+    let db = match &cli.tenant {
+        Some(tenant) => Tenants::open(&cli.db, config).context("opening database")?.store(tenant)?,
+        None => DocStore::open_with_config(&cli.db, config).context("opening database")?,
+    };
 
     match cli.command {
-        Commands::Store { path, ipfs_url, node_url, seed } => {
+        Commands::Store { path, ipfs_url, node_url, seed, uploader, tags, supersedes, owner, property_id } => {
             eprintln!("Storing document in Decentralize databse");
             
             // ALWAYS pin to IPFS (mandatory)
             eprintln!("Pinning to IPFS at {}...", ipfs_url);
-            let meta = db.store_pdf_with_ipfs(&path, Some(&ipfs_url))?;
-            eprintln!("IPFS CID: {}", meta.cid.as_ref().unwrap());
+            let file = tokio::fs::File::open(&path).await.with_context(|| format!("opening {path:?}"))?;
+            let input = DocInput { uploader, tags, supersedes, owner, property_id, ..DocInput::from_path(&path) };
+            let meta = db.store_with_ipfs(file, input, Some(&ipfs_url)).await?;
+            match (&meta.cid, &meta.encrypted_cid) {
+                (_, Some(cid)) => eprintln!("IPFS CID (encrypted): {cid}"),
+                (Some(cid), None) => eprintln!("IPFS CID: {cid}"),
+                (None, None) => eprintln!("warning: stored in plaintext before encryption was enabled; not pinned again"),
+            }
+            for defect in meta.pdf.iter().flat_map(|p| &p.defects) {
+                eprintln!("warning: {defect}");
+            }
+            for kind in meta.pdf.iter().flat_map(|p| &p.active_content) {
+                eprintln!("warning: contains active content: {kind}");
+            }
+            if let Some(sanitized) = &meta.sanitized {
+                eprintln!(
+                    "Stored a sanitized copy of {}; removed: {:?}",
+                    hex::encode(sanitized.original_sha256),
+                    sanitized.removed
+                );
+            }
             
             // ALWAYS publish to blockchain (mandatory)
             eprintln!("Publishing to on-chain at {}...", node_url);
-            use tokio::runtime::Runtime;
             use store::chain::publish_remark;
-            let rt = Runtime::new()?;
-            let block_hash = rt.block_on(publish_remark(&node_url, &seed, &meta))?;
+            let block_hash = publish_remark(&node_url, &seed, &meta).await?;
+            db.set_anchor(&meta.id_hex, &block_hash).await?;
             eprintln!("on-chain block hash: {}", block_hash);
             
             eprintln!("Document stored successfully!");
             println!("{}", meta.id_hex);
         }
         Commands::Get { id } => {
-            let meta = db.get_by_hex(&id)?.context("not found")?;
+            let meta = db.get_by_hex(&id).await?.context("not found")?;
             println!("{}", serde_json::to_string_pretty(&meta)?);
         }
-        Commands::List => {
-            for m in db.list()? {
-                println!("{}\t{}\t{} bytes", m.id_hex, m.filename, m.size_bytes);
+        Commands::List { filters, sort, limit, cursor } => {
+            let mut query = DocQuery { sort, limit: Some(limit.unwrap_or(MAX_QUERY_LIMIT)), cursor, ..Default::default() };
+            for filter in &filters {
+                let (key, value) = filter.split_once('=').with_context(|| format!("expected key=value, got {filter:?}"))?;
+                query.filter(key, value)?;
+            }
+            loop {
+                let page = db.query(query.clone()).await?;
+                for m in &page.documents {
+                    println!("{}\t{}\t{} bytes", m.id_hex, m.filename, m.size_bytes);
+                }
+                match page.next_cursor {
+                    Some(next) if limit.is_none() => query.cursor = Some(next),
+                    Some(next) => {
+                        eprintln!("next cursor: {next}");
+                        break;
+                    }
+                    None => break,
+                }
+            }
+        }
+        Commands::Versions { id, latest, at } => {
+            let version = match (latest, at) {
+                (true, _) => Some(db.latest(&id).await?),
+                (false, Some(ts)) => Some(db.at(&id, ts).await?),
+                (false, None) => None,
+            };
+            match version {
+                Some(meta) => {
+                    let meta = meta.context("not found")?;
+                    println!("{}", serde_json::to_string_pretty(&meta)?);
+                }
+                None => {
+                    for m in db.history(&id).await? {
+                        println!("{}\t{}\t{}\t{}", m.version, m.id_hex, m.filename, m.created_at_unix_ms);
+                    }
+                }
             }
         }
-        Commands::Delete { id } => {
-            let ok = db.delete_by_hex(&id)?;
+        Commands::Delete { id, by, reason } => {
+            let ok = db.trash(&id, by, reason).await?;
             println!("{}", if ok { "deleted" } else { "not-found" });
         }
+        Commands::Restore { id } => {
+            let ok = db.restore(&id).await?.is_some();
+            println!("{}", if ok { "restored" } else { "not-found" });
+        }
+        Commands::Purge { id: Some(id), .. } => {
+            let ok = db.purge(&id).await?;
+            println!("{}", if ok { "purged" } else { "not-found" });
+        }
+        Commands::Purge { id: None, .. } => {
+            let report = db.purge_expired().await?;
+            let uploads = db.expire_uploads().await?;
+            println!("purged {}\tkept-anchored {}\texpired-uploads {uploads}", report.purged, report.kept_anchored);
+        }
+        Commands::Fsck { repair, temp_min_age_secs } => {
+            let opts = FsckOptions { repair, temp_min_age: std::time::Duration::from_secs(temp_min_age_secs) };
+            let report = db.fsck(opts).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            let ok = if repair { report.unrepaired.is_empty() } else { report.is_clean() };
+            if !ok {
+                std::process::exit(1);
+            }
+        }
+        Commands::Scrub { max_bytes_per_sec, ipfs_url, no_refetch } => {
+            let opts = ScrubOptions {
+                max_bytes_per_sec: Some(max_bytes_per_sec).filter(|r| *r > 0),
+                refetch: !no_refetch,
+                ipfs_url: Some(ipfs_url),
+            };
+            let report = db.scrub(opts).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.unrecoverable.is_empty() || !report.errors.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Commands::Trash => {
+            for m in db.list_trash().await? {
+                let t = m.deleted.as_ref().context("trash entry without tombstone")?;
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    m.id_hex,
+                    m.filename,
+                    t.deleted_at_unix_ms,
+                    t.deleted_by.as_deref().unwrap_or("-"),
+                    t.reason.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        #[cfg(feature = "search")]
+        Commands::Search { query, limit } => {
+            for hit in db.search(&query, limit).await? {
+                let page = hit.page.map(|p| p.to_string()).unwrap_or_default();
+                println!("{}\t{}\t{}\t{}", hit.id_hex, page, hit.filename, hit.snippet);
+            }
+        }
+        Commands::Reindex => {
+            let report = db.reindex().await?;
+            eprintln!("reindexed {} record(s), {} page(s) of text", report.records, report.pages);
+        }
+        Commands::Stats => {
+            println!("{}", serde_json::to_string_pretty(&db.stats().await?)?);
+        }
+        Commands::RotateKek { new_key_file } => {
+            let report = db.rotate_kek(Kek::from_file(&new_key_file)?).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Commands::Recompress { codec } => {
+            let report = db.recompress(codec).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            eprintln!("saved {} bytes over {} blobs", report.bytes_saved(), report.recompressed + report.incompressible);
+        }
+        Commands::Backfill => {
+            let report = db.backfill().await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            eprintln!("backfilled {} of {} blobs", report.backfilled, report.blobs_checked);
+        }
         Commands::Export { id } => {
-            let meta = db.get_by_hex(&id)?.context("not found")?;
+            let meta = db.get_by_hex(&id).await?.context("not found")?;
             #[derive(serde::Serialize)]
-            struct OnChain<'a> { sha256: &'a [u8; 32], cid: &'a Option<String>, size_bytes: u64 }
-            let j = serde_json::to_string_pretty(&OnChain { sha256: &meta.sha256, cid: &meta.cid, size_bytes: meta.size_bytes })?;
+            struct OnChain<'a> {
+                sha256: &'a [u8; 32],
+                cid: &'a Option<String>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                encrypted_cid: &'a Option<String>,
+                size_bytes: u64,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                supersedes_sha256: Option<[u8; 32]>,
+            }
+            let j = serde_json::to_string_pretty(&OnChain {
+                sha256: &meta.sha256,
+                cid: &meta.cid,
+                encrypted_cid: &meta.encrypted_cid,
+                size_bytes: meta.size_bytes,
+                supersedes_sha256: meta.supersedes_sha256,
+            })?;
             println!("{j}");
         }
     }
@@ -0,0 +1,238 @@
+//! Merkle tree over fixed-size chunks, for verifying part of a document.
+//!
+//! At ingest the content is cut into [`MERKLE_CHUNK_BYTES`] leaves and
+//! hashed into a binary tree shaped as in RFC 6962 §2.1: a leaf is
+//! `sha256(0x00 ‖ chunk)`, a node `sha256(0x01 ‖ left ‖ right)`, and `n`
+//! leaves split at the largest power of two below `n`, so nothing is
+//! duplicated. The root is kept next to the sha256 (`merkle_root` on
+//! `BlobMeta` and [`DocMeta`](crate::DocMeta)) and the leaf hashes in the
+//! `merkle` tree. A range download carries a [`MerkleProof`] for the chunks
+//! it covers, and [`verify_range`] checks those bytes against the root
+//! without the rest of the document.
+
+use alloc::vec::Vec;
+
+use sha2::{Digest, Sha256};
+
+use crate::Hash32;
+
+/// Leaf size. Ranges must start on a multiple of it to be checked.
+pub const MERKLE_CHUNK_BYTES: u64 = 64 * 1024;
+
+/// Number of leaves for `size` bytes; an empty document has one empty leaf.
+pub fn leaf_count(size: u64) -> u64 {
+    size.div_ceil(MERKLE_CHUNK_BYTES).max(1)
+}
+
+/// Hash of one leaf's bytes.
+pub fn leaf_hash(chunk: &[u8]) -> Hash32 {
+    Sha256::new().chain_update([0]).chain_update(chunk).finalize().into()
+}
+
+fn node_hash(left: &Hash32, right: &Hash32) -> Hash32 {
+    Sha256::new().chain_update([1]).chain_update(left).chain_update(right).finalize().into()
+}
+
+/// Size of the left subtree over `n >= 2` leaves.
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// Root over a non-empty list of leaf hashes.
+pub fn root(leaves: &[Hash32]) -> Hash32 {
+    match leaves {
+        [leaf] => *leaf,
+        _ => {
+            let k = split(leaves.len());
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// Leaf hashes of `bytes`, fed in pieces of any size.
+#[derive(Default)]
+pub(crate) struct MerkleBuilder {
+    leaves: Vec<Hash32>,
+    buf: Vec<u8>,
+}
+
+impl MerkleBuilder {
+    pub(crate) fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let take = (MERKLE_CHUNK_BYTES as usize - self.buf.len()).min(bytes.len());
+            self.buf.extend_from_slice(&bytes[..take]);
+            bytes = &bytes[take..];
+            if self.buf.len() == MERKLE_CHUNK_BYTES as usize {
+                self.leaves.push(leaf_hash(&self.buf));
+                self.buf.clear();
+            }
+        }
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<Hash32> {
+        if !self.buf.is_empty() || self.leaves.is_empty() {
+            self.leaves.push(leaf_hash(&self.buf));
+        }
+        self.leaves
+    }
+}
+
+/// Leaf hashes of a whole document.
+pub fn leaves(bytes: &[u8]) -> Vec<Hash32> {
+    let mut builder = MerkleBuilder::default();
+    builder.update(bytes);
+    builder.finish()
+}
+
+/// Inclusion proof for a run of leaves: the roots of the subtrees beside
+/// them, left to right, and the document size that fixes the tree's shape.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub size_bytes: u64,
+    pub hashes: Vec<Hash32>,
+}
+
+impl MerkleProof {
+    /// Proof for leaves `first..end` of the tree over `leaves`.
+    pub fn new(leaves: &[Hash32], size_bytes: u64, first: usize, end: usize) -> Self {
+        fn walk(leaves: &[Hash32], lo: usize, first: usize, end: usize, out: &mut Vec<Hash32>) {
+            let hi = lo + leaves.len();
+            if hi <= first || lo >= end {
+                out.push(root(leaves));
+            } else if leaves.len() > 1 {
+                let k = split(leaves.len());
+                walk(&leaves[..k], lo, first, end, out);
+                walk(&leaves[k..], lo + k, first, end, out);
+            }
+        }
+        let mut hashes = Vec::new();
+        walk(leaves, 0, first, end, &mut hashes);
+        Self { size_bytes, hashes }
+    }
+
+    /// Size as 8 big-endian bytes, then the hashes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + 32 * self.hashes.len());
+        out.extend_from_slice(&self.size_bytes.to_be_bytes());
+        self.hashes.iter().for_each(|h| out.extend_from_slice(h));
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 || !(bytes.len() - 8).is_multiple_of(32) {
+            return None;
+        }
+        let size_bytes = u64::from_be_bytes(bytes[..8].try_into().ok()?);
+        let hashes = bytes[8..].chunks_exact(32).map(|h| h.try_into().unwrap()).collect();
+        Some(Self { size_bytes, hashes })
+    }
+}
+
+/// Whether `bytes`, read at `offset`, are part of the document with Merkle
+/// `root`. `offset` must fall on a leaf boundary and `bytes` must end on one
+/// or at the end of the document; anything else is rejected.
+pub fn verify_range(root: &Hash32, offset: u64, bytes: &[u8], proof: &MerkleProof) -> bool {
+    let size = proof.size_bytes;
+    let Some(end) = offset.checked_add(bytes.len() as u64) else { return false };
+    let aligned = |at: u64| at.is_multiple_of(MERKLE_CHUNK_BYTES);
+    if !aligned(offset) || end > size || (end != size && !aligned(end)) {
+        return false;
+    }
+    if bytes.is_empty() && size != 0 {
+        return false;
+    }
+    let range = match bytes.is_empty() {
+        true => alloc::vec![leaf_hash(&[])],
+        false => bytes.chunks(MERKLE_CHUNK_BYTES as usize).map(leaf_hash).collect(),
+    };
+    let first = (offset / MERKLE_CHUNK_BYTES) as usize;
+
+    fn rebuild(
+        lo: usize,
+        hi: usize,
+        first: usize,
+        range: &[Hash32],
+        proof: &mut core::slice::Iter<'_, Hash32>,
+    ) -> Option<Hash32> {
+        if hi <= first || lo >= first + range.len() {
+            return proof.next().copied();
+        }
+        if hi - lo == 1 {
+            return Some(range[lo - first]);
+        }
+        let k = split(hi - lo);
+        let left = rebuild(lo, lo + k, first, range, proof)?;
+        let right = rebuild(lo + k, hi, first, range, proof)?;
+        Some(node_hash(&left, &right))
+    }
+    let mut hashes = proof.hashes.iter();
+    let rebuilt = rebuild(0, leaf_count(size) as usize, first, &range, &mut hashes);
+    hashes.next().is_none() && rebuilt.as_ref() == Some(root)
+}
+
+#[cfg(feature = "std")]
+mod store {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{BlobMeta, DocMeta, DocStore, StoreInner};
+
+    impl StoreInner {
+        /// Keep the leaf hashes of a blob, for proofs.
+        pub(crate) fn put_merkle_leaves(&self, sha256: &Hash32, leaves: &[Hash32]) -> Result<()> {
+            self.merkle.insert(sha256, leaves.concat())?;
+            Ok(())
+        }
+
+        fn merkle_leaves(&self, sha256: &Hash32) -> Result<Option<Vec<Hash32>>> {
+            let Some(val) = self.merkle.get(sha256)? else { return Ok(None) };
+            Ok(Some(val.chunks_exact(32).map(|h| h.try_into().unwrap()).collect()))
+        }
+
+        /// Proof for the leaves covering `len` bytes from `offset`; `None`
+        /// for an empty range or a blob ingested before Merkle roots.
+        pub(crate) fn range_proof(&self, blob: &BlobMeta, offset: u64, len: u64) -> Result<Option<MerkleProof>> {
+            let Some(leaves) = self.merkle_leaves(&blob.sha256)?.filter(|_| blob.merkle_root.is_some()) else {
+                return Ok(None);
+            };
+            if len == 0 || leaves.len() as u64 != leaf_count(blob.size_bytes) {
+                return Ok(None);
+            }
+            let first = offset / MERKLE_CHUNK_BYTES;
+            let end = (offset + len).div_ceil(MERKLE_CHUNK_BYTES);
+            Ok(Some(MerkleProof::new(&leaves, blob.size_bytes, first as usize, end as usize)))
+        }
+
+        /// Compute the root and leaves of a blob ingested before Merkle
+        /// roots existed. Bytes that no longer match the sha256 are left for
+        /// scrub; returns whether anything was filled in.
+        pub(crate) fn backfill_merkle(&self, blob: &BlobMeta, bytes: &[u8]) -> Result<bool> {
+            if blob.merkle_root.is_some() && self.merkle.contains_key(blob.sha256)? {
+                return Ok(false);
+            }
+            if Hash32::from(Sha256::digest(bytes)) != blob.sha256 {
+                return Ok(false);
+            }
+            let leaves = leaves(bytes);
+            self.put_merkle_leaves(&blob.sha256, &leaves)?;
+            let merkle_root = root(&leaves);
+            self.update_blob(&blob.sha256, |b| b.merkle_root = Some(merkle_root))
+        }
+    }
+
+    impl DocStore {
+        /// Inclusion proof for the leaves a byte range of a document's
+        /// content touches, to send along with a range download. Check it
+        /// with [`verify_range`] against `meta.merkle_root`. `None` for an
+        /// empty range or a document stored before Merkle roots (see
+        /// [`DocStore::reindex`]).
+        pub async fn range_proof(&self, meta: &DocMeta, range: std::ops::Range<u64>) -> Result<Option<MerkleProof>> {
+            let sha256 = meta.sha256;
+            self.blocking(move |s| {
+                let Some(blob) = s.blob_meta(&sha256)? else { return Ok(None) };
+                s.range_proof(&blob, range.start, range.end.saturating_sub(range.start))
+            })
+            .await
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Multihash-style content ids, so a document can be named by either of its
+//! digests.
+//!
+//! An id is the multihash of the digest written as hex: the multicodec code
+//! of the hash function, the digest length, then the digest. A sha256 id
+//! reads `1220…` and a BLAKE3 id `1e20…`. Lookups such as
+//! [`DocStore::get_by_hex`](crate::DocStore::get_by_hex) take these as well
+//! as bare hex record ids and sha256s.
+
+use core::fmt;
+
+use crate::Hash32;
+
+/// Multicodec code of sha2-256.
+const SHA2_256: u8 = 0x12;
+/// Multicodec code of BLAKE3.
+const BLAKE3: u8 = 0x1e;
+
+/// A content digest tagged with the function that produced it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContentId {
+    Sha256(Hash32),
+    Blake3(Hash32),
+}
+
+impl ContentId {
+    pub fn digest(&self) -> &Hash32 {
+        match self {
+            Self::Sha256(d) | Self::Blake3(d) => d,
+        }
+    }
+
+    /// Multihash bytes: code, length, digest.
+    pub fn to_bytes(&self) -> [u8; 34] {
+        let mut out = [0u8; 34];
+        out[0] = match self {
+            Self::Sha256(_) => SHA2_256,
+            Self::Blake3(_) => BLAKE3,
+        };
+        out[1] = 32;
+        out[2..].copy_from_slice(self.digest());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&[code, 32], digest) = bytes.split_first_chunk::<2>()? else { return None };
+        let digest: Hash32 = digest.try_into().ok()?;
+        match code {
+            SHA2_256 => Some(Self::Sha256(digest)),
+            BLAKE3 => Some(Self::Blake3(digest)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ContentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.to_bytes().iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::str::FromStr for ContentId {
+    type Err = crate::StoreError;
+
+    fn from_str(s: &str) -> Result<Self, crate::StoreError> {
+        hex::decode(s)
+            .ok()
+            .and_then(|bytes| Self::from_bytes(&bytes))
+            .ok_or_else(|| crate::StoreError::InvalidQuery(format!("not a sha256 or BLAKE3 multihash: {s:?}")))
+    }
+}
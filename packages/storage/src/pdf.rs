@@ -0,0 +1,635 @@
+//! Reading inside stored PDFs: structural validation, active content,
+//! metadata and page text.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use lopdf::{xref::XrefEntry, Dictionary, Document, Object, ObjectId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, Date, Month, OffsetDateTime, Time, UtcOffset};
+
+use crate::{
+    content, ActiveContent, BlobMeta, Hash32, PdfDefect, PdfInfo, Sanitized, Staged, StoreError, StoreInner,
+};
+
+/// The linearization dictionary must start within the first 1024 bytes.
+const LINEARIZED_WINDOW: usize = 1024;
+/// `%%EOF` and `startxref` are looked for this far from the end.
+const TRAILER_WINDOW: usize = 1024;
+/// Form field trees deeper than this are not followed.
+const MAX_FIELD_DEPTH: usize = 32;
+/// Inline dictionaries and arrays nested deeper than this are not scanned.
+const MAX_NESTING: usize = 64;
+/// Keys whose values are active content wherever they appear.
+const ACTIVE_KEYS: [&[u8]; 7] = [b"JS", b"AA", b"OpenAction", b"XFA", b"EF", b"JavaScript", b"EmbeddedFiles"];
+
+/// Which structural defects make ingest reject a file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PdfValidation {
+    /// Reject any defect.
+    #[default]
+    Strict,
+    /// Accept files that can be repaired (see [`PdfDefect::repairable`]),
+    /// keeping their defects as warnings in [`PdfInfo::defects`].
+    AllowRepairable,
+    /// Accept everything; defects are still recorded.
+    Off,
+}
+
+impl PdfValidation {
+    /// Whether a file with these defects is refused.
+    pub fn rejects(self, defects: &[PdfDefect]) -> bool {
+        match self {
+            Self::Strict => !defects.is_empty(),
+            Self::AllowRepairable => defects.iter().any(|d| !d.repairable()),
+            Self::Off => false,
+        }
+    }
+}
+
+impl FromStr for PdfValidation {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "repairable" | "allow_repairable" => Ok(Self::AllowRepairable),
+            "off" => Ok(Self::Off),
+            _ => Err(StoreError::InvalidQuery(format!("unknown PDF validation policy {s:?}"))),
+        }
+    }
+}
+
+impl fmt::Display for PdfValidation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "strict",
+            Self::AllowRepairable => "repairable",
+            Self::Off => "off",
+        })
+    }
+}
+
+/// What ingest does with a PDF carrying [`ActiveContent`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActiveContentPolicy {
+    /// Store it as uploaded, listing what was found in [`PdfInfo::active_content`].
+    #[default]
+    Flag,
+    /// Refuse the upload.
+    Reject,
+    /// Store a copy with the active content removed; the record keeps the
+    /// upload's hash in [`Sanitized`].
+    Sanitize,
+}
+
+impl FromStr for ActiveContentPolicy {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s {
+            "flag" => Ok(Self::Flag),
+            "reject" => Ok(Self::Reject),
+            "sanitize" => Ok(Self::Sanitize),
+            _ => Err(StoreError::InvalidQuery(format!("unknown active content policy {s:?}"))),
+        }
+    }
+}
+
+impl fmt::Display for ActiveContentPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Flag => "flag",
+            Self::Reject => "reject",
+            Self::Sanitize => "sanitize",
+        })
+    }
+}
+
+/// What ingest learns from a file's contents.
+#[derive(Default)]
+pub(crate) struct Inspected {
+    /// `None` if the file could not be parsed, even after repair.
+    pub info: Option<PdfInfo>,
+    pub pages: Vec<(u32, String)>,
+    pub defects: Vec<PdfDefect>,
+}
+
+/// Validate `bytes` and parse them once for metadata and, with `text` set,
+/// page text. Metadata of a repairable file comes from the repaired copy.
+pub(crate) fn inspect(bytes: &[u8], text: bool) -> Inspected {
+    let (doc, defects) = load(bytes);
+    let Some(doc) = doc else { return Inspected { defects, ..Default::default() } };
+    Inspected {
+        info: Some(PdfInfo { defects: defects.clone(), ..info_of(&doc, bytes) }),
+        pages: if text { pages_of(&doc) } else { Vec::new() },
+        defects,
+    }
+}
+
+/// Structural defects of a PDF; empty for a well-formed file.
+pub fn validate(bytes: &[u8]) -> Vec<PdfDefect> {
+    load(bytes).1
+}
+
+/// Structural metadata of a PDF.
+pub fn read_info(bytes: &[u8]) -> Result<PdfInfo> {
+    let (doc, defects) = load(bytes);
+    let doc = doc.ok_or(StoreError::InvalidPdf { defects: defects.clone() })?;
+    Ok(PdfInfo { defects, ..info_of(&doc, bytes) })
+}
+
+/// A copy of the PDF with every kind of [`ActiveContent`] removed: scripts,
+/// launch and remote actions, open actions, embedded files and XFA.
+pub fn sanitize(bytes: &[u8]) -> Result<Vec<u8>> {
+    let (doc, defects) = load(bytes);
+    let mut doc = doc.ok_or(StoreError::InvalidPdf { defects })?;
+    let actions: BTreeSet<ObjectId> =
+        doc.objects.iter().filter(|(_, o)| object_dict(o).is_some_and(is_active_action)).map(|(id, _)| *id).collect();
+    for object in doc.objects.values_mut() {
+        strip(object, &actions, 0);
+    }
+    doc.prune_objects();
+    let mut out = Vec::new();
+    doc.save_to(&mut out)?;
+    Ok(out)
+}
+
+fn object_dict(object: &Object) -> Option<&Dictionary> {
+    match object {
+        Object::Dictionary(d) => Some(d),
+        Object::Stream(s) => Some(&s.dict),
+        _ => None,
+    }
+}
+
+/// Kinds of active content anywhere in the document.
+fn active_content(doc: &Document) -> Vec<ActiveContent> {
+    let mut found = BTreeSet::new();
+    if let Ok(catalog) = doc.catalog() {
+        if catalog.has(b"OpenAction") || catalog.has(b"AA") {
+            found.insert(ActiveContent::OpenAction);
+        }
+    }
+    for object in doc.objects.values() {
+        scan(object, &mut found, 0);
+    }
+    found.into_iter().collect()
+}
+
+fn scan(object: &Object, found: &mut BTreeSet<ActiveContent>, depth: usize) {
+    if depth > MAX_NESTING {
+        return;
+    }
+    if let Object::Array(items) = object {
+        items.iter().for_each(|item| scan(item, found, depth + 1));
+    }
+    let Some(dict) = object_dict(object) else { return };
+    let name = |key: &[u8]| dict.get(key).and_then(Object::as_name).ok();
+    if dict.has(b"JS") || dict.has(b"JavaScript") {
+        found.insert(ActiveContent::JavaScript);
+    }
+    match name(b"S") {
+        Some(b"JavaScript") => found.insert(ActiveContent::JavaScript),
+        Some(b"Launch") => found.insert(ActiveContent::Launch),
+        Some(b"URI" | b"SubmitForm" | b"GoToR" | b"GoToE" | b"ImportData") => found.insert(ActiveContent::Uri),
+        _ => false,
+    };
+    if dict.has(b"EF") || dict.has(b"EmbeddedFiles") || dict.has_type(b"EmbeddedFile") || name(b"Subtype") == Some(b"FileAttachment") {
+        found.insert(ActiveContent::EmbeddedFile);
+    }
+    if dict.has(b"XFA") {
+        found.insert(ActiveContent::Xfa);
+    }
+    dict.iter().for_each(|(_, value)| scan(value, found, depth + 1));
+}
+
+/// An action dictionary that runs code, starts programs or reaches out.
+fn is_active_action(dict: &Dictionary) -> bool {
+    dict.has(b"JS")
+        || dict.get(b"S").and_then(Object::as_name).is_ok_and(|s| {
+            matches!(s, b"JavaScript" | b"Launch" | b"URI" | b"SubmitForm" | b"GoToR" | b"GoToE" | b"ImportData")
+        })
+}
+
+/// Drop active keys, file attachments and any link to an active action,
+/// inline or by reference to one of `actions`.
+fn strip(object: &mut Object, actions: &BTreeSet<ObjectId>, depth: usize) {
+    let active = |value: &Object| match value {
+        Object::Reference(id) => actions.contains(id),
+        Object::Dictionary(d) => is_active_action(d),
+        _ => false,
+    };
+    if depth > MAX_NESTING {
+        return;
+    }
+    let dict = match object {
+        Object::Array(items) => {
+            items.retain(|item| !active(item));
+            items.iter_mut().for_each(|item| strip(item, actions, depth + 1));
+            return;
+        }
+        Object::Dictionary(d) => d,
+        Object::Stream(s) => &mut s.dict,
+        _ => return,
+    };
+    let mut doomed: Vec<Vec<u8>> = dict
+        .iter()
+        .filter(|(key, value)| ACTIVE_KEYS.contains(&key.as_slice()) || active(value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    if dict.get(b"Subtype").and_then(Object::as_name).is_ok_and(|s| s == b"FileAttachment") {
+        doomed.push(b"FS".to_vec());
+    }
+    for key in doomed {
+        dict.remove(&key);
+    }
+    dict.iter_mut().for_each(|(_, value)| strip(value, actions, depth + 1));
+}
+
+/// Text of each page, numbered from 1, with runs of whitespace collapsed.
+/// Pages whose text cannot be decoded are skipped; a file that cannot be
+/// parsed even after repair is an error.
+pub fn extract_pages(bytes: &[u8]) -> Result<Vec<(u32, String)>> {
+    let (doc, defects) = load(bytes);
+    Ok(pages_of(&doc.ok_or(StoreError::InvalidPdf { defects })?))
+}
+
+/// Parse `bytes`, rebuilding the cross-reference section from the object
+/// headers when it cannot be read, and list every defect found on the way.
+fn load(bytes: &[u8]) -> (Option<Document>, Vec<PdfDefect>) {
+    let mut defects = Vec::new();
+    let tail = &bytes[bytes.len().saturating_sub(TRAILER_WINDOW)..];
+    if !find(tail, b"%%EOF") {
+        defects.push(PdfDefect::MissingEof);
+    }
+    if !find(tail, b"startxref") {
+        defects.push(PdfDefect::MissingStartxref);
+    }
+    let doc = match Document::load_mem(bytes) {
+        Ok(doc) => Some(doc),
+        Err(e) => {
+            if defects.is_empty() {
+                defects.push(PdfDefect::BrokenXref { detail: e.to_string() });
+            }
+            if !find(bytes, b"trailer") && !find(bytes, b"/XRef") {
+                defects.push(PdfDefect::MissingTrailer);
+            }
+            repair(bytes)
+        }
+    };
+    let Some(doc) = doc else {
+        defects.push(PdfDefect::NoCatalog);
+        return (None, defects);
+    };
+
+    let bad_objects = doc
+        .reference_table
+        .entries
+        .iter()
+        .filter(|(&id, entry)| match **entry {
+            XrefEntry::Normal { generation, .. } => !doc.objects.contains_key(&(id, generation)),
+            XrefEntry::Compressed { .. } => !doc.objects.contains_key(&(id, 0)),
+            _ => false,
+        })
+        .count();
+    if bad_objects > 0 {
+        defects.push(PdfDefect::BadObjects { count: bad_objects as u32 });
+    }
+    if let Some(defect) = page_tree_defect(&doc) {
+        defects.push(defect);
+    }
+    (Some(doc), defects)
+}
+
+fn page_tree_defect(doc: &Document) -> Option<PdfDefect> {
+    let Ok(catalog) = doc.catalog() else { return Some(PdfDefect::NoCatalog) };
+    let pages = catalog.get(b"Pages").ok().and_then(|p| dict(doc, p));
+    let Some(pages) = pages.filter(|p| p.has_type(b"Pages")) else {
+        return Some(PdfDefect::PageTree { detail: "catalog has no /Pages tree".into() });
+    };
+    let actual = doc.get_pages().len() as u32;
+    if actual == 0 {
+        return Some(PdfDefect::PageTree { detail: "page tree has no pages".into() });
+    }
+    let declared = pages.get(b"Count").and_then(|c| deref(doc, c).as_i64()).unwrap_or(-1);
+    (declared != i64::from(actual)).then(|| PdfDefect::PageCountMismatch {
+        declared: u32::try_from(declared).unwrap_or(0),
+        actual,
+    })
+}
+
+/// Append a fresh xref table and trailer listing every `N G obj` header in
+/// the file (later definitions win) and parse the result.
+fn repair(bytes: &[u8]) -> Option<Document> {
+    let mut offsets = BTreeMap::new();
+    let mut from = 0;
+    while let Some(at) = position(&bytes[from..], b"obj").map(|p| p + from) {
+        from = at + 3;
+        if let Some((id, gen, start)) = object_header(bytes, at) {
+            offsets.insert(id, (gen, start));
+        }
+    }
+    let root = last_reference(bytes, b"/Root").or_else(|| {
+        // no usable trailer: the catalog is the object typed as one
+        offsets.iter().find_map(|(&id, &(gen, start))| {
+            let body = &bytes[start..bytes.len().min(start + 512)];
+            let end = position(body, b"endobj").unwrap_or(body.len());
+            find_type_catalog(&body[..end]).then_some((id, gen))
+        })
+    })?;
+
+    let mut fixed = bytes.to_vec();
+    fixed.extend_from_slice(b"\n");
+    let xref = fixed.len();
+    fixed.extend_from_slice(b"xref\n0 1\n0000000000 65535 f\r\n");
+    for (id, (gen, start)) in &offsets {
+        fixed.extend_from_slice(format!("{id} 1\n{start:010} {gen:05} n\r\n").as_bytes());
+    }
+    let size = offsets.keys().next_back().map_or(1, |id| id + 1);
+    let trailer = format!(
+        "trailer\n<< /Size {size} /Root {} {} R >>\nstartxref\n{xref}\n%%EOF\n",
+        root.0, root.1
+    );
+    fixed.extend_from_slice(trailer.as_bytes());
+    Document::load_mem(&fixed).ok()
+}
+
+/// `N G obj` ending at `at`: the object number, generation and offset.
+fn object_header(bytes: &[u8], at: usize) -> Option<(u32, u16, usize)> {
+    let digits_before = |end: usize| {
+        let start = bytes[..end].iter().rposition(|b| !b.is_ascii_digit()).map_or(0, |p| p + 1);
+        (start < end).then_some(start)
+    };
+    if bytes.get(at + 3).is_some_and(|b| b.is_ascii_alphanumeric()) || at < 4 || !bytes[at - 1].is_ascii_whitespace() {
+        return None;
+    }
+    let gen_start = digits_before(at - 1)?;
+    if gen_start < 2 || !bytes[gen_start - 1].is_ascii_whitespace() {
+        return None;
+    }
+    let id_start = digits_before(gen_start - 1)?;
+    if id_start > 0 && !bytes[id_start - 1].is_ascii_whitespace() {
+        return None;
+    }
+    Some((number(&bytes[id_start..gen_start - 1])?, number(&bytes[gen_start..at - 1])?, id_start))
+}
+
+fn number<T: FromStr>(digits: &[u8]) -> Option<T> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// Target of the last `key N G R` in the file.
+fn last_reference(bytes: &[u8], key: &[u8]) -> Option<(u32, u16)> {
+    let at = bytes.windows(key.len()).rposition(|w| w == key)? + key.len();
+    let rest = std::str::from_utf8(&bytes[at..bytes.len().min(at + 32)]).ok()?;
+    let mut words = rest.split_whitespace();
+    let (id, gen) = (words.next()?.parse().ok()?, words.next()?.parse().ok()?);
+    words.next()?.starts_with('R').then_some((id, gen))
+}
+
+fn find_type_catalog(body: &[u8]) -> bool {
+    let Some(at) = position(body, b"/Type") else { return false };
+    body[at + 5..].iter().skip_while(|b| b.is_ascii_whitespace()).take(8).eq(b"/Catalog".iter())
+}
+
+fn pages_of(doc: &Document) -> Vec<(u32, String)> {
+    let mut pages = Vec::new();
+    for number in doc.get_pages().into_keys() {
+        let Ok(text) = doc.extract_text(&[number]) else { continue };
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            pages.push((number, text));
+        }
+    }
+    pages
+}
+
+fn info_of(doc: &Document, bytes: &[u8]) -> PdfInfo {
+    let catalog = doc.catalog().ok();
+    // a catalog /Version overrides the header when it is newer
+    let version = catalog
+        .and_then(|c| c.get(b"Version").ok())
+        .and_then(|v| v.as_name().ok())
+        .map(|v| String::from_utf8_lossy(v).into_owned())
+        .filter(|v| v.as_str() > doc.version.as_str())
+        .unwrap_or_else(|| doc.version.clone());
+    let info = doc.trailer.get(b"Info").ok().and_then(|o| dict(doc, o));
+    let text = |key: &[u8]| {
+        let value = info?.get(key).ok()?;
+        lopdf::decode_text_string(deref(doc, value)).ok().filter(|s| !s.is_empty())
+    };
+    let date = |key: &[u8]| text(key).map(|raw| pdf_date(&raw).unwrap_or(raw));
+    let xmp = catalog
+        .and_then(|c| c.get(b"Metadata").ok())
+        .and_then(|o| deref(doc, o).as_stream().ok())
+        .and_then(|s| s.decompressed_content().ok().or_else(|| Some(s.content.clone())))
+        .map(|b| String::from_utf8_lossy(&b).into_owned());
+    let form_fields = catalog
+        .and_then(|c| c.get(b"AcroForm").ok())
+        .and_then(|o| dict(doc, o))
+        .and_then(|f| f.get(b"Fields").ok())
+        .map_or(0, |fields| count_fields(doc, fields, 0));
+
+    PdfInfo {
+        version,
+        page_count: doc.get_pages().len() as u32,
+        title: text(b"Title"),
+        author: text(b"Author"),
+        subject: text(b"Subject"),
+        keywords: text(b"Keywords"),
+        creator: text(b"Creator"),
+        producer: text(b"Producer"),
+        created: date(b"CreationDate"),
+        modified: date(b"ModDate"),
+        xmp,
+        encrypted: doc.trailer.has(b"Encrypt"),
+        linearized: find(&bytes[..bytes.len().min(LINEARIZED_WINDOW)], b"/Linearized"),
+        form_fields,
+        defects: Vec::new(),
+        active_content: active_content(doc),
+        uninspected: false,
+    }
+}
+
+/// What ingest records for a PDF too large to parse: the header's version.
+fn uninspected(path: &std::path::Path) -> Result<Inspected> {
+    let mut head = [0u8; 16];
+    let n = std::fs::File::open(path)?.read(&mut head)?;
+    let version = head[..n]
+        .strip_prefix(b"%PDF-")
+        .map(|v| String::from_utf8_lossy(v.split(|b| b.is_ascii_whitespace()).next().unwrap_or_default()).into_owned())
+        .unwrap_or_default();
+    Ok(Inspected { info: Some(PdfInfo { version, uninspected: true, ..Default::default() }), ..Default::default() })
+}
+
+fn deref<'a>(doc: &'a Document, obj: &'a Object) -> &'a Object {
+    doc.dereference(obj).map_or(obj, |(_, o)| o)
+}
+
+fn dict<'a>(doc: &'a Document, obj: &'a Object) -> Option<&'a Dictionary> {
+    deref(doc, obj).as_dict().ok()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> bool {
+    position(haystack, needle).is_some()
+}
+
+fn position(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Terminal fields under an AcroForm `/Fields` array. A field whose kids
+/// carry no `/T` of their own is terminal; the kids are its widgets.
+fn count_fields(doc: &Document, fields: &Object, depth: usize) -> u32 {
+    let Ok(fields) = deref(doc, fields).as_array() else { return 0 };
+    if depth > MAX_FIELD_DEPTH {
+        return 0;
+    }
+    let mut count = 0;
+    for field in fields {
+        let Some(field) = dict(doc, field) else { continue };
+        let named_kids = field
+            .get(b"Kids")
+            .ok()
+            .and_then(|k| deref(doc, k).as_array().ok())
+            .is_some_and(|kids| kids.iter().any(|k| dict(doc, k).is_some_and(|k| k.has(b"T"))));
+        count += if named_kids { count_fields(doc, field.get(b"Kids").unwrap(), depth + 1) } else { 1 };
+    }
+    count
+}
+
+/// PDF date string (`D:YYYYMMDDHHmmSSOHH'mm'`, trailing parts optional) as
+/// RFC 3339.
+fn pdf_date(raw: &str) -> Option<String> {
+    let s = raw.strip_prefix("D:").unwrap_or(raw);
+    let digits = |from: usize, len: usize, default: u32| -> Option<u32> {
+        match s.get(from..from + len) {
+            Some(part) if part.bytes().all(|b| b.is_ascii_digit()) => part.parse().ok(),
+            Some(_) => None,
+            None => Some(default),
+        }
+    };
+    let year = i32::try_from(digits(0, 4, u32::MAX)?).ok()?;
+    let month = Month::try_from(digits(4, 2, 1)? as u8).ok()?;
+    let date = Date::from_calendar_date(year, month, digits(6, 2, 1)? as u8).ok()?;
+    let time = Time::from_hms(digits(8, 2, 0)? as u8, digits(10, 2, 0)? as u8, digits(12, 2, 0)? as u8).ok()?;
+    let offset = match s.get(14..15) {
+        Some(sign @ ("+" | "-")) => {
+            let zone = s[15..].replace('\'', "");
+            let hours: i8 = zone.get(0..2)?.parse().ok()?;
+            let minutes: i8 = zone.get(2..4).map_or(Some(0), |m| m.parse().ok())?;
+            let sign = if sign == "-" { -1 } else { 1 };
+            UtcOffset::from_hms(sign * hours, sign * minutes, 0).ok()?
+        }
+        _ => UtcOffset::UTC,
+    };
+    OffsetDateTime::new_in_offset(date, time, offset).format(&Rfc3339).ok()
+}
+
+impl StoreInner {
+    /// Identify a staged upload and run its type's validator. PDFs up to
+    /// [`crate::StoreConfig::max_pdf_inspect_bytes`] are then loaded and parsed,
+    /// refused if the configured [`PdfValidation`] policy does not accept
+    /// their defects, and put through the [`ActiveContentPolicy`]; larger
+    /// ones are passed through marked [`PdfInfo::uninspected`].
+    pub(crate) fn inspect_staged(&self, mut staged: Staged) -> Result<Staged> {
+        staged.content_type = content::check(staged.temp.path(), &self.allowed)?;
+        if staged.content_type.mime != content::PDF {
+            return Ok(staged);
+        }
+        // a PDF cannot be parsed without holding all of it
+        if staged.size_bytes > self.config.max_pdf_inspect_bytes {
+            staged.inspected = uninspected(staged.temp.path())?;
+            return Ok(staged);
+        }
+        let bytes = std::fs::read(staged.temp.path()).context("reading staged upload")?;
+        let inspected = inspect(&bytes, cfg!(feature = "search"));
+        if self.config.pdf_validation.rejects(&inspected.defects) {
+            bail!(StoreError::InvalidPdf { defects: inspected.defects });
+        }
+        let found = inspected.info.as_ref().map(|i| i.active_content.clone()).unwrap_or_default();
+        staged.inspected = inspected;
+        match self.config.active_content {
+            _ if found.is_empty() => Ok(staged),
+            ActiveContentPolicy::Flag => Ok(staged),
+            ActiveContentPolicy::Reject => bail!(StoreError::ActiveContent { found }),
+            ActiveContentPolicy::Sanitize => self.sanitize_staged(staged, &bytes, found),
+        }
+    }
+
+    /// Replace a staged upload with its sanitized derivative.
+    fn sanitize_staged(&self, staged: Staged, bytes: &[u8], removed: Vec<ActiveContent>) -> Result<Staged> {
+        let clean = sanitize(bytes).context("sanitizing upload")?;
+        let inspected = inspect(&clean, cfg!(feature = "search"));
+        if let Some(left) = inspected.info.as_ref().filter(|i| !i.active_content.is_empty()) {
+            bail!(StoreError::ActiveContent { found: left.active_content.clone() });
+        }
+        let mut temp = tempfile::Builder::new().prefix("upload-").tempfile_in(self.root.join("tmp"))?;
+        temp.write_all(&clean)?;
+        Ok(Staged {
+            content_type: staged.content_type,
+            temp,
+            sha256: Sha256::digest(&clean).into(),
+            blake3: *blake3::hash(&clean).as_bytes(),
+            size_bytes: clean.len() as u64,
+            leaves: crate::merkle::leaves(&clean),
+            inspected,
+            sanitized: Some(Sanitized {
+                original_sha256: staged.sha256,
+                original_size_bytes: staged.size_bytes,
+                removed,
+            }),
+            sealed: None,
+        })
+    }
+
+    /// Re-read every blob once: fill in [`PdfInfo`] and Merkle roots where
+    /// they are missing or ingest skipped the parse and, with the `search` feature, rebuild the full-text
+    /// index. Returns the
+    /// number of pages indexed.
+    pub(crate) fn reindex_blobs(&self) -> Result<usize> {
+        #[cfg(feature = "search")]
+        let mut rebuild = self.text_index.rebuild()?;
+        let mut pages = 0;
+        for item in self.blob_index.iter() {
+            let blob: BlobMeta = serde_json::from_slice(&item?.1)?;
+            let Some(bytes) = self.read_plain(&blob)? else { continue };
+            self.backfill_merkle(&blob, &bytes)?;
+            let inspected = match blob.content_type.as_str() {
+                content::PDF => inspect(&bytes, cfg!(feature = "search")),
+                _ => Inspected::default(),
+            };
+            if blob.pdf.as_ref().is_none_or(|p| p.uninspected) {
+                if let Some(info) = &inspected.info {
+                    self.set_pdf_info(&blob.sha256, info)?;
+                }
+            }
+            #[cfg(feature = "search")]
+            for record in self.records_for_blob(&blob.sha256)? {
+                if record.deleted.is_none() {
+                    pages += rebuild.add(&hex::encode(record.id), &record.filename, &inspected.pages)?;
+                }
+            }
+        }
+        #[cfg(feature = "search")]
+        rebuild.commit()?;
+        Ok(pages)
+    }
+
+    fn set_pdf_info(&self, sha256: &Hash32, info: &PdfInfo) -> Result<bool> {
+        self.update_blob(sha256, |b| {
+            if b.pdf.as_ref().is_none_or(|p| p.uninspected) {
+                b.pdf = Some(info.clone());
+            }
+        })
+    }
+}
@@ -0,0 +1,379 @@
+//! Secondary indexes and paginated document queries.
+//!
+//! Each record index maps `value ++ 0x00 ++ created_at (BE) ++ record id` to
+//! nothing, so a prefix scan yields the records sharing one value oldest
+//! first, and `idx_created` (empty value) orders every record by age. The CID
+//! index is per blob: `cid ++ 0x00 ++ sha256`. Entries are written in the same
+//! transaction as the record they point at and rebuilt on open if missing.
+
+use std::{fmt, ops::Bound, ops::RangeBounds, str::FromStr};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{TransactionalTree, UnabortableTransactionError};
+
+use crate::{DocMeta, DocRecord, DocStore, Hash32, StoreError, StoreInner};
+
+/// Page size when a query sets no limit.
+pub const DEFAULT_QUERY_LIMIT: usize = 100;
+/// Largest page a query may ask for.
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+/// Record fields with their own index tree, in `StoreInner::indexes` order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Index {
+    Created,
+    Filename,
+    Mime,
+    Owner,
+    Property,
+}
+
+impl Index {
+    pub(crate) const ALL: [Index; 5] = [Index::Created, Index::Filename, Index::Mime, Index::Owner, Index::Property];
+
+    pub(crate) fn tree_name(self) -> &'static str {
+        match self {
+            Index::Created => "idx_created",
+            Index::Filename => "idx_filename",
+            Index::Mime => "idx_mime",
+            Index::Owner => "idx_owner",
+            Index::Property => "idx_property",
+        }
+    }
+
+    /// Indexed value of a record; records without one are left out.
+    fn value(self, record: &DocRecord) -> Option<&str> {
+        match self {
+            Index::Created => Some(""),
+            Index::Filename => Some(&record.filename),
+            Index::Mime => Some(&record.mime),
+            Index::Owner => record.owner.as_deref(),
+            Index::Property => record.property_id.as_deref(),
+        }
+    }
+
+    fn key(self, record: &DocRecord) -> Option<Vec<u8>> {
+        self.value(record).map(|v| index_key(v, record.created_at_unix_ms, &record.id))
+    }
+}
+
+fn index_key(value: &str, created_at_unix_ms: u64, id: &Hash32) -> Vec<u8> {
+    [value.as_bytes(), &[0], &created_at_unix_ms.to_be_bytes(), id].concat()
+}
+
+/// Key in the CID index: CID, a separator, then the blob's sha256.
+pub(crate) fn cid_key(cid: &str, sha256: &Hash32) -> Vec<u8> {
+    [cid.as_bytes(), &[0], sha256].concat()
+}
+
+/// Add (or with `insert` unset, remove) a record's entries inside a
+/// transaction over the index trees.
+pub(crate) fn index_record(
+    trees: [&TransactionalTree; 5],
+    record: &DocRecord,
+    insert: bool,
+) -> Result<(), UnabortableTransactionError> {
+    for (index, tree) in Index::ALL.into_iter().zip(trees) {
+        let Some(key) = index.key(record) else { continue };
+        if insert {
+            tree.insert(key, &[])?;
+        } else {
+            tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Result order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+    FilenameAsc,
+    FilenameDesc,
+}
+
+impl SortOrder {
+    fn by_filename(self) -> bool {
+        matches!(self, SortOrder::FilenameAsc | SortOrder::FilenameDesc)
+    }
+
+    fn descending(self) -> bool {
+        matches!(self, SortOrder::Newest | SortOrder::FilenameDesc)
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s {
+            "newest" => Ok(SortOrder::Newest),
+            "oldest" => Ok(SortOrder::Oldest),
+            "filename_asc" => Ok(SortOrder::FilenameAsc),
+            "filename_desc" => Ok(SortOrder::FilenameDesc),
+            _ => Err(StoreError::InvalidQuery(format!(
+                "unknown sort {s:?} (expected newest, oldest, filename_asc or filename_desc)"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for SortOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SortOrder::Newest => "newest",
+            SortOrder::Oldest => "oldest",
+            SortOrder::FilenameAsc => "filename_asc",
+            SortOrder::FilenameDesc => "filename_desc",
+        })
+    }
+}
+
+/// Filters, order and page for [`DocStore::query`]. Filters are exact
+/// matches and combine with AND; trashed records are never returned.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocQuery {
+    pub filename: Option<String>,
+    pub mime: Option<String>,
+    pub cid: Option<String>,
+    pub owner: Option<String>,
+    pub property_id: Option<String>,
+    /// Inclusive lower bound on `created_at_unix_ms`.
+    pub created_after: Option<u64>,
+    /// Exclusive upper bound on `created_at_unix_ms`.
+    pub created_before: Option<u64>,
+    pub sort: SortOrder,
+    /// Page size, capped at [`MAX_QUERY_LIMIT`]; defaults to [`DEFAULT_QUERY_LIMIT`].
+    pub limit: Option<usize>,
+    /// `next_cursor` of the previous page. Only valid with the same query.
+    pub cursor: Option<String>,
+}
+
+/// One page of query results.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DocPage {
+    pub documents: Vec<DocMeta>,
+    /// Pass back as [`DocQuery::cursor`] for the next page; `None` on the last.
+    pub next_cursor: Option<String>,
+}
+
+impl DocQuery {
+    /// Set a filter from a `key`/`value` pair, e.g. from `--filter mime=application/pdf`.
+    pub fn filter(&mut self, key: &str, value: &str) -> Result<(), StoreError> {
+        let ms = || value.parse().map_err(|_| StoreError::InvalidQuery(format!("{key} must be unix ms, got {value:?}")));
+        match key {
+            "filename" => self.filename = Some(value.into()),
+            "mime" => self.mime = Some(value.into()),
+            "cid" => self.cid = Some(value.into()),
+            "owner" => self.owner = Some(value.into()),
+            "property_id" => self.property_id = Some(value.into()),
+            "created_after" => self.created_after = Some(ms()?),
+            "created_before" => self.created_before = Some(ms()?),
+            _ => return Err(StoreError::InvalidQuery(format!("unknown filter {key:?}"))),
+        }
+        Ok(())
+    }
+
+    /// Index to walk and the value to scan within it (`None` = whole tree).
+    /// Filename order needs the filename index; otherwise the most selective
+    /// filter wins.
+    fn plan(&self) -> (Index, Option<&str>) {
+        if self.sort.by_filename() {
+            return (Index::Filename, self.filename.as_deref());
+        }
+        if self.cid.is_some() {
+            return (Index::Created, Some(""));
+        }
+        [
+            (Index::Property, &self.property_id),
+            (Index::Owner, &self.owner),
+            (Index::Filename, &self.filename),
+            (Index::Mime, &self.mime),
+        ]
+        .into_iter()
+        .find_map(|(index, value)| value.as_deref().map(|v| (index, Some(v))))
+        .unwrap_or((Index::Created, Some("")))
+    }
+
+    fn matches(&self, record: &DocRecord) -> bool {
+        fn eq(filter: &Option<String>, value: Option<&str>) -> bool {
+            filter.as_deref().is_none_or(|f| Some(f) == value)
+        }
+        record.deleted.is_none()
+            && eq(&self.filename, Some(&record.filename))
+            && eq(&self.mime, Some(&record.mime))
+            && eq(&self.owner, record.owner.as_deref())
+            && eq(&self.property_id, record.property_id.as_deref())
+            && self.created_after.is_none_or(|t| record.created_at_unix_ms >= t)
+            && self.created_before.is_none_or(|t| record.created_at_unix_ms < t)
+    }
+
+    /// Key range to walk in the planned index.
+    fn bounds(&self, value: Option<&str>, cursor: Option<Vec<u8>>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+        let (mut lower, mut upper) = match value {
+            None => (Bound::Unbounded, Bound::Unbounded),
+            Some(v) => {
+                let prefix = [v.as_bytes(), &[0]].concat();
+                let at = |ms: u64| [prefix.as_slice(), &ms.to_be_bytes()].concat();
+                let lower = Bound::Included(at(self.created_after.unwrap_or(0)));
+                let upper = match self.created_before {
+                    Some(ms) => Bound::Excluded(at(ms)),
+                    None => Bound::Excluded([v.as_bytes(), &[1]].concat()),
+                };
+                (lower, upper)
+            }
+        };
+        if let Some(cursor) = cursor {
+            if self.sort.descending() {
+                upper = Bound::Excluded(cursor);
+            } else {
+                lower = Bound::Excluded(cursor);
+            }
+        }
+        (lower, upper)
+    }
+}
+
+fn encode_cursor(index: Index, key: &[u8]) -> String {
+    hex::encode([&[index as u8], key].concat())
+}
+
+fn decode_cursor(cursor: &str, index: Index) -> Result<Vec<u8>, StoreError> {
+    let invalid = || StoreError::InvalidQuery("cursor does not belong to this query".into());
+    let bytes = hex::decode(cursor).map_err(|_| invalid())?;
+    match bytes.split_first() {
+        Some((&tag, key)) if tag == index as u8 && key.len() > 40 => Ok(key.to_vec()),
+        _ => Err(invalid()),
+    }
+}
+
+impl StoreInner {
+    fn index_tree(&self, index: Index) -> &sled::Tree {
+        &self.indexes[index as usize]
+    }
+
+    /// Rebuild every index from the records and blobs. The created index is
+    /// written last in one batch, so an interrupted rebuild is redone on the
+    /// next open.
+    pub(crate) fn reindex(&self) -> Result<()> {
+        for tree in self.indexes.iter().chain([&self.cid_index]) {
+            tree.clear()?;
+        }
+        let mut created = sled::Batch::default();
+        for record in self.records() {
+            let record = record?;
+            for index in Index::ALL {
+                let Some(key) = index.key(&record) else { continue };
+                match index {
+                    Index::Created => created.insert(key, &[]),
+                    _ => {
+                        self.index_tree(index).insert(key, &[])?;
+                    }
+                }
+            }
+        }
+        for blob in self.blob_index.iter() {
+            let blob: crate::BlobMeta = serde_json::from_slice(&blob?.1)?;
+            for cid in blob.cids() {
+                self.cid_index.insert(cid_key(cid, &blob.sha256), &[])?;
+            }
+        }
+        self.index_tree(Index::Created).apply_batch(created)?;
+        self.kv.flush()?;
+        Ok(())
+    }
+
+    /// Whether the indexes need a rebuild, e.g. after an upgrade.
+    pub(crate) fn indexes_missing(&self) -> bool {
+        self.index_tree(Index::Created).is_empty() && !self.docs.is_empty()
+    }
+
+    /// Keys, in `index`, of live records whose blob has `cid`.
+    fn cid_keys(&self, cid: &str, index: Index) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for item in self.cid_index.scan_prefix([cid.as_bytes(), &[0]].concat()) {
+            let (key, _) = item?;
+            let sha256: Hash32 = key[key.len() - 32..].try_into()?;
+            for record in self.records_for_blob(&sha256)? {
+                keys.extend(index.key(&record));
+            }
+        }
+        Ok(keys)
+    }
+
+    fn query(&self, q: &DocQuery) -> Result<DocPage> {
+        let limit = q.limit.unwrap_or(DEFAULT_QUERY_LIMIT).clamp(1, MAX_QUERY_LIMIT);
+        let (index, value) = q.plan();
+        let cursor = q.cursor.as_deref().map(|c| decode_cursor(c, index)).transpose()?;
+        let range = q.bounds(value, cursor);
+        let descending = q.sort.descending();
+
+        let keys: Box<dyn Iterator<Item = Result<Vec<u8>>>> = match &q.cid {
+            Some(cid) => {
+                let mut keys = self.cid_keys(cid, index)?;
+                keys.retain(|k| range.contains(k));
+                keys.sort();
+                if descending {
+                    keys.reverse();
+                }
+                Box::new(keys.into_iter().map(Ok))
+            }
+            None => {
+                let iter = self.index_tree(index).range(range).map(|item| Ok(item?.0.to_vec()));
+                if descending { Box::new(iter.rev()) } else { Box::new(iter) }
+            }
+        };
+
+        let mut page = DocPage::default();
+        let mut last_key = Vec::new();
+        for key in keys {
+            let key = key?;
+            let id: Hash32 = key[key.len() - 32..].try_into()?;
+            // entries can outlive a record purged mid-scan
+            let Some(record) = self.record(&id)? else { continue };
+            if !q.matches(&record) {
+                continue;
+            }
+            if page.documents.len() == limit {
+                page.next_cursor = Some(encode_cursor(index, &last_key));
+                break;
+            }
+            page.documents.push(self.record_meta(&record)?);
+            last_key = key;
+        }
+        Ok(page)
+    }
+}
+
+/// Outcome of [`DocStore::reindex`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReindexReport {
+    pub records: usize,
+    /// Pages of text in the full-text index.
+    pub pages: usize,
+}
+
+impl DocStore {
+    /// Rebuild the secondary indexes and, with the `search` feature, the
+    /// full-text index from the stored blobs. Blobs stored before PDF
+    /// metadata was extracted get their [`crate::PdfInfo`] filled in.
+    pub async fn reindex(&self) -> Result<ReindexReport> {
+        self.blocking(|s| {
+            s.reindex()?;
+            let pages = s.reindex_blobs()?;
+            Ok(ReindexReport { records: s.docs.len(), pages })
+        })
+        .await
+    }
+
+    /// One page of live documents matching `query`, served from the
+    /// secondary indexes rather than a scan of every record.
+    pub async fn query(&self, query: DocQuery) -> Result<DocPage> {
+        self.blocking(move |s| s.query(&query)).await
+    }
+}
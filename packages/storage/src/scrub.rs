@@ -0,0 +1,297 @@
+//! Background integrity scrubbing.
+//!
+//! A scrub pass re-hashes every indexed blob at a bounded read rate. Blobs
+//! that still match get `last_verified_at_unix_ms` stamped; corrupted ones
+//! are moved to `<root>/quarantine/` and, when they have a CID, fetched
+//! again from IPFS and re-verified before being put back.
+
+use std::{
+    fs, io,
+    io::Read,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{abort, blob_key, now_ms, tx_result, BlobMeta, DocStore, Hash32, StoreInner};
+
+/// Default scrub read rate: 32 MiB/s.
+pub const DEFAULT_SCRUB_BYTES_PER_SEC: u64 = 32 * 1024 * 1024;
+
+/// How a scrub pass runs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScrubOptions {
+    /// Read-rate cap while hashing; `None` reads as fast as the backend allows.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Fetch corrupted or missing blobs again from IPFS by their CID.
+    pub refetch: bool,
+    /// IPFS API to refetch from (defaults to [`crate::ipfs::DEFAULT_IPFS_URL`]).
+    pub ipfs_url: Option<String>,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self { max_bytes_per_sec: Some(DEFAULT_SCRUB_BYTES_PER_SEC), refetch: true, ipfs_url: None }
+    }
+}
+
+/// Outcome of one scrub pass. The latest one is kept in the `scrub` tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrubReport {
+    pub started_at_unix_ms: u64,
+    pub finished_at_unix_ms: u64,
+    pub blobs_checked: usize,
+    pub bytes_read: u64,
+    /// sha256 of blobs whose bytes no longer matched; now quarantined.
+    pub corrupt: Vec<String>,
+    /// sha256 of indexed blobs with no bytes in the store.
+    pub missing: Vec<String>,
+    /// Corrupt or missing blobs fetched again from IPFS and verified.
+    pub restored: Vec<String>,
+    /// Corrupt or missing blobs still without good bytes.
+    pub unrecoverable: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+enum Verdict {
+    Ok,
+    Corrupt,
+    Missing,
+    /// Deleted since the pass started.
+    Gone,
+}
+
+/// Hash a reader, sleeping as needed to stay under `max_bytes_per_sec`.
+fn hash_throttled(reader: &mut dyn Read, max_bytes_per_sec: Option<u64>) -> io::Result<(Hash32, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut total = 0u64;
+    let start = Instant::now();
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        total += n as u64;
+        if let Some(rate) = max_bytes_per_sec.filter(|r| *r > 0) {
+            let due = Duration::from_secs_f64(total as f64 / rate as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+    Ok((hasher.finalize().into(), total))
+}
+
+impl StoreInner {
+    pub(crate) fn update_blob<F: Fn(&mut BlobMeta)>(&self, sha256: &Hash32, f: F) -> Result<bool> {
+        let updated = tx_result(self.blob_index.transaction(|blobs| {
+            let Some(val) = blobs.get(sha256)? else { return Ok(false) };
+            let mut blob: BlobMeta = serde_json::from_slice(&val).map_err(abort)?;
+            f(&mut blob);
+            blobs.insert(sha256, serde_json::to_vec(&blob).map_err(abort)?)?;
+            Ok(true)
+        }))?;
+        self.kv.flush()?;
+        Ok(updated)
+    }
+
+    /// Whether a blob's plaintext still hashes to its sha256, and how many
+    /// bytes were read; `None` if the bytes are missing. Sealed bytes that
+    /// fail authentication are not intact.
+    fn verify_blob(&self, blob: &BlobMeta, max_bytes_per_sec: Option<u64>) -> Result<Option<(bool, u64)>> {
+        let Some(mut reader) = self.open_blob(blob)? else { return Ok(None) };
+        match hash_throttled(&mut reader, max_bytes_per_sec) {
+            Ok((actual, read)) => Ok(Some((actual == blob.sha256, read))),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(Some((false, 0))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn scrub_blob(&self, sha256: &Hash32, max_bytes_per_sec: Option<u64>) -> Result<(Verdict, u64)> {
+        let Some(blob) = self.blob_meta(sha256)? else {
+            return Ok((Verdict::Gone, 0));
+        };
+        let Some((intact, read)) = self.verify_blob(&blob, max_bytes_per_sec)? else {
+            return Ok((Verdict::Missing, 0));
+        };
+        if intact {
+            let now = now_ms();
+            self.update_blob(sha256, |b| b.last_verified_at_unix_ms = Some(now))?;
+            return Ok((Verdict::Ok, read));
+        }
+        if self.quarantine(sha256)? {
+            Ok((Verdict::Corrupt, read))
+        } else {
+            Ok((Verdict::Gone, read))
+        }
+    }
+
+    /// Move corrupted bytes out of the blob store. Re-checks under the blob
+    /// lock, so bytes replaced or deleted in the meantime are left alone.
+    fn quarantine(&self, sha256: &Hash32) -> Result<bool> {
+        let _guard = self.lock_blob(sha256);
+        let key = blob_key(sha256);
+        let Some(blob) = self.blob_meta(sha256)? else {
+            return Ok(false);
+        };
+        let Some(mut reader) = self.blobs.stream(&key)? else { return Ok(false) };
+        let dir = self.root.join("quarantine");
+        fs::create_dir_all(&dir).context("creating quarantine dir")?;
+        let now = now_ms();
+        let path = dir.join(format!("{}-{now}", hex::encode(sha256)));
+        let mut out = fs::File::create(&path).with_context(|| format!("creating {path:?}"))?;
+        // keep the bytes exactly as stored, sealed or not (a chunked blob's
+        // manifest; its chunks stay while other blobs use them)
+        io::copy(&mut reader, &mut out)?;
+        drop(reader);
+        if self.verify_blob(&blob, None)?.is_none_or(|(intact, _)| intact) {
+            // the bad bytes were replaced by good ones, or removed, since the scan
+            drop(out);
+            fs::remove_file(&path)?;
+            return Ok(false);
+        }
+        out.sync_all()?;
+        self.delete_blob(&blob)?;
+        self.update_blob(sha256, |b| b.quarantined_at_unix_ms = Some(now))?;
+        Ok(true)
+    }
+
+    /// Put refetched bytes back if they hash to `sha256` and the blob is
+    /// still indexed and absent.
+    fn restore_blob(&self, sha256: &Hash32, temp: NamedTempFile) -> Result<bool> {
+        let (actual, _) = hash_throttled(&mut temp.reopen()?, None)?;
+        if actual != *sha256 {
+            return Ok(false);
+        }
+        let _guard = self.lock_blob(sha256);
+        let Some(blob) = self.blob_meta(sha256)? else {
+            return Ok(false);
+        };
+        let encoding = match self.blobs.exists(&blob_key(sha256))? {
+            true => blob.encoding.clone(),
+            false => self.rewrite_blob(&blob, temp)?,
+        };
+        let now = now_ms();
+        self.update_blob(sha256, |b| {
+            b.encoding = encoding.clone();
+            b.quarantined_at_unix_ms = None;
+            b.last_verified_at_unix_ms = Some(now);
+        })?;
+        Ok(true)
+    }
+
+    fn save_scrub_report(&self, report: &ScrubReport) -> Result<()> {
+        self.scrub_state.insert("last", serde_json::to_vec(report)?)?;
+        self.kv.flush()?;
+        Ok(())
+    }
+
+    fn last_scrub_report(&self) -> Result<Option<ScrubReport>> {
+        let Some(val) = self.scrub_state.get("last")? else { return Ok(None) };
+        Ok(Some(serde_json::from_slice(&val)?))
+    }
+}
+
+impl DocStore {
+    /// Re-hash every blob, quarantining and (optionally) refetching the ones
+    /// that no longer match. Passes never overlap; a second caller waits.
+    pub async fn scrub(&self, opts: ScrubOptions) -> Result<ScrubReport> {
+        let _pass = self.inner.scrub_lock.lock().await;
+        let mut report = ScrubReport { started_at_unix_ms: now_ms(), ..Default::default() };
+        let blobs = self
+            .blocking(|s| {
+                s.blob_index
+                    .iter()
+                    .map(|item| Ok(serde_json::from_slice::<BlobMeta>(&item?.1)?))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .await?;
+
+        for blob in blobs {
+            let sha256 = blob.sha256;
+            let sha_hex = hex::encode(sha256);
+            let limit = opts.max_bytes_per_sec;
+            let verdict = match self.blocking(move |s| s.scrub_blob(&sha256, limit)).await {
+                Ok((verdict, read)) => {
+                    report.bytes_read += read;
+                    verdict
+                }
+                Err(e) => {
+                    report.errors.push(format!("{sha_hex}: {e:#}"));
+                    continue;
+                }
+            };
+            match verdict {
+                Verdict::Gone => continue,
+                Verdict::Ok => {}
+                Verdict::Corrupt => report.corrupt.push(sha_hex.clone()),
+                Verdict::Missing => report.missing.push(sha_hex.clone()),
+            }
+            report.blobs_checked += 1;
+            if matches!(verdict, Verdict::Corrupt | Verdict::Missing) {
+                match self.refetch(&blob, &opts).await {
+                    Ok(true) => report.restored.push(sha_hex),
+                    Ok(false) => report.unrecoverable.push(sha_hex),
+                    Err(e) => {
+                        report.errors.push(format!("{sha_hex}: refetch failed: {e:#}"));
+                        report.unrecoverable.push(sha_hex);
+                    }
+                }
+            }
+        }
+
+        report.finished_at_unix_ms = now_ms();
+        let saved = report.clone();
+        self.blocking(move |s| s.save_scrub_report(&saved)).await?;
+        Ok(report)
+    }
+
+    #[cfg(feature = "ipfs")]
+    async fn refetch(&self, blob: &BlobMeta, opts: &ScrubOptions) -> Result<bool> {
+        if !opts.refetch {
+            return Ok(false);
+        }
+        let Some(temp) = self.fetch_ipfs(blob, opts.ipfs_url.as_deref()).await? else { return Ok(false) };
+        let sha256 = blob.sha256;
+        self.blocking(move |s| s.restore_blob(&sha256, temp)).await
+    }
+
+    #[cfg(not(feature = "ipfs"))]
+    async fn refetch(&self, _blob: &BlobMeta, _opts: &ScrubOptions) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Report of the most recent scrub pass, if any.
+    pub async fn last_scrub(&self) -> Result<Option<ScrubReport>> {
+        self.blocking(|s| s.last_scrub_report()).await
+    }
+
+    /// Run [`Self::scrub`] every `every`, starting one interval from now.
+    pub fn spawn_scrub_job(&self, every: Duration, opts: ScrubOptions) -> tokio::task::JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+            loop {
+                ticker.tick().await;
+                match store.scrub(opts.clone()).await {
+                    Ok(r) if !r.corrupt.is_empty() || !r.missing.is_empty() || !r.errors.is_empty() => eprintln!(
+                        "scrub: {} corrupt, {} missing, {} restored, {} unrecoverable, {} errors",
+                        r.corrupt.len(),
+                        r.missing.len(),
+                        r.restored.len(),
+                        r.unrecoverable.len(),
+                        r.errors.len(),
+                    ),
+                    Ok(_) => {}
+                    Err(e) => eprintln!("scrub: pass failed: {e:#}"),
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,216 @@
+//! Full-text search over document contents.
+//!
+//! Page text is extracted when a document is stored and indexed with tantivy
+//! under `<root>/search`, one entry per page (plus one for the filename when
+//! a file has no extractable text). Trashing a record removes its entries,
+//! restoring adds them back, and [`DocStore::reindex`] rebuilds everything
+//! from the stored blobs.
+
+use std::{
+    fs,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tantivy::{
+    collector::TopDocs,
+    doc,
+    query::QueryParser,
+    schema::{Field, Schema, Value, STORED, STRING, TEXT},
+    snippet::SnippetGenerator,
+    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+
+use crate::{pdf, DocRecord, DocStore, Hash32, StoreError, StoreInner};
+
+/// Hits returned when a search sets no limit.
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+const WRITER_MEMORY_BYTES: usize = 32 * 1024 * 1024;
+const SNIPPET_CHARS: usize = 200;
+
+/// One matching page.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SearchHit {
+    pub id_hex: String,
+    pub filename: String,
+    /// 1-based page number; `None` when only the filename matched.
+    pub page: Option<u32>,
+    pub score: f32,
+    /// HTML fragment of the page text with matches in `<b>` tags.
+    pub snippet: String,
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    id: Field,
+    filename: Field,
+    page: Field,
+    text: Field,
+}
+
+pub(crate) struct SearchIndex {
+    index: Index,
+    writer: Mutex<IndexWriter>,
+    reader: IndexReader,
+    fields: Fields,
+}
+
+impl SearchIndex {
+    pub(crate) fn open(dir: &Path) -> Result<Self> {
+        let mut schema = Schema::builder();
+        let fields = Fields {
+            id: schema.add_text_field("id", STRING | STORED),
+            filename: schema.add_text_field("filename", TEXT | STORED),
+            page: schema.add_u64_field("page", STORED),
+            text: schema.add_text_field("text", TEXT | STORED),
+        };
+        fs::create_dir_all(dir).context("creating search dir")?;
+        let directory = tantivy::directory::MmapDirectory::open(dir)?;
+        let index = Index::open_or_create(directory, schema.build())?;
+        let writer = index.writer(WRITER_MEMORY_BYTES)?;
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into()?;
+        Ok(Self { index, writer: Mutex::new(writer), reader, fields })
+    }
+
+    fn writer(&self) -> MutexGuard<'_, IndexWriter> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Start replacing every entry.
+    pub(crate) fn rebuild(&self) -> Result<Rebuild<'_>> {
+        let writer = self.writer();
+        writer.delete_all_documents()?;
+        Ok(Rebuild { index: self, writer })
+    }
+
+    fn commit(&self, writer: &mut IndexWriter) -> Result<()> {
+        writer.commit()?;
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    fn add_pages(&self, writer: &IndexWriter, id: &str, filename: &str, pages: &[(u32, String)]) -> Result<usize> {
+        let f = self.fields;
+        writer.delete_term(Term::from_field_text(f.id, id));
+        if pages.is_empty() {
+            writer.add_document(doc!(f.id => id, f.filename => filename, f.page => 0u64))?;
+        }
+        for (number, text) in pages {
+            writer.add_document(doc!(
+                f.id => id,
+                f.filename => filename,
+                f.page => u64::from(*number),
+                f.text => text.clone(),
+            ))?;
+        }
+        Ok(pages.len())
+    }
+
+    fn add(&self, id: &str, filename: &str, pages: &[(u32, String)]) -> Result<()> {
+        let mut writer = self.writer();
+        self.add_pages(&writer, id, filename, pages)?;
+        self.commit(&mut writer)
+    }
+
+    fn remove(&self, id: &Hash32) -> Result<()> {
+        let mut writer = self.writer();
+        writer.delete_term(Term::from_field_text(self.fields.id, &hex::encode(id)));
+        self.commit(&mut writer)
+    }
+
+    fn search(&self, q: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let f = self.fields;
+        let parser = QueryParser::for_index(&self.index, vec![f.text, f.filename]);
+        let query = parser.parse_query(q).map_err(|e| StoreError::InvalidQuery(e.to_string()))?;
+        let searcher = self.reader.searcher();
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, f.text)?;
+        snippets.set_max_num_chars(SNIPPET_CHARS);
+        let mut hits = Vec::new();
+        for (score, addr) in searcher.search(&query, &TopDocs::with_limit(limit))? {
+            let doc: TantivyDocument = searcher.doc(addr)?;
+            let text = |field| doc.get_first(field).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+            let page = doc.get_first(f.page).and_then(|v| v.as_u64()).unwrap_or(0);
+            hits.push(SearchHit {
+                id_hex: text(f.id),
+                filename: text(f.filename),
+                page: u32::try_from(page).ok().filter(|p| *p > 0),
+                score,
+                snippet: snippets.snippet_from_doc(&doc).to_html(),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+/// Replaces the whole index; nothing is visible until [`Rebuild::commit`].
+pub(crate) struct Rebuild<'a> {
+    index: &'a SearchIndex,
+    writer: MutexGuard<'a, IndexWriter>,
+}
+
+impl Rebuild<'_> {
+    /// Queue a record's pages, returning how many were added.
+    pub(crate) fn add(&mut self, id_hex: &str, filename: &str, pages: &[(u32, String)]) -> Result<usize> {
+        self.index.add_pages(&self.writer, id_hex, filename, pages)
+    }
+
+    pub(crate) fn commit(mut self) -> Result<()> {
+        self.index.commit(&mut self.writer)
+    }
+}
+
+impl StoreInner {
+    /// Index a record's pages. The record is stored either way, so failures
+    /// are only logged; [`DocStore::reindex`] catches up.
+    pub(crate) fn search_add(&self, id_hex: &str, filename: &str, pages: &[(u32, String)]) {
+        if let Err(e) = self.text_index.add(id_hex, filename, pages) {
+            eprintln!("search: indexing {id_hex} failed: {e:#}");
+        }
+    }
+
+    /// Index a record again from its stored bytes, e.g. after a restore.
+    pub(crate) fn search_add_stored(&self, record: &DocRecord) {
+        let bytes = self.blob_meta(&record.sha256).and_then(|blob| match blob {
+            Some(blob) => self.read_plain(&blob),
+            None => Ok(None),
+        });
+        let pages = match bytes {
+            Ok(Some(bytes)) => pdf::extract_pages(&bytes).unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        self.search_add(&hex::encode(record.id), &record.filename, &pages);
+    }
+
+    pub(crate) fn search_remove(&self, id: &Hash32) {
+        if let Err(e) = self.text_index.remove(id) {
+            eprintln!("search: removing {} failed: {e:#}", hex::encode(id));
+        }
+    }
+
+    fn search(&self, q: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        if q.trim().is_empty() {
+            return Err(StoreError::InvalidQuery("empty search".into()).into());
+        }
+        let mut hits = self.text_index.search(q, limit)?;
+        // entries of a record trashed or purged mid-commit
+        hits.retain(|hit| {
+            crate::parse_id(&hit.id_hex)
+                .ok()
+                .and_then(|id| self.record(&id).ok().flatten())
+                .is_some_and(|r| r.deleted.is_none())
+        });
+        Ok(hits)
+    }
+}
+
+impl DocStore {
+    /// Pages whose text or filename match `q` (tantivy query syntax: words,
+    /// `"phrases"`, `AND`/`OR`), best first. Trashed records are excluded.
+    pub async fn search(&self, q: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let q = q.to_string();
+        self.blocking(move |s| s.search(&q, limit)).await
+    }
+}
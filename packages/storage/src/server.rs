@@ -1,24 +1,115 @@
 
 use anyhow::{Context, Result};
 use axum::{
-    extract::{Path, Query, State, Multipart},
-    http::{StatusCode, header},
+    body::{Body, Bytes},
+    async_trait,
+    extract::{DefaultBodyLimit, FromRequestParts, Path, Query, State, Multipart, multipart::MultipartError},
+    extract::Request,
+    http::{request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    middleware::{self, Next},
     response::{IntoResponse, Response, Json},
-    routing::{get, post},
+    routing::{get, head, post},
     Router,
 };
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, path::PathBuf, net::SocketAddr};
-use store::DocStore;
+use sha2::{Digest, Sha256};
+use std::{collections::{BTreeMap, HashMap}, net::SocketAddr, sync::Arc};
+use futures_util::{StreamExt, TryStreamExt};
+use store::{Checksum, ChecksumAlgorithm, DocQuery, DocStore, ScrubOptions, StoreConfig, StoreError, Tenants};
+use tokio_util::io::{ReaderStream, StreamReader};
 use tower_http::cors::CorsLayer;
 
 /// Application state shared across handlers
 #[derive(Clone)]
 struct AppState {
-    db: Arc<DocStore>,
+    /// The only store, when the server runs without tenants.
+    single: Option<TenantState>,
+    tenants: Arc<BTreeMap<String, TenantState>>,
+    /// sha256 of a bearer token -> tenant it grants
+    tokens: Arc<HashMap<[u8; 32], String>>,
+    config: StoreConfig,
+}
+
+/// One tenant's store and where it pins and publishes.
+#[derive(Clone)]
+struct TenantState {
+    db: DocStore,
     ipfs_url: Option<String>,
     node_url: String,
     seed: String,
+    scrub: ScrubOptions,
+    /// Selectable with `X-Tenant` alone; only tenants without tokens are.
+    open: bool,
+}
+
+/// One entry of `TENANTS_FILE`; unset settings fall back to `IPFS_URL`,
+/// `NODE_URL` and `SEED`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantSettings {
+    /// Hex sha256 of each bearer token granting the tenant. Without any,
+    /// the `X-Tenant` header alone selects it.
+    #[serde(default)]
+    tokens_sha256: Vec<String>,
+    ipfs_url: Option<String>,
+    node_url: Option<String>,
+    seed: Option<String>,
+}
+
+/// The caller's tenant: the one its bearer token grants, else the one
+/// `X-Tenant` names. Every `/api` handler works inside it, so ids from
+/// other tenants are not found.
+struct Tenant(TenantState);
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let reject = |status: StatusCode, error: String| {
+            let mut res = (status, Json(ErrorResponse { success: false, error, defects: Vec::new() })).into_response();
+            if status == StatusCode::UNAUTHORIZED {
+                res.headers_mut().insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            res
+        };
+        let named = match parts.headers.get("x-tenant").map(HeaderValue::to_str).transpose() {
+            Ok(named) => named,
+            Err(_) => return Err(reject(StatusCode::BAD_REQUEST, "malformed X-Tenant header".into())),
+        };
+        if let Some(single) = &state.single {
+            return match named {
+                Some(_) => Err(reject(StatusCode::BAD_REQUEST, "this server does not run tenants (no TENANTS_FILE)".into())),
+                None => Ok(Self(single.clone())),
+            };
+        }
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let name = match (token, named) {
+            (Some(token), named) => {
+                let digest: [u8; 32] = Sha256::digest(token.trim()).into();
+                let Some(name) = state.tokens.get(&digest) else {
+                    return Err(reject(StatusCode::UNAUTHORIZED, "invalid bearer token".into()));
+                };
+                if named.is_some_and(|n| n != name) {
+                    return Err(reject(StatusCode::FORBIDDEN, "the bearer token is for another tenant".into()));
+                }
+                name.as_str()
+            }
+            (None, Some(named)) if state.tenants.get(named).is_some_and(|t| t.open) => named,
+            (None, Some(named)) => {
+                return Err(reject(StatusCode::UNAUTHORIZED, format!("tenant {named:?} is unknown or needs a bearer token")));
+            }
+            (None, None) => {
+                return Err(reject(StatusCode::UNAUTHORIZED, "send a bearer token or name a tenant with X-Tenant".into()));
+            }
+        };
+        Ok(Self(state.tenants[name].clone()))
+    }
 }
 
 /// Response for successful document storage
@@ -28,9 +119,17 @@ struct StoreResponse {
     id: String,
     sha256: String,
     cid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encrypted_cid: Option<String>,
     size_bytes: u64,
     block_hash: Option<String>,
     message: String,
+    /// Repairable PDF defects and active content the upload was accepted with.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+    /// Hash of the upload when a sanitized copy was stored instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_sha256: Option<String>,
 }
 
 /// Response for document retrieval
@@ -41,12 +140,41 @@ struct GetResponse {
     message: String,
 }
 
+/// Response for version history
+#[derive(Serialize)]
+struct VersionsResponse {
+    success: bool,
+    document_id: Option<String>,
+    latest: Option<store::DocMeta>,
+    /// Version current at the requested `at` timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    at: Option<store::DocMeta>,
+    versions: Vec<store::DocMeta>,
+}
+
+/// Query parameters for version history
+#[derive(Deserialize)]
+struct VersionsQuery {
+    /// Unix timestamp in milliseconds
+    at: Option<u64>,
+}
+
+/// Query parameters for delete
+#[derive(Deserialize)]
+struct DeleteQuery {
+    deleted_by: Option<String>,
+    reason: Option<String>,
+}
+
 /// Response for list operation
 #[derive(Serialize)]
 struct ListResponse {
     success: bool,
     documents: Vec<store::DocMeta>,
     count: usize,
+    /// Cursor for the next page, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
 /// Response for delete operation
@@ -61,13 +189,29 @@ struct DeleteResponse {
 struct ErrorResponse {
     success: bool,
     error: String,
+    /// Why a PDF was rejected, one entry per structural defect.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    defects: Vec<store::PdfDefect>,
 }
 
 /// Query parameters for store endpoint
 /// Note: IPFS pinning and blockchain publishing are now MANDATORY for full decentralization
 #[derive(Deserialize)]
 struct StoreQuery {
-    // Removed optional flags - always pin to IPFS and publish to blockchain
+    /// Who uploaded the document, recorded on its record
+    uploader: Option<String>,
+    /// Comma-separated tags
+    tags: Option<String>,
+    /// Record id of the version this upload replaces
+    supersedes: Option<String>,
+    /// Owner of the property the document belongs to
+    owner: Option<String>,
+    /// Property the document belongs to
+    property_id: Option<String>,
+}
+
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect()
 }
 
 /// Health check endpoint
@@ -82,62 +226,86 @@ async fn health_check() -> impl IntoResponse {
 /// Store a PDF document with MANDATORY IPFS pinning and blockchain publishing
 /// POST /api/store (always pins to IPFS and publishes to blockchain)
 async fn store_pdf(
-    State(state): State<AppState>,
-    Query(_params): Query<StoreQuery>,
+    Tenant(state): Tenant,
+    Query(params): Query<StoreQuery>,
+    headers: HeaderMap,
     mut multipart: Multipart,
 ) -> Result<Json<StoreResponse>, AppError> {
+    let expected_sha256 = upload_digest(&headers)?;
     // Extract the file from multipart form data
-    let mut temp_path: Option<PathBuf> = None;
+    let mut stored: Option<store::DocMeta> = None;
     
     while let Some(field) = multipart.next_field().await? {
         let name = field.name().unwrap_or("");
         if name == "file" {
             let filename = field.file_name()
-                .unwrap_or("upload.pdf")
+                .unwrap_or("upload")
                 .to_string();
+            let mime = field.content_type()
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let input = store::DocInput {
+                uploader: params.uploader.clone(),
+                tags: params.tags.as_deref().map(split_tags).unwrap_or_default(),
+                supersedes: params.supersedes.clone(),
+                owner: params.owner.clone(),
+                property_id: params.property_id.clone(),
+                expected_sha256,
+                ..store::DocInput::new(filename, mime)
+            };
             
-            // Save to temp file
-            let data = field.bytes().await?;
-            let temp_dir = std::env::temp_dir();
-            let temp_file = temp_dir.join(filename);
-            std::fs::write(&temp_file, data)?;
-            temp_path = Some(temp_file);
+            // Stream chunks straight into the store; nothing is buffered whole
+            let reader = StreamReader::new(field.map_err(std::io::Error::other));
+            // ALWAYS store with IPFS pinning (mandatory for decentralization)
+            stored = Some(state.db.store_with_ipfs(reader, input, state.ipfs_url.as_deref()).await?);
             break;
         }
     }
     
-    let temp_path = temp_path.ok_or_else(|| anyhow::anyhow!("No file provided"))?;
-    
-    // ALWAYS store with IPFS pinning (mandatory for decentralization)
-    let meta = state.db.store_pdf_with_ipfs(&temp_path, state.ipfs_url.as_deref())?;
+    let meta = stored.ok_or_else(|| anyhow::anyhow!("No file provided"))?;
     
     // ALWAYS publish to blockchain (mandatory for tamper-proof registry)
-    use tokio::runtime::Runtime;
     use store::chain::publish_remark;
-    let rt = Runtime::new()?;
-    let block_hash = rt.block_on(publish_remark(&state.node_url, &state.seed, &meta))?;
-    
-    // Clean up temp file
-    let _ = std::fs::remove_file(temp_path);
+    let block_hash = publish_remark(&state.node_url, &state.seed, &meta).await?;
+    state.db.set_anchor(&meta.id_hex, &block_hash).await?;
     
     Ok(Json(StoreResponse {
         success: true,
         id: meta.id_hex.clone(),
-        sha256: meta.id_hex.clone(),
+        sha256: hex::encode(meta.sha256),
         cid: meta.cid.clone(),
+        encrypted_cid: meta.encrypted_cid.clone(),
         size_bytes: meta.size_bytes,
         block_hash: Some(block_hash),
         message: "PDF stored successfully on IPFS and on-chain".to_string(),
+        warnings: upload_warnings(&meta),
+        original_sha256: meta.sanitized.as_ref().map(|s| hex::encode(s.original_sha256)),
     }))
 }
 
+fn upload_warnings(meta: &store::DocMeta) -> Vec<String> {
+    let mut warnings: Vec<String> = meta.pdf.iter().flat_map(|p| &p.defects).map(ToString::to_string).collect();
+    if let Some(pdf) = meta.pdf.as_ref().filter(|p| !p.active_content.is_empty()) {
+        let kinds: Vec<_> = pdf.active_content.iter().map(ToString::to_string).collect();
+        warnings.push(format!("contains active content: {}", kinds.join(", ")));
+    }
+    if meta.pdf.as_ref().is_some_and(|p| p.uninspected) {
+        warnings.push("too large to inspect: stored without validation or active content checks".into());
+    }
+    if let Some(sanitized) = &meta.sanitized {
+        let kinds: Vec<_> = sanitized.removed.iter().map(ToString::to_string).collect();
+        warnings.push(format!("removed active content: {}", kinds.join(", ")));
+    }
+    warnings
+}
+
 /// Get document metadata by ID
 /// GET /api/docs/:id
 async fn get_metadata(
-    State(state): State<AppState>,
+    Tenant(state): Tenant,
     Path(id): Path<String>,
 ) -> Result<Json<GetResponse>, AppError> {
-    let meta = state.db.get_by_hex(&id)?;
+    let meta = state.db.get_by_hex(&id).await?;
     
     Ok(Json(GetResponse {
         success: meta.is_some(),
@@ -150,84 +318,699 @@ async fn get_metadata(
     }))
 }
 
-/// Download PDF file by ID
-/// GET /api/docs/:id/download
+/// Version history of the document a record belongs to
+/// GET /api/docs/:id/versions?at=<unix_ms>
+async fn get_versions(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+    Query(params): Query<VersionsQuery>,
+) -> Result<Json<VersionsResponse>, AppError> {
+    let versions = state.db.history(&id).await?;
+    // trashed versions stay in the history but are never current
+    let latest = state.db.latest(&id).await?;
+    let at = match params.at {
+        Some(ts) => state.db.at(&id, ts).await?,
+        None => None,
+    };
+
+    Ok(Json(VersionsResponse {
+        success: !versions.is_empty(),
+        document_id: versions.first().map(|m| m.document_id.clone()),
+        latest,
+        at,
+        versions,
+    }))
+}
+
+/// Documents never change once stored, so blob responses may be cached for good.
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// The `sha-256` member of an RFC 9530 `Content-Digest` or `Repr-Digest`
+/// dictionary (`sha-256=:<base64>:, sha-512=...`). Other algorithms are
+/// ignored, as the store only computes sha256.
+fn sha256_digest(headers: &HeaderMap, name: header::HeaderName) -> Result<Option<[u8; 32]>, StoreError> {
+    let invalid = || StoreError::InvalidQuery(format!("invalid {name} header"));
+    let Some(value) = headers.get(&name) else { return Ok(None) };
+    for member in value.to_str().map_err(|_| invalid())?.split(',') {
+        let Some((key, value)) = member.split_once('=') else { continue };
+        if !key.trim().eq_ignore_ascii_case("sha-256") {
+            continue;
+        }
+        // drop parameters, then the byte-sequence colons
+        let value = value.split(';').next().unwrap_or_default().trim();
+        let encoded = value.strip_prefix(':').and_then(|v| v.strip_suffix(':')).ok_or_else(invalid)?;
+        let digest = BASE64.decode(encoded).map_err(|_| invalid())?;
+        return digest.try_into().map(Some).map_err(|_| invalid());
+    }
+    Ok(None)
+}
+
+/// sha256 an uploaded file must have, from `Repr-Digest` or `Content-Digest`.
+/// Both are taken to describe the file itself, not any multipart framing.
+fn upload_digest(headers: &HeaderMap) -> Result<Option<[u8; 32]>, StoreError> {
+    let repr = sha256_digest(headers, HeaderName::from_static("repr-digest"))?;
+    let content = sha256_digest(headers, HeaderName::from_static("content-digest"))?;
+    match (repr, content) {
+        (Some(a), Some(b)) if a != b => Err(StoreError::InvalidQuery("Repr-Digest and Content-Digest disagree".into())),
+        (repr, content) => Ok(repr.or(content)),
+    }
+}
+
+/// Validators and caching headers shared by every response for a document's bytes.
+fn blob_headers(meta: &store::DocMeta) -> Result<HeaderMap, AppError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, format!("\"{}\"", hex::encode(meta.sha256)).parse()?);
+    headers.insert(header::LAST_MODIFIED, httpdate::fmt_http_date(created_at(meta)).parse()?);
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(IMMUTABLE));
+    headers.insert(
+        HeaderName::from_static("repr-digest"),
+        format!("sha-256=:{}:", BASE64.encode(meta.sha256)).parse()?,
+    );
+    if let Some(root) = meta.merkle_root {
+        headers.insert(HeaderName::from_static("merkle-root"), hex::encode(root).parse()?);
+    }
+    if let Some(blake3) = meta.blake3 {
+        headers.insert(HeaderName::from_static("blake3"), hex::encode(blake3).parse()?);
+    }
+    Ok(headers)
+}
+
+/// `Merkle-Proof` value for the chunks `range` touches: the proof's bytes as
+/// a structured-field byte sequence. Checks with [`store::verify_range`] once
+/// the client holds those whole chunks.
+async fn merkle_proof(db: &DocStore, meta: &store::DocMeta, range: std::ops::Range<u64>) -> Result<Option<String>, AppError> {
+    let proof = db.range_proof(meta, range).await?;
+    Ok(proof.map(|p| format!(":{}:", BASE64.encode(p.to_bytes()))))
+}
+
+/// When the record was stored, at the one-second resolution of HTTP dates.
+fn created_at(meta: &store::DocMeta) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs(meta.created_at_unix_ms / 1000)
+}
+
+/// Whether an entity tag list (`If-None-Match`, `If-Range`) names this
+/// document; `weak` allows `W/` tags, as `If-None-Match` does.
+fn etag_matches(value: &HeaderValue, meta: &store::DocMeta, weak: bool) -> bool {
+    let ours = hex::encode(meta.sha256);
+    value.to_str().unwrap_or_default().split(',').map(str::trim).any(|tag| {
+        let tag = match tag.strip_prefix("W/") {
+            Some(t) if weak => t,
+            Some(_) => return false,
+            None => tag,
+        };
+        tag == "*" || tag.trim_matches('"') == ours
+    })
+}
+
+/// Whether the client's cached copy is current (RFC 9110 §13.2.2):
+/// `If-None-Match` when sent, else `If-Modified-Since`.
+fn not_modified(headers: &HeaderMap, meta: &store::DocMeta) -> bool {
+    if let Some(tags) = headers.get(header::IF_NONE_MATCH) {
+        return etag_matches(tags, meta, true);
+    }
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| httpdate::parse_http_date(v.to_str().ok()?).ok())
+        .is_some_and(|since| created_at(meta) <= since)
+}
+
+/// Whether a `Range` may be honoured: without `If-Range`, or when it still
+/// names this document by strong tag or date.
+fn if_range_holds(headers: &HeaderMap, meta: &store::DocMeta) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else { return true };
+    match value.to_str().ok().and_then(|v| httpdate::parse_http_date(v).ok()) {
+        Some(date) => created_at(meta) <= date,
+        None => etag_matches(value, meta, false),
+    }
+}
+
+/// How a download is presented: saved as a file, or shown in the browser
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Disposition {
+    #[default]
+    Attachment,
+    Inline,
+}
+
+/// Query parameters for downloads
+#[derive(Debug, Deserialize)]
+struct DownloadQuery {
+    #[serde(default)]
+    disposition: Disposition,
+}
+
+/// `Content-Disposition` per RFC 6266: a quoted ASCII fallback plus the exact
+/// name as RFC 8187 `filename*`. Quotes, backslashes, `%` and control
+/// characters never reach the header, so a stored name cannot break or
+/// inject one.
+fn content_disposition(disposition: Disposition, filename: &str) -> String {
+    use std::fmt::Write;
+
+    let kind = match disposition {
+        Disposition::Attachment => "attachment",
+        Disposition::Inline => "inline",
+    };
+    let filename = if filename.is_empty() { "download" } else { filename };
+    let fallback: String = filename
+        .chars()
+        .map(|c| if c == ' ' || (c.is_ascii_graphic() && !matches!(c, '"' | '\\' | '%')) { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for b in filename.bytes() {
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{b:02X}");
+        }
+    }
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Ranges beyond this many in one request are ignored and the whole body sent.
+const MAX_RANGES: usize = 64;
+
+/// Byte ranges of a `Range` header (RFC 9110 §14.2) within `size` bytes,
+/// sorted and coalesced. `None` means the header is to be ignored (another
+/// unit, malformed, or too many ranges); an empty list means no range is
+/// satisfiable.
+fn parse_ranges(value: &str, size: u64) -> Option<Vec<std::ops::Range<u64>>> {
+    let specs: Vec<_> = value.trim().strip_prefix("bytes=")?.split(',').map(str::trim).collect();
+    if specs.len() > MAX_RANGES {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let range = if first.is_empty() {
+            let suffix: u64 = last.parse().ok()?;
+            size.saturating_sub(suffix)..size
+        } else {
+            let start: u64 = first.parse().ok()?;
+            let end = match last {
+                "" => size,
+                last => {
+                    let last: u64 = last.parse().ok()?;
+                    if last < start {
+                        return None;
+                    }
+                    last.saturating_add(1).min(size)
+                }
+            };
+            start..end
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<std::ops::Range<u64>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(prev) if range.start <= prev.end => prev.end = prev.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    Some(merged)
+}
+
+/// Stream a document's bytes by ID, as their detected content type. Honours
+/// single and multiple `Range`s (206, multipart/byteranges for several, 416),
+/// `If-Range`, and `If-None-Match`/`If-Modified-Since` (304)
+/// GET /api/docs/:id/download?disposition=attachment|inline
 async fn download_pdf(
-    State(state): State<AppState>,
+    Tenant(state): Tenant,
     Path(id): Path<String>,
+    Query(params): Query<DownloadQuery>,
+    headers: HeaderMap,
 ) -> Result<Response, AppError> {
-    let meta = state.db.get_by_hex(&id)?
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
-    
-    let pdf_path = state.db.root().join("pdfs").join(format!("{id}.pdf"));
-    let data = std::fs::read(&pdf_path)?;
-    
-    Ok((
-        StatusCode::OK,
-        [
-            (header::CONTENT_TYPE, "application/pdf"),
-            (header::CONTENT_DISPOSITION, &format!("attachment; filename=\"{}\"", meta.filename)),
-        ],
-        data,
-    ).into_response())
+    let meta = state.db.get_by_hex(&id).await?
+        .ok_or_else(|| StoreError::NotFound { id: id.clone() })?;
+    if meta.deleted.is_some() {
+        return Err(StoreError::Deleted { id: meta.id_hex }.into());
+    }
+    let size = meta.size_bytes;
+    let mut res_headers = blob_headers(&meta)?;
+    if not_modified(&headers, &meta) {
+        return Ok((StatusCode::NOT_MODIFIED, res_headers).into_response());
+    }
+    res_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    res_headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    res_headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&content_disposition(params.disposition, &meta.filename))?,
+    );
+    let content_type = HeaderValue::from_str(&meta.content_type)?;
+    let ranges = headers
+        .get(header::RANGE)
+        .filter(|_| if_range_holds(&headers, &meta))
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_ranges(v, size));
+
+    let (status, body, length) = match ranges.as_deref() {
+        None => {
+            res_headers.insert(header::CONTENT_TYPE, content_type);
+            (StatusCode::OK, Body::from_stream(ReaderStream::new(state.db.stream_blob(&meta, 0..size).await?)), size)
+        }
+        Some([]) => {
+            res_headers.insert(header::CONTENT_RANGE, format!("bytes */{size}").parse()?);
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, res_headers).into_response());
+        }
+        Some([range]) => {
+            res_headers.insert(header::CONTENT_TYPE, content_type);
+            res_headers.insert(header::CONTENT_RANGE, format!("bytes {}-{}/{size}", range.start, range.end - 1).parse()?);
+            if let Some(proof) = merkle_proof(&state.db, &meta, range.clone()).await? {
+                res_headers.insert(HeaderName::from_static("merkle-proof"), proof.parse()?);
+            }
+            let reader = state.db.stream_blob(&meta, range.clone()).await?;
+            (StatusCode::PARTIAL_CONTENT, Body::from_stream(ReaderStream::new(reader)), range.end - range.start)
+        }
+        Some(ranges) => {
+            let mut boundary = [0u8; 16];
+            getrandom::getrandom(&mut boundary).map_err(|e| anyhow::anyhow!("generating boundary: {e}"))?;
+            let boundary = hex::encode(boundary);
+            res_headers.insert(header::CONTENT_TYPE, format!("multipart/byteranges; boundary={boundary}").parse()?);
+            let mut parts = Vec::with_capacity(ranges.len());
+            for r in ranges {
+                let proof = merkle_proof(&state.db, &meta, r.clone()).await?;
+                let head = format!(
+                    "\r\n--{boundary}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{size}\r\n{}\r\n",
+                    meta.content_type,
+                    r.start,
+                    r.end - 1,
+                    proof.map_or(String::new(), |p| format!("Merkle-Proof: {p}\r\n")),
+                );
+                parts.push((Bytes::from(head), r.clone()));
+            }
+            let closing = Bytes::from(format!("\r\n--{boundary}--\r\n"));
+            let length = parts.iter().map(|(head, r)| head.len() as u64 + r.end - r.start).sum::<u64>() + closing.len() as u64;
+            // each range is opened only once the previous one has been sent
+            let (db, meta) = (state.db.clone(), meta.clone());
+            let body = futures_util::stream::iter(parts)
+                .then(move |(head, range)| {
+                    let (db, meta) = (db.clone(), meta.clone());
+                    async move {
+                        let reader = db.stream_blob(&meta, range).await.map_err(std::io::Error::other)?;
+                        let head = futures_util::stream::once(async { Ok(head) });
+                        Ok::<_, std::io::Error>(head.chain(ReaderStream::new(reader)))
+                    }
+                })
+                .try_flatten()
+                .chain(futures_util::stream::once(async { Ok(closing) }));
+            (StatusCode::PARTIAL_CONTENT, Body::from_stream(body), length)
+        }
+    };
+    res_headers.insert(header::CONTENT_LENGTH, length.into());
+    Ok((status, res_headers, body).into_response())
+}
+
+/// Stream a document as Bao's combined encoding, for the client to verify
+/// against the `Blake3` header as it arrives. 409 until the blob has an
+/// outboard (`store-cli backfill`)
+/// GET /api/docs/:id/bao
+async fn download_bao(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let meta = state.db.get_by_hex(&id).await?
+        .ok_or_else(|| StoreError::NotFound { id: id.clone() })?;
+    if meta.deleted.is_some() {
+        return Err(StoreError::Deleted { id: meta.id_hex }.into());
+    }
+    let mut res_headers = blob_headers(&meta)?;
+    if not_modified(&headers, &meta) {
+        return Ok((StatusCode::NOT_MODIFIED, res_headers).into_response());
+    }
+    let Some(reader) = state.db.stream_bao(&meta).await? else {
+        let error = format!("document {} has no Bao outboard yet; run `store-cli backfill`", meta.id_hex);
+        let body = ErrorResponse { success: false, error, defects: Vec::new() };
+        return Ok((StatusCode::CONFLICT, Json(body)).into_response());
+    };
+    res_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    res_headers.insert(header::CONTENT_LENGTH, store::bao::encoded_len(meta.size_bytes).into());
+    Ok((StatusCode::OK, res_headers, Body::from_stream(ReaderStream::new(reader))).into_response())
 }
 
-/// List all documents
-/// GET /api/docs
+/// One page of documents matching the filters
+/// GET /api/docs?filename=&mime=&cid=&owner=&property_id=&created_after=&created_before=&sort=&limit=&cursor=
 async fn list_docs(
-    State(state): State<AppState>,
+    Tenant(state): Tenant,
+    Query(query): Query<DocQuery>,
 ) -> Result<Json<ListResponse>, AppError> {
-    let docs = state.db.list()?;
-    let count = docs.len();
-    
+    let page = state.db.query(query).await?;
+    let count = page.documents.len();
+
     Ok(Json(ListResponse {
         success: true,
-        documents: docs,
+        documents: page.documents,
         count,
+        next_cursor: page.next_cursor,
     }))
 }
 
-/// Delete a document by ID
-/// DELETE /api/docs/:id
+/// Move a document to the trash
+/// DELETE /api/docs/:id?deleted_by=&reason=
 async fn delete_doc(
-    State(state): State<AppState>,
+    Tenant(state): Tenant,
     Path(id): Path<String>,
+    Query(params): Query<DeleteQuery>,
 ) -> Result<Json<DeleteResponse>, AppError> {
-    let deleted = state.db.delete_by_hex(&id)?;
+    let deleted = state.db.trash(&id, params.deleted_by, params.reason).await?;
     
     Ok(Json(DeleteResponse {
         success: deleted,
         message: if deleted {
-            "Document deleted successfully".to_string()
+            "Document moved to trash".to_string()
+        } else {
+            "Document not found or already in trash".to_string()
+        },
+    }))
+}
+
+/// Restore a document from the trash
+/// POST /api/docs/:id/restore
+async fn restore_doc(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<GetResponse>, AppError> {
+    let meta = state.db.restore(&id).await?;
+
+    Ok(Json(GetResponse {
+        success: meta.is_some(),
+        message: if meta.is_some() {
+            "Document restored".to_string()
         } else {
             "Document not found".to_string()
         },
+        metadata: meta,
     }))
 }
 
+/// Permanently remove a trashed document
+/// POST /api/docs/:id/purge
+async fn purge_doc(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+) -> Result<Json<DeleteResponse>, AppError> {
+    let purged = state.db.purge(&id).await?;
+
+    Ok(Json(DeleteResponse {
+        success: purged,
+        message: if purged {
+            "Document purged".to_string()
+        } else {
+            "Document not found".to_string()
+        },
+    }))
+}
+
+/// List documents in the trash
+/// GET /api/trash
+async fn list_trash(
+    Tenant(state): Tenant,
+) -> Result<Json<ListResponse>, AppError> {
+    let docs = state.db.list_trash().await?;
+    let count = docs.len();
+
+    Ok(Json(ListResponse {
+        success: true,
+        documents: docs,
+        count,
+        next_cursor: None,
+    }))
+}
+
+/// Purge every trashed document past retention now
+/// POST /api/trash/purge
+async fn purge_expired(
+    Tenant(state): Tenant,
+) -> Result<Json<store::PurgeReport>, AppError> {
+    Ok(Json(state.db.purge_expired().await?))
+}
+
 /// Export on-chain JSON for a document
 /// GET /api/docs/:id/export
 async fn export_onchain(
-    State(state): State<AppState>,
+    Tenant(state): Tenant,
     Path(id): Path<String>,
 ) -> Result<Json<serde_json::Value>, AppError> {
-    let meta = state.db.get_by_hex(&id)?
-        .ok_or_else(|| anyhow::anyhow!("Document not found"))?;
+    let meta = state.db.get_by_hex(&id).await?
+        .ok_or_else(|| StoreError::NotFound { id: id.clone() })?;
     
     Ok(Json(serde_json::json!({
         "sha256": hex::encode(meta.sha256),
         "cid": meta.cid,
+        "encrypted_cid": meta.encrypted_cid,
         "size_bytes": meta.size_bytes,
         "filename": meta.filename,
         "created_at": meta.created_at_unix_ms,
+        "supersedes_sha256": meta.supersedes_sha256.map(hex::encode),
     })))
 }
 
+/// Query parameters for full-text search
+#[cfg(feature = "search")]
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+/// Response for full-text search
+#[cfg(feature = "search")]
+#[derive(Serialize)]
+struct SearchResponse {
+    success: bool,
+    query: String,
+    hits: Vec<store::SearchHit>,
+    count: usize,
+}
+
+/// Pages matching a full-text query, with highlighted snippets
+/// GET /api/search?q=&limit=
+#[cfg(feature = "search")]
+async fn search_docs(
+    Tenant(state): Tenant,
+    Query(params): Query<SearchQuery>,
+) -> Result<Json<SearchResponse>, AppError> {
+    let limit = params.limit.unwrap_or(store::DEFAULT_SEARCH_LIMIT).clamp(1, store::MAX_QUERY_LIMIT);
+    let hits = state.db.search(&params.q, limit).await?;
+    let count = hits.len();
+
+    Ok(Json(SearchResponse {
+        success: true,
+        query: params.q,
+        hits,
+        count,
+    }))
+}
+
+/// Space used and the deduplication ratios
+/// GET /api/admin/stats
+async fn store_stats(
+    Tenant(state): Tenant,
+) -> Result<Json<store::StoreStats>, AppError> {
+    Ok(Json(state.db.stats().await?))
+}
+
+/// Report of the most recent scrub pass
+/// GET /api/admin/scrub
+async fn last_scrub(
+    Tenant(state): Tenant,
+) -> Result<Json<Option<store::ScrubReport>>, AppError> {
+    Ok(Json(state.db.last_scrub().await?))
+}
+
+/// Run a scrub pass now and return its report
+/// POST /api/admin/scrub
+async fn run_scrub(
+    Tenant(state): Tenant,
+) -> Result<Json<store::ScrubReport>, AppError> {
+    Ok(Json(state.db.scrub(state.scrub.clone()).await?))
+}
+
+// Resumable uploads: tus 1.0 core with the creation, checksum, termination
+// and expiration extensions. https://tus.io/protocols/resumable-upload
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,checksum,termination,expiration";
+/// Content type every tus PATCH body must declare.
+const TUS_CHUNK_TYPE: &str = "application/offset+octet-stream";
+
+/// Every tus response names the protocol version, errors included. The CORS
+/// layer answers every OPTIONS request itself, so the discovery headers
+/// (version, extensions, limits) are added to its answer here.
+async fn tus_headers(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let discovery = req.method() == Method::OPTIONS;
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+    headers.insert("tus-resumable", HeaderValue::from_static(TUS_VERSION));
+    if discovery {
+        let algorithms: Vec<_> = ChecksumAlgorithm::ALL.iter().map(ToString::to_string).collect();
+        headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+        headers.insert("tus-extension", HeaderValue::from_static(TUS_EXTENSIONS));
+        headers.insert("tus-max-size", state.config.max_upload_bytes.into());
+        if let Ok(algorithms) = HeaderValue::from_str(&algorithms.join(",")) {
+            headers.insert("tus-checksum-algorithm", algorithms);
+        }
+    }
+    res
+}
+
+/// 412 for requests speaking another tus version.
+fn tus_precondition(headers: &HeaderMap) -> Option<Response> {
+    match headers.get("tus-resumable") {
+        Some(v) if v == TUS_VERSION => None,
+        _ => Some((StatusCode::PRECONDITION_FAILED, [("tus-version", TUS_VERSION)]).into_response()),
+    }
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<u64, StoreError> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+        .ok_or_else(|| StoreError::InvalidQuery(format!("missing or invalid {name} header")))
+}
+
+/// `Upload-Metadata`: comma-separated `key base64(value)` pairs.
+fn parse_upload_metadata(headers: &HeaderMap) -> Result<BTreeMap<String, String>, StoreError> {
+    let invalid = || StoreError::InvalidQuery("invalid Upload-Metadata header".into());
+    let Some(value) = headers.get("upload-metadata") else { return Ok(BTreeMap::new()) };
+    let mut out = BTreeMap::new();
+    for pair in value.to_str().map_err(|_| invalid())?.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, encoded) = pair.split_once(' ').unwrap_or((pair, ""));
+        let decoded = BASE64.decode(encoded.trim()).map_err(|_| invalid())?;
+        out.insert(key.to_string(), String::from_utf8(decoded).map_err(|_| invalid())?);
+    }
+    Ok(out)
+}
+
+/// `Upload-Checksum`: algorithm name and base64 digest of the PATCH body.
+/// A `Content-Digest` sha-256 serves the same purpose when it is absent.
+fn parse_upload_checksum(headers: &HeaderMap) -> Result<Option<Checksum>, StoreError> {
+    let invalid = || StoreError::InvalidQuery("invalid Upload-Checksum header".into());
+    let Some(value) = headers.get("upload-checksum") else {
+        let digest = sha256_digest(headers, HeaderName::from_static("content-digest"))?;
+        return Ok(digest.map(|d| Checksum { algorithm: ChecksumAlgorithm::Sha256, digest: d.to_vec() }));
+    };
+    let (algorithm, digest) = value.to_str().map_err(|_| invalid())?.trim().split_once(' ').ok_or_else(invalid)?;
+    Ok(Some(Checksum {
+        algorithm: algorithm.parse()?,
+        digest: BASE64.decode(digest.trim()).map_err(|_| invalid())?,
+    }))
+}
+
+fn upload_headers(state: &TenantState, upload: &store::Upload) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("upload-offset", upload.offset.into());
+    if let Some(at) = upload.expires_at_unix_ms(state.db.config().upload_expiry_secs).filter(|_| !upload.is_complete()) {
+        let at = std::time::UNIX_EPOCH + std::time::Duration::from_millis(at);
+        if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(at)) {
+            headers.insert("upload-expires", value);
+        }
+    }
+    if let Some(doc) = upload.document.as_deref().and_then(|d| HeaderValue::from_str(d).ok()) {
+        headers.insert("document-id", doc);
+    }
+    headers
+}
+
+/// Start a resumable upload; `Upload-Metadata` may carry filename, filetype
+/// and the same fields as the /api/store query
+/// POST /api/uploads
+async fn create_upload(Tenant(state): Tenant, headers: HeaderMap) -> Result<Response, AppError> {
+    if let Some(res) = tus_precondition(&headers) {
+        return Ok(res);
+    }
+    let length = header_u64(&headers, "upload-length")?;
+    let upload = state.db.create_upload(length, parse_upload_metadata(&headers)?, upload_digest(&headers)?).await?;
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/api/uploads/{}", upload.id))],
+        upload_headers(&state, &upload),
+    )
+        .into_response())
+}
+
+/// Offset to resume from
+/// HEAD /api/uploads/:id
+async fn upload_offset(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(res) = tus_precondition(&headers) {
+        return Ok(res);
+    }
+    let upload = state.db.upload(&id).await?.ok_or(StoreError::UnknownUpload { id })?;
+    let mut res_headers = upload_headers(&state, &upload);
+    res_headers.insert("upload-length", upload.length.into());
+    res_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    let metadata: Vec<_> = upload.metadata.iter().map(|(k, v)| format!("{k} {}", BASE64.encode(v))).collect();
+    if let Ok(metadata) = HeaderValue::from_str(&metadata.join(",")) {
+        res_headers.insert("upload-metadata", metadata);
+    }
+    Ok((StatusCode::OK, res_headers).into_response())
+}
+
+/// Append a chunk at `Upload-Offset`; the final chunk stores the document
+/// like /api/store, IPFS pin and on-chain anchor included. The upload stays
+/// until the document is anchored, so a PATCH with an empty body at the full
+/// length retries a completion or anchoring that failed.
+/// PATCH /api/uploads/:id
+async fn append_upload(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError> {
+    if let Some(res) = tus_precondition(&headers) {
+        return Ok(res);
+    }
+    if headers.get(header::CONTENT_TYPE).is_none_or(|v| v != TUS_CHUNK_TYPE) {
+        return Ok((StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("Content-Type must be {TUS_CHUNK_TYPE}")).into_response());
+    }
+    let offset = header_u64(&headers, "upload-offset")?;
+    let checksum = parse_upload_checksum(&headers)?;
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    let mut upload = state.db.append_upload(&id, offset, reader, checksum).await?;
+    if upload.is_complete() {
+        let meta = state.db.store_upload_with_ipfs(&id, state.ipfs_url.as_deref()).await?;
+        if meta.anchor.is_none() {
+            use store::chain::publish_remark;
+            let block_hash = publish_remark(&state.node_url, &state.seed, &meta).await?;
+            state.db.set_anchor(&meta.id_hex, &block_hash).await?;
+        }
+        state.db.terminate_upload(&id).await?;
+        upload.document = Some(meta.id_hex);
+    }
+    Ok((StatusCode::NO_CONTENT, upload_headers(&state, &upload)).into_response())
+}
+
+/// Abandon an upload and discard its bytes
+/// DELETE /api/uploads/:id
+async fn terminate_upload(
+    Tenant(state): Tenant,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if let Some(res) = tus_precondition(&headers) {
+        return Ok(res);
+    }
+    if !state.db.terminate_upload(&id).await? {
+        return Err(StoreError::UnknownUpload { id }.into());
+    }
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
 /// API documentation endpoint
 async fn api_docs() -> impl IntoResponse {
     Json(serde_json::json!({
         "service": "Decentralized PDF Storage API",
         "version": env!("CARGO_PKG_VERSION"),
+        "tenants": "With TENANTS_FILE set, every /api endpoint works inside one tenant: the one an Authorization: Bearer token grants, or the one an X-Tenant header names if that tenant has no tokens. Ids only resolve in the tenant that created them.",
         "endpoints": {
             "health": {
                 "method": "GET",
@@ -237,29 +1020,99 @@ async fn api_docs() -> impl IntoResponse {
             "store": {
                 "method": "POST",
                 "path": "/api/store",
-                "query_params": "None - IPFS pinning and on-chain publishing",
+                "query_params": "uploader, tags (comma-separated), supersedes (record id of the version being replaced), owner, property_id",
                 "body": "multipart/form-data with 'file' field",
+                "headers": "Repr-Digest or Content-Digest (sha-256 of the file) to have a corrupted upload rejected",
                 "description": "Store a PDF document (automatically pins to IPFS and publishes to blockchain)"
             },
+            "uploads": {
+                "method": "OPTIONS, POST",
+                "path": "/api/uploads",
+                "headers": "Tus-Resumable: 1.0.0, Upload-Length, Upload-Metadata (filename, filetype, uploader, tags, supersedes, owner, property_id), Repr-Digest (sha-256 of the whole file)",
+                "description": "Start a resumable tus 1.0 upload (creation, checksum, termination and expiration extensions); the Location header names it"
+            },
+            "upload": {
+                "method": "HEAD, PATCH, DELETE",
+                "path": "/api/uploads/:id",
+                "headers": "Tus-Resumable: 1.0.0, Upload-Offset, Upload-Checksum (sha1 or sha256, base64) or Content-Digest, Content-Type: application/offset+octet-stream",
+                "description": "Resume, append to or abandon an upload; the last chunk stores it like /api/store and returns Document-Id"
+            },
             "get_metadata": {
                 "method": "GET",
                 "path": "/api/docs/:id",
-                "description": "Get document metadata by SHA-256 ID"
+                "description": "Get document metadata by record ID (a SHA-256 resolves to the oldest record)"
+            },
+            "versions": {
+                "method": "GET",
+                "path": "/api/docs/:id/versions",
+                "query_params": "at (unix ms) - also return the version current at that time",
+                "description": "Version history of the document, with its latest version"
             },
             "download": {
                 "method": "GET",
                 "path": "/api/docs/:id/download",
-                "description": "Download PDF file"
+                "query_params": "disposition (attachment or inline, default attachment)",
+                "headers": "Range: bytes=... (one or several ranges; 206, or 416 if none is satisfiable), If-Range, If-None-Match, If-Modified-Since (304); responses carry ETag (the sha256), immutable Cache-Control, Repr-Digest, Merkle-Root and Blake3; each range (or multipart part) carries a Merkle-Proof for the 64 KiB chunks it touches",
+                "description": "Stream the document's bytes"
+            },
+            "download_bao": {
+                "method": "GET",
+                "path": "/api/docs/:id/bao",
+                "headers": "If-None-Match, If-Modified-Since (304); the Blake3 header carries the digest to decode against",
+                "description": "Stream the document as a Bao combined encoding, verifiable chunk by chunk as it arrives (409 until backfilled)"
             },
             "list": {
                 "method": "GET",
                 "path": "/api/docs",
-                "description": "List all stored documents"
+                "query_params": "filename, mime, cid, owner, property_id, created_after, created_before (unix ms), sort (newest, oldest, filename_asc, filename_desc), limit (default 100, max 1000), cursor (next_cursor of the previous page)",
+                "description": "List stored documents one page at a time"
             },
             "delete": {
                 "method": "DELETE",
                 "path": "/api/docs/:id",
-                "description": "Delete a document by ID"
+                "query_params": "deleted_by, reason",
+                "description": "Move a document to the trash (restorable until retention ends)"
+            },
+            "restore": {
+                "method": "POST",
+                "path": "/api/docs/:id/restore",
+                "description": "Restore a document from the trash"
+            },
+            "purge": {
+                "method": "POST",
+                "path": "/api/docs/:id/purge",
+                "description": "Permanently remove a trashed document"
+            },
+            "trash": {
+                "method": "GET",
+                "path": "/api/trash",
+                "description": "List documents in the trash"
+            },
+            "search": {
+                "method": "GET",
+                "path": "/api/search",
+                "query_params": "q (words, \"phrases\", AND/OR), limit (default 20)",
+                "description": "Full-text search inside documents; hits carry the page number and a snippet with matches in <b> tags"
+            },
+            "stats": {
+                "method": "GET",
+                "path": "/api/admin/stats",
+                "description": "Records, blobs and chunks, bytes stored against bytes uploaded, and the dedup ratio"
+            },
+            "scrub_report": {
+                "method": "GET",
+                "path": "/api/admin/scrub",
+                "description": "Report of the most recent integrity scrub"
+            },
+            "scrub": {
+                "method": "POST",
+                "path": "/api/admin/scrub",
+                "description": "Re-hash every blob now, quarantining and refetching corrupted ones"
+            },
+            "purge_expired": {
+                "method": "POST",
+                "path": "/api/trash/purge",
+                "description": "Purge trashed documents past retention now"
             },
             "export": {
                 "method": "GET",
@@ -273,13 +1126,65 @@ async fn api_docs() -> impl IntoResponse {
 // Error handling
 struct AppError(anyhow::Error);
 
+impl AppError {
+    /// Map well-known failures to a client error status; everything else is a 500.
+    fn status(&self) -> StatusCode {
+        for cause in self.0.chain() {
+            if let Some(e) = cause.downcast_ref::<StoreError>() {
+                return match e {
+                    StoreError::UnsupportedType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    StoreError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                    StoreError::NotFound { .. } | StoreError::UnknownUpload { .. } => StatusCode::NOT_FOUND,
+                    StoreError::AlreadySuperseded { .. }
+                    | StoreError::NotDeleted { .. }
+                    | StoreError::UploadOffsetMismatch { .. }
+                    | StoreError::UploadIncomplete { .. } => StatusCode::CONFLICT,
+                    StoreError::UploadLocked { .. } => StatusCode::LOCKED,
+                    // tus checksum extension: 460 Checksum Mismatch
+                    StoreError::ChecksumMismatch { .. } => StatusCode::from_u16(460).expect("valid status code"),
+                    StoreError::Deleted { .. } | StoreError::RetentionExpired { .. } => StatusCode::GONE,
+                    StoreError::InvalidQuery(_) | StoreError::DigestMismatch { .. } | StoreError::InvalidTenant { .. } => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    StoreError::InvalidPdf { .. } | StoreError::ActiveContent { .. } | StoreError::InvalidContent { .. } => {
+                        StatusCode::UNPROCESSABLE_ENTITY
+                    }
+                };
+            }
+            if let Some(e) = cause.downcast_ref::<MultipartError>() {
+                return e.status();
+            }
+            // Multipart errors surfaced through the upload stream reader
+            if let Some(e) = cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.get_ref())
+                .and_then(|e| e.downcast_ref::<MultipartError>())
+            {
+                return e.status();
+            }
+        }
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn defects(&self) -> Vec<store::PdfDefect> {
+        self.0
+            .chain()
+            .find_map(|cause| match cause.downcast_ref::<StoreError>() {
+                Some(StoreError::InvalidPdf { defects }) => Some(defects.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         (
-            StatusCode::INTERNAL_SERVER_ERROR,
+            self.status(),
             Json(ErrorResponse {
                 success: false,
                 error: self.0.to_string(),
+                defects: self.defects(),
             }),
         ).into_response()
     }
@@ -296,15 +1201,39 @@ where
 
 /// Build the application router
 fn app(state: AppState) -> Router {
-    Router::new()
+    // Leave room for multipart framing around the file itself; the store
+    // enforces the exact per-file limit while streaming.
+    let body_limit = usize::try_from(state.config.max_upload_bytes)
+        .unwrap_or(usize::MAX)
+        .saturating_add(64 * 1024);
+    let router = Router::new()
         .route("/health", get(health_check))
         .route("/", get(api_docs))
-        .route("/api/store", post(store_pdf))
+        .route("/api/store", post(store_pdf).layer(DefaultBodyLimit::max(body_limit)))
         .route("/api/docs", get(list_docs))
         .route("/api/docs/:id", get(get_metadata).delete(delete_doc))
+        .route("/api/docs/:id/versions", get(get_versions))
         .route("/api/docs/:id/download", get(download_pdf))
+        .route("/api/docs/:id/bao", get(download_bao))
         .route("/api/docs/:id/export", get(export_onchain))
+        .route("/api/docs/:id/restore", post(restore_doc))
+        .route("/api/docs/:id/purge", post(purge_doc))
+        .route("/api/trash", get(list_trash))
+        .route("/api/trash/purge", post(purge_expired))
+        .route("/api/admin/stats", get(store_stats))
+        .route("/api/admin/scrub", get(last_scrub).post(run_scrub));
+    let uploads = Router::new()
+        .route("/api/uploads", post(create_upload))
+        // chunks are capped by the upload's own length, not a body limit
+        .route("/api/uploads/:id", head(upload_offset).patch(append_upload).delete(terminate_upload))
+        .layer(CorsLayer::permissive())
+        .layer(middleware::from_fn_with_state(state.clone(), tus_headers));
+    #[cfg(feature = "search")]
+    let router = router.route("/api/search", get(search_docs));
+    // CORS is layered per router so OPTIONS on /api/uploads passes tus_headers
+    router
         .layer(CorsLayer::permissive())
+        .merge(uploads)
         .with_state(state)
 }
 
@@ -323,16 +1252,94 @@ async fn main() -> Result<()> {
     println!("Database: {db_path}");
     
     // Initialize database
-    let db = DocStore::open(db_path)
-        .context("Failed to open database")?;
-    println!("Database initialized");
-    
-    let state = AppState {
-        db: Arc::new(db),
-        ipfs_url: std::env::var("IPFS_URL").ok(),
-        node_url: std::env::var("NODE_URL").unwrap_or_else(|_| "ws://localhost:9944".to_string()),
-        seed: std::env::var("SEED").unwrap_or_else(|_| "//Alice".to_string()),
+    let config = StoreConfig::from_env().context("Invalid store configuration")?;
+    println!("Blob backend: {:?}", config.backend);
+    println!("Max upload size: {} bytes", config.max_upload_bytes);
+    println!("Trash retention: {} s", config.retention_secs);
+
+    let ipfs_url = std::env::var("IPFS_URL").ok();
+    let node_url = std::env::var("NODE_URL").unwrap_or_else(|_| "ws://localhost:9944".to_string());
+    let seed = std::env::var("SEED").unwrap_or_else(|_| "//Alice".to_string());
+    let scrub = ScrubOptions {
+        max_bytes_per_sec: std::env::var("SCRUB_MAX_BYTES_PER_SEC")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .or(ScrubOptions::default().max_bytes_per_sec),
+        ..Default::default()
+    };
+    let tenant = |db: DocStore, ipfs_url: Option<String>, node_url: String, seed: String, open: bool| TenantState {
+        db,
+        scrub: ScrubOptions { ipfs_url: ipfs_url.clone(), ..scrub.clone() },
+        ipfs_url,
+        node_url,
+        seed,
+        open,
+    };
+
+    let mut state = AppState {
+        single: None,
+        tenants: Default::default(),
+        tokens: Default::default(),
+        config: config.clone(),
     };
+    match std::env::var("TENANTS_FILE") {
+        Ok(path) => {
+            let file = std::fs::read(&path).with_context(|| format!("reading TENANTS_FILE {path}"))?;
+            let settings: BTreeMap<String, TenantSettings> =
+                serde_json::from_slice(&file).with_context(|| format!("parsing TENANTS_FILE {path}"))?;
+            let opened = Tenants::open(db_path, config).context("Failed to open database")?;
+            let (mut tenants, mut tokens) = (BTreeMap::new(), HashMap::new());
+            for (name, t) in settings {
+                let db = opened.store(&name).context("Failed to open tenant")?;
+                for token in &t.tokens_sha256 {
+                    let digest = hex::decode(token)
+                        .ok()
+                        .and_then(|d| <[u8; 32]>::try_from(d).ok())
+                        .with_context(|| format!("tenant {name}: tokens_sha256 entries must be 64 hex characters"))?;
+                    if let Some(other) = tokens.insert(digest, name.clone()) {
+                        anyhow::bail!("tenants {other} and {name} share a token");
+                    }
+                }
+                let state = tenant(
+                    db,
+                    t.ipfs_url.or_else(|| ipfs_url.clone()),
+                    t.node_url.unwrap_or_else(|| node_url.clone()),
+                    t.seed.unwrap_or_else(|| seed.clone()),
+                    t.tokens_sha256.is_empty(),
+                );
+                tenants.insert(name, state);
+            }
+            if tenants.is_empty() {
+                anyhow::bail!("TENANTS_FILE {path} names no tenants");
+            }
+            println!("Tenants: {}", tenants.keys().cloned().collect::<Vec<_>>().join(", "));
+            state.tenants = Arc::new(tenants);
+            state.tokens = Arc::new(tokens);
+        }
+        Err(_) => {
+            let db = DocStore::open_with_config(db_path, config)
+                .context("Failed to open database")?;
+            state.single = Some(tenant(db, ipfs_url, node_url, seed, true));
+        }
+    }
+    println!("Database initialized");
+
+    let purge_every: u64 = std::env::var("PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3600);
+    let scrub_every: u64 = std::env::var("SCRUB_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(24 * 60 * 60);
+    for t in state.single.iter().chain(state.tenants.values()) {
+        if purge_every > 0 {
+            t.db.spawn_purge_job(std::time::Duration::from_secs(purge_every));
+        }
+        if scrub_every > 0 {
+            t.db.spawn_scrub_job(std::time::Duration::from_secs(scrub_every), t.scrub.clone());
+        }
+    }
     
     // Build application
     let app = app(state);
@@ -0,0 +1,106 @@
+//! Tenant namespaces: isolated stores sharing one database root.
+//!
+//! [`Tenants`] opens the sled database and the blob backend once and hands
+//! out a [`DocStore`] per tenant. A tenant's trees are named
+//! `tenants/<name>/<tree>` and its blobs live under the `tenants/<name>/`
+//! key prefix, so records, indexes, uploads and blobs of one tenant are
+//! invisible to every other: ids only resolve in the tenant that created
+//! them. Staging files, uploads, the search index and quarantine live under
+//! `<root>/tenants/<name>/`. The unscoped store at the same root (what
+//! [`DocStore::open`] opens) keeps the default tree names and leaves the
+//! tenant prefixes alone.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use sled::Db;
+
+use crate::{BlobStore, DocStore, PrefixedBlobStore, StoreConfig, StoreError};
+
+/// Directory and key prefix every tenant's namespace sits under.
+const TENANTS: &str = "tenants";
+
+/// Whether `name` can name a tenant: 1 to 64 lowercase ASCII letters,
+/// digits, `-` or `_`, starting with a letter or digit.
+pub fn check_name(name: &str) -> Result<(), StoreError> {
+    let valid = (1..=64).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'-' | b'_'))
+        && !name.starts_with(['-', '_']);
+    match valid {
+        true => Ok(()),
+        false => Err(StoreError::InvalidTenant { name: name.into() }),
+    }
+}
+
+/// sled tree `name` of `tenant`.
+pub(crate) fn tree_name(tenant: &str, name: &str) -> String {
+    format!("{TENANTS}/{tenant}/{name}")
+}
+
+/// Whether a key of the shared backend belongs to some tenant.
+pub(crate) fn is_tenant_key(key: &str) -> bool {
+    key.strip_prefix(TENANTS).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// The tenants of one database root, opened on first use.
+///
+/// Cloning is cheap; clones share the open stores.
+#[derive(Clone)]
+pub struct Tenants {
+    root: PathBuf,
+    kv: Db,
+    blobs: Arc<dyn BlobStore>,
+    config: StoreConfig,
+    open: Arc<Mutex<HashMap<String, DocStore>>>,
+}
+
+impl Tenants {
+    /// Open the database at `root`; every tenant gets these settings.
+    pub fn open<P: AsRef<Path>>(root: P, config: StoreConfig) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root).context("creating database dir")?;
+        let blobs = config.backend.open(&root)?;
+        let kv = sled::open(root.join("kv"))?;
+        Ok(Self { root, kv, blobs, config, open: Default::default() })
+    }
+
+    /// The store of tenant `name`, created if it has none yet.
+    pub fn store(&self, name: &str) -> Result<DocStore> {
+        check_name(name)?;
+        // held while opening, so a tenant's search index is only opened once
+        let mut open = self.open.lock().expect("tenant map poisoned");
+        if let Some(store) = open.get(name) {
+            return Ok(store.clone());
+        }
+        let dir = self.root.join(TENANTS).join(name);
+        fs::create_dir_all(&dir).with_context(|| format!("creating {dir:?}"))?;
+        let blobs = Arc::new(PrefixedBlobStore::new(self.blobs.clone(), &format!("{TENANTS}/{name}"))?);
+        let store = DocStore::open_inner(&dir, self.kv.clone(), Some(name), blobs, self.config.clone())
+            .with_context(|| format!("opening tenant {name}"))?;
+        open.insert(name.to_string(), store.clone());
+        Ok(store)
+    }
+
+    /// Settings every tenant is opened with.
+    pub fn config(&self) -> &StoreConfig {
+        &self.config
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl std::fmt::Debug for Tenants {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tenants")
+            .field("root", &self.root)
+            .field("blobs", &self.blobs)
+            .finish_non_exhaustive()
+    }
+}
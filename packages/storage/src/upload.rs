@@ -0,0 +1,491 @@
+//! Resumable uploads, as served by the tus endpoint of `store-server`.
+//!
+//! An upload is created with its final length, filled by appending chunks at
+//! its current offset and, once complete, stored through the same pipeline
+//! as any other upload. State lives in the `uploads` tree and the bytes
+//! received so far in `<root>/uploads/<id>`, so an interrupted upload
+//! survives a restart and resumes from its last saved offset. Both go once
+//! the upload is stored, rejected, terminated or idle for longer than
+//! [`crate::StoreConfig::upload_expiry_secs`].
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    future::Future,
+    io::{ErrorKind, SeekFrom},
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{now_ms, DocInput, DocMeta, DocStore, Hash32, StoreError, StoreInner};
+
+/// A resumable upload and how far it has got.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Upload {
+    pub id: String,
+    /// Total bytes announced when the upload was created.
+    pub length: u64,
+    /// Bytes received and saved so far.
+    pub offset: u64,
+    /// Client-supplied pairs; see [`Upload::input`].
+    pub metadata: BTreeMap<String, String>,
+    pub created_at_unix_ms: u64,
+    /// When bytes were last appended.
+    #[serde(default)]
+    pub updated_at_unix_ms: u64,
+    /// sha256 the client announced for the whole upload, checked on completion.
+    #[serde(default)]
+    pub sha256: Option<Hash32>,
+    /// Record id once the completed upload has been stored.
+    #[serde(default)]
+    pub document: Option<String>,
+}
+
+impl Upload {
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+
+    /// When the upload expires if nothing more is appended, for uploads
+    /// that expire `expiry_secs` after their last activity (`0`: never).
+    pub fn expires_at_unix_ms(&self, expiry_secs: u64) -> Option<u64> {
+        let active = self.created_at_unix_ms.max(self.updated_at_unix_ms);
+        (expiry_secs > 0).then(|| active.saturating_add(expiry_secs.saturating_mul(1000)))
+    }
+
+    /// Record details from the metadata keys `filename`, `filetype`,
+    /// `uploader`, `tags` (comma-separated), `owner`, `property_id` and
+    /// `supersedes`.
+    pub fn input(&self) -> DocInput {
+        let get = |key: &str| self.metadata.get(key).filter(|v| !v.is_empty()).cloned();
+        DocInput {
+            filename: get("filename").unwrap_or_else(|| "upload".into()),
+            mime: get("filetype").unwrap_or_else(|| "application/octet-stream".into()),
+            uploader: get("uploader"),
+            tags: get("tags")
+                .map(|t| t.split(',').map(str::trim).filter(|t| !t.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+            owner: get("owner"),
+            property_id: get("property_id"),
+            supersedes: get("supersedes"),
+            expected_sha256: self.sha256,
+        }
+    }
+}
+
+/// Digest algorithms accepted for per-chunk checksums.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha1,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub const ALL: [Self; 2] = [Self::Sha1, Self::Sha256];
+
+    fn hasher(self) -> ChunkHasher {
+        match self {
+            Self::Sha1 => ChunkHasher::Sha1(Sha1::new()),
+            Self::Sha256 => ChunkHasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+        })
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, StoreError> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha1" => Ok(Self::Sha1),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(StoreError::InvalidQuery(format!("unsupported checksum algorithm {s:?}"))),
+        }
+    }
+}
+
+/// Expected digest of one appended chunk.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: Vec<u8>,
+}
+
+enum ChunkHasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl ChunkHasher {
+    fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::Sha1(h) => h.update(bytes),
+            Self::Sha256(h) => h.update(bytes),
+        }
+    }
+
+    fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Sha1(h) => h.finalize().to_vec(),
+            Self::Sha256(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// Marks an upload busy until dropped, so appends never interleave.
+struct UploadLock<'a> {
+    busy: &'a Mutex<HashSet<String>>,
+    id: String,
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+    }
+}
+
+/// Saves how far an append got if it stops early, e.g. when the client
+/// disconnects and the request future is dropped. Chunks under a checksum
+/// are all-or-nothing, so nothing is saved for them.
+struct Progress {
+    uploads: sled::Tree,
+    upload: Upload,
+    written: u64,
+    keep_partial: bool,
+    done: bool,
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.done || !self.keep_partial || self.written == 0 {
+            return;
+        }
+        let mut upload = self.upload.clone();
+        upload.offset += self.written;
+        upload.updated_at_unix_ms = now_ms();
+        if let Ok(val) = serde_json::to_vec(&upload) {
+            let _ = self.uploads.insert(upload.id.as_bytes(), val);
+            let _ = self.uploads.flush();
+        }
+    }
+}
+
+fn unknown(id: &str) -> anyhow::Error {
+    StoreError::UnknownUpload { id: id.to_string() }.into()
+}
+
+impl StoreInner {
+    fn upload_path(&self, id: &str) -> PathBuf {
+        self.root.join("uploads").join(id)
+    }
+
+    /// Ids are ours, so anything but 32 hex digits is unknown; this also
+    /// keeps them safe to use as file names.
+    fn check_upload_id(id: &str) -> Result<()> {
+        if id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Ok(())
+        } else {
+            Err(unknown(id))
+        }
+    }
+
+    fn lock_upload(&self, id: &str) -> Result<UploadLock<'_>> {
+        Self::check_upload_id(id)?;
+        if !self.upload_locks.lock().unwrap_or_else(|e| e.into_inner()).insert(id.to_string()) {
+            return Err(StoreError::UploadLocked { id: id.to_string() }.into());
+        }
+        Ok(UploadLock { busy: &self.upload_locks, id: id.to_string() })
+    }
+
+    fn upload_expired(&self, upload: &Upload, now: u64) -> bool {
+        upload.expires_at_unix_ms(self.config.upload_expiry_secs).is_some_and(|at| at <= now)
+    }
+
+    /// Saved state of an upload. The offset never exceeds the bytes on disk,
+    /// which can lag behind after a crash. Expired uploads are unknown even
+    /// before they are swept.
+    fn load_upload(&self, id: &str) -> Result<Option<Upload>> {
+        Self::check_upload_id(id)?;
+        let Some(val) = self.uploads.get(id)? else { return Ok(None) };
+        let mut upload: Upload = serde_json::from_slice(&val)?;
+        if self.upload_expired(&upload, now_ms()) {
+            return Ok(None);
+        }
+        if upload.document.is_none() {
+            let on_disk = match std::fs::metadata(self.upload_path(id)) {
+                Ok(m) => m.len(),
+                Err(e) if e.kind() == ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+            upload.offset = upload.offset.min(on_disk);
+        }
+        Ok(Some(upload))
+    }
+
+    fn save_upload(&self, upload: &Upload) -> Result<()> {
+        self.uploads.insert(upload.id.as_bytes(), serde_json::to_vec(upload)?)?;
+        self.uploads.flush()?;
+        Ok(())
+    }
+
+    /// Record that `upload` was stored as `document` and drop its bytes.
+    fn settle_upload(&self, mut upload: Upload, document: &str) -> Result<()> {
+        upload.document = Some(document.to_string());
+        upload.updated_at_unix_ms = now_ms();
+        self.save_upload(&upload)?;
+        match std::fs::remove_file(self.upload_path(&upload.id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_upload(&self, id: &str) -> Result<bool> {
+        let existed = self.uploads.remove(id)?.is_some();
+        self.uploads.flush()?;
+        match std::fs::remove_file(self.upload_path(id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(existed),
+        }
+    }
+
+    fn expire_uploads(&self) -> Result<usize> {
+        let now = now_ms();
+        let mut expired = 0;
+        for item in self.uploads.iter() {
+            let upload: Upload = serde_json::from_slice(&item?.1)?;
+            if !self.upload_expired(&upload, now) {
+                continue;
+            }
+            // an append in progress keeps it alive
+            let Ok(_lock) = self.lock_upload(&upload.id) else { continue };
+            let Some(val) = self.uploads.get(&upload.id)? else { continue };
+            if self.upload_expired(&serde_json::from_slice(&val)?, now) {
+                expired += usize::from(self.remove_upload(&upload.id)?);
+            }
+        }
+        Ok(expired)
+    }
+}
+
+/// Whether a failed completion was the upload's own fault, which no retry
+/// changes.
+fn rejects_upload(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref(),
+        Some(
+            StoreError::UnsupportedType { .. }
+                | StoreError::InvalidContent { .. }
+                | StoreError::TooLarge { .. }
+                | StoreError::InvalidPdf { .. }
+                | StoreError::ActiveContent { .. }
+                | StoreError::DigestMismatch { .. }
+        )
+    )
+}
+
+impl DocStore {
+    /// Start a resumable upload of `length` bytes, optionally with the
+    /// sha256 the finished upload must have.
+    pub async fn create_upload(
+        &self,
+        length: u64,
+        metadata: BTreeMap<String, String>,
+        sha256: Option<Hash32>,
+    ) -> Result<Upload> {
+        let limit = self.inner.config.max_upload_bytes;
+        if length > limit {
+            return Err(StoreError::TooLarge { limit }.into());
+        }
+        let mut id = [0u8; 16];
+        getrandom::getrandom(&mut id).map_err(|e| anyhow::anyhow!("generating upload id: {e}"))?;
+        let now = now_ms();
+        let upload = Upload {
+            id: hex::encode(id),
+            length,
+            offset: 0,
+            metadata,
+            created_at_unix_ms: now,
+            updated_at_unix_ms: now,
+            sha256,
+            document: None,
+        };
+        tokio::fs::File::create(self.inner.upload_path(&upload.id))
+            .await
+            .context("creating upload file")?;
+        let saved = upload.clone();
+        self.blocking(move |s| s.save_upload(&saved)).await?;
+        Ok(upload)
+    }
+
+    /// Current state of an upload.
+    pub async fn upload(&self, id: &str) -> Result<Option<Upload>> {
+        let id = id.to_string();
+        self.blocking(move |s| s.load_upload(&id)).await
+    }
+
+    /// Append `reader` to an upload at `offset`, which must be the upload's
+    /// current offset. Without a checksum, bytes received before the reader
+    /// fails are kept; with one, the chunk is kept only if it matches.
+    pub async fn append_upload<R: AsyncRead + Unpin>(
+        &self,
+        id: &str,
+        offset: u64,
+        mut reader: R,
+        checksum: Option<Checksum>,
+    ) -> Result<Upload> {
+        let _lock = self.inner.lock_upload(id)?;
+        let mut upload = self.upload(id).await?.ok_or_else(|| unknown(id))?;
+        if offset != upload.offset {
+            return Err(StoreError::UploadOffsetMismatch { expected: upload.offset }.into());
+        }
+        if upload.document.is_some() {
+            return Ok(upload);
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(self.inner.upload_path(id))
+            .await
+            .context("opening upload file")?;
+        // drop whatever an earlier, unconfirmed chunk left behind
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let mut hasher = checksum.as_ref().map(|c| c.algorithm.hasher());
+        let mut progress = Progress {
+            uploads: self.inner.uploads.clone(),
+            upload: upload.clone(),
+            written: 0,
+            keep_partial: checksum.is_none(),
+            done: false,
+        };
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            if offset + progress.written + n as u64 > upload.length {
+                return Err(StoreError::TooLarge { limit: upload.length }.into());
+            }
+            file.write_all(&buf[..n]).await?;
+            if let Some(h) = &mut hasher {
+                h.update(&buf[..n]);
+            }
+            progress.written += n as u64;
+        }
+        file.flush().await?;
+        if let (Some(expected), Some(h)) = (&checksum, hasher) {
+            if h.finalize() != expected.digest {
+                file.set_len(offset).await?;
+                return Err(StoreError::ChecksumMismatch { algorithm: expected.algorithm.to_string() }.into());
+            }
+        }
+        file.sync_data().await?;
+        upload.offset += progress.written;
+        upload.updated_at_unix_ms = now_ms();
+        let saved = upload.clone();
+        self.blocking(move |s| s.save_upload(&saved)).await?;
+        progress.done = true;
+        Ok(upload)
+    }
+
+    /// Store a complete upload as a new document record and discard the
+    /// upload. Uploads whose content the store rejects are discarded too;
+    /// other failures, such as IPFS errors or a version superseded in the
+    /// meantime, leave them in place to retry.
+    pub async fn complete_upload(&self, id: &str) -> Result<DocMeta> {
+        self.finish_upload(id, false, |file, input| self.store(file, input)).await
+    }
+
+    /// Like [`Self::complete_upload`], pinning the bytes to IPFS as
+    /// [`Self::store_with_ipfs`] does.
+    #[cfg(feature = "ipfs")]
+    pub async fn complete_upload_with_ipfs(&self, id: &str, ipfs_url: Option<&str>) -> Result<DocMeta> {
+        self.finish_upload(id, false, |file, input| self.store_with_ipfs(file, input, ipfs_url)).await
+    }
+
+    /// Like [`Self::complete_upload`], but a stored upload stays, without
+    /// its bytes and with [`Upload::document`] set, until
+    /// [`Self::terminate_upload`] or expiry discards it. Completing it again
+    /// returns the same record, so a caller with more to do once the
+    /// document is stored (the server anchors it) can be retried.
+    pub async fn store_upload(&self, id: &str) -> Result<DocMeta> {
+        self.finish_upload(id, true, |file, input| self.store(file, input)).await
+    }
+
+    /// Like [`Self::store_upload`], pinning the bytes to IPFS as
+    /// [`Self::store_with_ipfs`] does.
+    #[cfg(feature = "ipfs")]
+    pub async fn store_upload_with_ipfs(&self, id: &str, ipfs_url: Option<&str>) -> Result<DocMeta> {
+        self.finish_upload(id, true, |file, input| self.store_with_ipfs(file, input, ipfs_url)).await
+    }
+
+    async fn finish_upload<F, Fut>(&self, id: &str, keep: bool, store: F) -> Result<DocMeta>
+    where
+        F: FnOnce(tokio::fs::File, DocInput) -> Fut,
+        Fut: Future<Output = Result<DocMeta>>,
+    {
+        let _lock = self.inner.lock_upload(id)?;
+        let upload = self.upload(id).await?.ok_or_else(|| unknown(id))?;
+        if let Some(doc) = &upload.document {
+            let meta = self
+                .get_by_hex(doc)
+                .await?
+                .with_context(|| format!("document {doc} stored from upload {id} is gone"))?;
+            if !keep {
+                let id = id.to_string();
+                self.blocking(move |s| s.remove_upload(&id)).await?;
+            }
+            return Ok(meta);
+        }
+        if !upload.is_complete() {
+            return Err(StoreError::UploadIncomplete { offset: upload.offset, length: upload.length }.into());
+        }
+        let file = tokio::fs::File::open(self.inner.upload_path(id))
+            .await
+            .context("opening upload file")?;
+        let result = store(file, upload.input()).await;
+        let discard = match &result {
+            Ok(meta) if keep => {
+                let doc = meta.id_hex.clone();
+                self.blocking(move |s| s.settle_upload(upload, &doc)).await?;
+                false
+            }
+            Ok(_) => true,
+            Err(e) => rejects_upload(e),
+        };
+        if discard {
+            let id = id.to_string();
+            self.blocking(move |s| s.remove_upload(&id)).await?;
+        }
+        result
+    }
+
+    /// Discard an upload and the bytes received for it. Returns false if it
+    /// is unknown.
+    pub async fn terminate_upload(&self, id: &str) -> Result<bool> {
+        let _lock = self.inner.lock_upload(id)?;
+        let id = id.to_string();
+        self.blocking(move |s| s.remove_upload(&id)).await
+    }
+
+    /// Discard uploads idle for longer than
+    /// [`crate::StoreConfig::upload_expiry_secs`]. Returns how many went.
+    pub async fn expire_uploads(&self) -> Result<usize> {
+        self.blocking(|s| s.expire_uploads()).await
+    }
+}
@@ -5,38 +5,1413 @@ use tempfile::TempDir;
 
 use store::DocStore;
 
-fn write_fake_pdf(dir: &TempDir, name: &str, body: &[u8]) -> PathBuf {
+fn write_pdf(dir: &TempDir, name: &str, text: &str) -> PathBuf {
     let path = dir.path().join(name);
     let mut f = fs::File::create(&path).unwrap();
-    // minimal PDF header + body
-    f.write_all(b"%PDF-1.4\n%").unwrap();
-    f.write_all(body).unwrap();
+    f.write_all(&text_pdf(&[text])).unwrap();
     f.flush().unwrap();
     path
 }
 
-#[test]
-fn store_get_list_delete_flow() {
+#[tokio::test]
+async fn store_get_list_delete_flow() {
     let tmp = TempDir::new().unwrap();
-    let pdf = write_fake_pdf(&tmp, "test.pdf", b"Hello BrickChain");
+    let pdf = write_pdf(&tmp, "test.pdf", "Hello BrickChain");
 
     let db_root = tmp.path().join("db");
     let db = DocStore::open(&db_root).expect("open db");
 
-    let meta = db.store_pdf(&pdf, Some("bafy...cid".to_string())).expect("store");
+    let meta = db.store_pdf(&pdf, Some("bafy...cid".to_string())).await.expect("store");
     assert_eq!(meta.filename, "test.pdf");
     assert_eq!(meta.mime, "application/pdf");
     assert_eq!(meta.id_hex.len(), 64);
 
-    let got = db.get_by_hex(&meta.id_hex).expect("get").expect("some");
+    let got = db.get_by_hex(&meta.id_hex).await.expect("get").expect("some");
     assert_eq!(got.sha256, meta.sha256);
 
-    let list = db.list().expect("list");
+    let list = db.list().await.expect("list");
     assert_eq!(list.len(), 1);
 
-    let deleted = db.delete_by_hex(&meta.id_hex).expect("delete");
+    let deleted = db.delete_by_hex(&meta.id_hex).await.expect("delete");
     assert!(deleted);
 
-    let list2 = db.list().expect("list2");
+    let list2 = db.list().await.expect("list2");
     assert!(list2.is_empty());
 }
+
+#[tokio::test]
+async fn memory_backend_round_trip() {
+    use std::sync::Arc;
+    use store::{BlobStore, MemBlobStore};
+
+    let tmp = TempDir::new().unwrap();
+    let pdf = write_pdf(&tmp, "mem.pdf", "in memory");
+    let blobs = Arc::new(MemBlobStore::new());
+    let db = DocStore::open_with_blobs(tmp.path().join("db"), blobs.clone()).expect("open db");
+
+    let meta = db.store_pdf(&pdf, None).await.expect("store");
+    // the blob and its Bao outboard
+    assert_eq!(blobs.len(), 2);
+    assert!(!tmp.path().join("db").join("blobs").exists());
+    assert_eq!(db.read_blob(&meta).await.unwrap(), fs::read(&pdf).unwrap());
+
+    assert!(db.delete_by_hex(&meta.id_hex).await.unwrap());
+    assert!(db.purge(&meta.id_hex).await.unwrap());
+    assert!(blobs.is_empty());
+    assert!(blobs.get(&store::blob_key(&meta.sha256)).unwrap().is_none());
+}
+
+/// Run against a local MinIO, e.g.
+/// `S3_ENDPOINT=http://127.0.0.1:9000 S3_BUCKET=store-test S3_ACCESS_KEY_ID=minioadmin
+/// S3_SECRET_ACCESS_KEY=minioadmin cargo test -- --ignored s3_backend`
+#[tokio::test]
+#[ignore = "requires a running MinIO (see S3_* env vars)"]
+async fn s3_backend_round_trip() {
+    use store::{BlobBackend, S3Config};
+
+    let tmp = TempDir::new().unwrap();
+    let pdf = write_pdf(&tmp, "s3.pdf", "object storage");
+    let backend = BlobBackend::S3(S3Config::from_env().expect("S3 config"));
+    let db = DocStore::open_with_backend(tmp.path().join("db"), &backend).expect("open db");
+
+    let meta = db.store_pdf(&pdf, None).await.expect("store");
+    let key = store::blob_key(&meta.sha256);
+    assert!(db.blobs().exists(&key).unwrap());
+    assert_eq!(db.read_blob(&meta).await.unwrap(), fs::read(&pdf).unwrap());
+
+    assert!(db.delete_by_hex(&meta.id_hex).await.unwrap());
+    assert!(db.purge(&meta.id_hex).await.unwrap());
+    assert!(!db.blobs().exists(&key).unwrap());
+}
+
+#[test]
+fn s3_settings_print_without_credentials() {
+    use store::{BlobBackend, S3Config};
+
+    let config = S3Config {
+        endpoint: "http://127.0.0.1:9000".into(),
+        region: "us-east-1".into(),
+        bucket: "deeds".into(),
+        access_key: Some("AKIAEXAMPLE".into()),
+        secret_key: Some("wJalrXUtnFEMI".into()),
+        prefix: String::new(),
+        path_style: true,
+    };
+    let printed = format!("{:?}", BlobBackend::S3(config));
+    assert!(printed.contains("deeds") && printed.contains("127.0.0.1:9000"));
+    assert!(!printed.contains("AKIAEXAMPLE") && !printed.contains("wJalrXUtnFEMI"));
+}
+
+#[tokio::test]
+async fn store_from_reader_and_stream() {
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).expect("open db");
+    let body = text_pdf(&["from a reader"]);
+
+    let meta = db
+        .store_reader(std::io::Cursor::new(body.clone()), "deed.pdf", "application/pdf")
+        .await
+        .expect("store_reader");
+    assert_eq!(meta.filename, "deed.pdf");
+    assert_eq!(meta.size_bytes, body.len() as u64);
+
+    let streamed = db
+        .store_stream(&text_pdf(&["from a stream"])[..], "valuation", "application/x-pdf")
+        .await
+        .expect("store_stream");
+    assert_eq!(streamed.filename, "valuation");
+    assert_eq!(streamed.mime, "application/x-pdf");
+    assert_eq!(db.list().await.unwrap().len(), 2);
+
+    let err = db
+        .store_reader(&b"plain text"[..], "x.pdf", "application/pdf")
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("unrecognised content type"));
+    assert_eq!(fs::read_dir(tmp.path().join("db").join("tmp")).unwrap().count(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_uploads() {
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).expect("open db");
+
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                let body = text_pdf(&[&format!("upload {i}")]);
+                db.store_stream(&body[..], &format!("{i}.pdf"), "application/pdf").await
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap().expect("store_stream");
+    }
+    assert_eq!(db.list().await.unwrap().len(), 16);
+}
+
+#[tokio::test]
+async fn upload_limits_and_temp_cleanup() {
+    use store::{StoreConfig, StoreError};
+    use tokio::io::AsyncWriteExt;
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let config = StoreConfig { max_upload_bytes: 32, ..Default::default() };
+    let db = DocStore::open_with_config(&root, config).expect("open db");
+    let staging = || fs::read_dir(root.join("tmp")).unwrap().count();
+
+    let err = db
+        .store_stream(&[b"%PDF-1.4\n".as_slice(), &[b'x'; 64]].concat()[..], "big.pdf", "application/pdf")
+        .await
+        .unwrap_err();
+    assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::TooLarge { limit: 32 }));
+
+    // Rejected on the first chunk, without waiting for the rest of the stream
+    let (mut tx, rx) = tokio::io::duplex(64);
+    tx.write_all(b"GIF89a").await.unwrap();
+    let err = db.store_stream(rx, "fake.pdf", "application/pdf").await.unwrap_err();
+    assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::UnsupportedType { detected: Some("image/gif".into()) }));
+    assert_eq!(staging(), 0);
+
+    // A client that disconnects mid-upload leaves nothing behind
+    let (mut tx, rx) = tokio::io::duplex(64);
+    tx.write_all(b"%PDF-1.4\npartial").await.unwrap();
+    let task = tokio::spawn({
+        let db = db.clone();
+        async move { db.store_stream(rx, "partial.pdf", "application/pdf").await }
+    });
+    while staging() == 0 {
+        tokio::task::yield_now().await;
+    }
+    task.abort();
+    assert!(task.await.unwrap_err().is_cancelled());
+    assert_eq!(staging(), 0);
+    assert!(db.list().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn duplicate_uploads_share_one_blob() {
+    use store::{BlobStore, DocInput, MemBlobStore};
+    use std::sync::Arc;
+
+    let tmp = TempDir::new().unwrap();
+    let blobs = MemBlobStore::new();
+    let db = DocStore::open_with_blobs(tmp.path().join("db"), Arc::new(blobs.clone())).expect("open db");
+    let body = text_pdf(&["same bytes"]);
+
+    let first = db
+        .store(&body[..], DocInput { uploader: Some("alice".into()), ..DocInput::new("a.pdf", "application/pdf") })
+        .await
+        .unwrap();
+    let second = db
+        .store(&body[..], DocInput { tags: vec!["lease".into()], ..DocInput::new("b.pdf", "application/pdf") })
+        .await
+        .unwrap();
+    assert_ne!(first.id_hex, second.id_hex);
+    assert_eq!(first.sha256, second.sha256);
+    assert_eq!(blobs.len(), 2, "one blob and one outboard");
+    assert_eq!(db.get_blob(&first.sha256).await.unwrap().unwrap().refs, 2);
+
+    let got = db.get_by_hex(&second.id_hex).await.unwrap().unwrap();
+    assert_eq!((got.filename.as_str(), got.tags.clone()), ("b.pdf", vec!["lease".to_string()]));
+    assert_eq!(db.get_by_hex(&first.id_hex).await.unwrap().unwrap().uploader.as_deref(), Some("alice"));
+    assert_eq!(db.records_for_blob(&first.sha256).await.unwrap().len(), 2);
+
+    assert!(db.delete_by_hex(&first.id_hex).await.unwrap());
+    assert!(db.purge(&first.id_hex).await.unwrap());
+    assert_eq!(db.read_blob(&second).await.unwrap(), body);
+    assert_eq!(db.get_blob(&first.sha256).await.unwrap().unwrap().refs, 1);
+
+    assert!(db.delete_by_hex(&second.id_hex).await.unwrap());
+    assert!(db.purge(&second.id_hex).await.unwrap());
+    assert!(db.get_blob(&first.sha256).await.unwrap().is_none());
+    assert!(!blobs.exists(&store::blob_key(&first.sha256)).unwrap());
+}
+
+#[tokio::test]
+async fn versions_form_a_supersession_chain() {
+    use store::{DocInput, StoreError};
+
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).expect("open db");
+    let pdf = |n: u8| text_pdf(&[&format!("valuation rev {n}")]);
+
+    let v1 = db.store(&pdf(1)[..], DocInput::new("valuation.pdf", "application/pdf")).await.unwrap();
+    let input = |prev: &store::DocMeta| DocInput {
+        supersedes: Some(prev.id_hex.clone()),
+        ..DocInput::new("valuation.pdf", "application/pdf")
+    };
+    let v2 = db.store(&pdf(2)[..], input(&v1)).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    let v3 = db.store(&pdf(3)[..], input(&v2)).await.unwrap();
+
+    assert_eq!((v1.version, v2.version, v3.version), (1, 2, 3));
+    assert_eq!(v3.document_id, v1.id_hex);
+    assert_eq!(v3.supersedes_sha256, Some(v2.sha256));
+    let ids: Vec<_> = db.history(&v2.id_hex).await.unwrap().into_iter().map(|m| m.id_hex).collect();
+    assert_eq!(ids, [v1.id_hex.clone(), v2.id_hex.clone(), v3.id_hex.clone()]);
+    assert_eq!(db.latest(&v1.id_hex).await.unwrap().unwrap().id_hex, v3.id_hex);
+    assert_eq!(db.at(&v3.id_hex, v2.created_at_unix_ms).await.unwrap().unwrap().id_hex, v2.id_hex);
+    assert!(db.at(&v3.id_hex, v1.created_at_unix_ms - 1).await.unwrap().is_none());
+
+    // Trashing the newest version makes the one before it current again.
+    db.trash(&v3.id_hex, None, None).await.unwrap();
+    assert_eq!(db.history(&v1.id_hex).await.unwrap().len(), 3);
+    assert_eq!(db.latest(&v1.id_hex).await.unwrap().unwrap().id_hex, v2.id_hex);
+    assert_eq!(db.at(&v1.id_hex, v3.created_at_unix_ms).await.unwrap().unwrap().id_hex, v2.id_hex);
+    db.restore(&v3.id_hex).await.unwrap();
+
+    // Only the latest version can be superseded, and only once.
+    let err = db.store(&pdf(4)[..], input(&v2)).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::AlreadySuperseded { .. })));
+    let missing = DocInput { supersedes: Some("00".repeat(32)), ..DocInput::new("x.pdf", "application/pdf") };
+    let err = db.store(&pdf(5)[..], missing).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::NotFound { .. })));
+}
+
+#[tokio::test]
+async fn trash_restore_and_retention() {
+    use store::{DocInput, StoreConfig, StoreError};
+
+    let tmp = TempDir::new().unwrap();
+    let config = StoreConfig { retention_secs: 0, explicit_purge_anchored: true, ..Default::default() };
+    let db = DocStore::open_with_config(tmp.path().join("db"), config).expect("open db");
+    let store = |text: &str, name: &str| db.store(std::io::Cursor::new(text_pdf(&[text])), DocInput::new(name, "application/pdf"));
+
+    let deed = store("deed", "deed.pdf").await.unwrap();
+    assert!(db.trash(&deed.id_hex, Some("bob".into()), Some("duplicate".into())).await.unwrap());
+    assert!(!db.trash(&deed.id_hex, None, None).await.unwrap());
+    assert!(db.list().await.unwrap().is_empty());
+    let tombstone = db.get_by_hex(&deed.id_hex).await.unwrap().unwrap().deleted.unwrap();
+    assert_eq!((tombstone.deleted_by.as_deref(), tombstone.reason.as_deref()), (Some("bob"), Some("duplicate")));
+    assert_eq!(db.list_trash().await.unwrap().len(), 1);
+
+    // With zero retention the record cannot come back, only be purged.
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    let err = db.restore(&deed.id_hex).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::RetentionExpired { .. })));
+
+    let anchored = store("anchored", "anchored.pdf").await.unwrap();
+    db.set_anchor(&anchored.id_hex, "0xabc").await.unwrap();
+    db.delete_by_hex(&anchored.id_hex).await.unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    let report = db.purge_expired().await.unwrap();
+    assert_eq!((report.purged, report.kept_anchored), (1, 1));
+    assert!(db.get_by_hex(&deed.id_hex).await.unwrap().is_none());
+    assert!(db.purge(&anchored.id_hex).await.unwrap());
+    assert!(db.list_trash().await.unwrap().is_empty());
+
+    // Restoring inside the retention window.
+    let db = DocStore::open_with_config(tmp.path().join("db2"), StoreConfig::default()).unwrap();
+    let lease = db.store(&text_pdf(&["lease"])[..], DocInput::new("lease.pdf", "application/pdf")).await.unwrap();
+    let err = db.purge(&lease.id_hex).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::NotDeleted { .. })));
+    db.delete_by_hex(&lease.id_hex).await.unwrap();
+    let restored = db.restore(&lease.id_hex).await.unwrap().unwrap();
+    assert!(restored.deleted.is_none());
+    assert_eq!(db.list().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn racing_purges_drop_a_shared_blob_reference_once() {
+    use store::{bao, DocInput};
+
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).unwrap();
+    let pdf = text_pdf(&[&prose(3, 150_000)]);
+    let kept = db.store(&pdf[..], DocInput::new("kept.pdf", "application/pdf")).await.unwrap();
+    let copy = db.store(&pdf[..], DocInput::new("copy.pdf", "application/pdf")).await.unwrap();
+    db.delete_by_hex(&copy.id_hex).await.unwrap();
+
+    // a backfill holds the blob lock while it hashes, so every purge reads
+    // the trashed record before the first one removes it
+    db.blobs().delete(&bao::bao_key(&kept.sha256)).unwrap();
+    let backfill = tokio::spawn({
+        let db = db.clone();
+        async move { db.backfill().await.unwrap() }
+    });
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    let purges = (0..4).map(|_| {
+        let (db, id) = (db.clone(), copy.id_hex.clone());
+        tokio::spawn(async move { db.purge(&id).await.unwrap() })
+    });
+    let purged = futures_util::future::join_all(purges).await;
+    assert_eq!(backfill.await.unwrap().backfilled, 1);
+    assert_eq!(purged.into_iter().filter(|p| *p.as_ref().unwrap()).count(), 1);
+    assert_eq!(db.read_blob(&kept).await.unwrap(), pdf);
+    assert!(db.check().await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn fsck_reports_and_repairs_drift() {
+    use store::{DocInput, FsckOptions};
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let db = DocStore::open(&root).expect("open db");
+    let kept = db.store(&text_pdf(&["kept"])[..], DocInput::new("kept.pdf", "application/pdf")).await.unwrap();
+    let lost = db.store(&text_pdf(&["lost"])[..], DocInput::new("lost.pdf", "application/pdf")).await.unwrap();
+
+    db.blobs().delete(&store::blob_key(&lost.sha256)).unwrap();
+    db.blobs().put(&store::blob_key(&[7u8; 32]), &mut &b"%PDF-1.4\norphan"[..]).unwrap();
+    fs::write(root.join("tmp").join("upload-crashed"), b"partial").unwrap();
+
+    let report = db.fsck(FsckOptions { temp_min_age: Default::default(), ..Default::default() }).await.unwrap();
+    assert_eq!(report.missing_blobs, [hex::encode(lost.sha256)]);
+    assert_eq!(report.orphan_blobs, [store::blob_key(&[7u8; 32])]);
+    assert_eq!(report.stray_temp_files, ["upload-crashed"]);
+    assert!(!report.repaired && !report.is_clean());
+
+    let opts = FsckOptions { repair: true, temp_min_age: Default::default() };
+    let repaired = db.fsck(opts.clone()).await.unwrap();
+    assert_eq!(repaired.unrepaired.len(), 1, "missing bytes cannot be recreated");
+    // Only the missing blob is left, until its trashed record is purged.
+    let after = db.fsck(opts.clone()).await.unwrap();
+    assert!(after.orphan_blobs.is_empty() && after.stray_temp_files.is_empty());
+    assert_eq!(after.missing_blobs, [hex::encode(lost.sha256)]);
+    assert_eq!(db.list().await.unwrap(), [db.get_by_hex(&kept.id_hex).await.unwrap().unwrap()]);
+    let trashed = db.get_by_hex(&lost.id_hex).await.unwrap().unwrap().deleted.unwrap();
+    assert_eq!(trashed.reason.as_deref(), Some("blob missing"));
+    assert_eq!(fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+    db.purge(&lost.id_hex).await.unwrap();
+    assert!(db.fsck(opts).await.unwrap().is_clean());
+}
+
+/// Serve `body` for every request, like `ipfs cat` would.
+async fn mock_ipfs(body: Vec<u8>) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let mut req = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = conn.read(&mut buf).await.unwrap();
+                if n == 0 { break; }
+                req.extend_from_slice(&buf[..n]);
+            }
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            conn.write_all(head.as_bytes()).await.unwrap();
+            conn.write_all(&body).await.unwrap();
+        }
+    });
+    format!("http://{addr}")
+}
+
+#[tokio::test]
+async fn scrub_quarantines_and_refetches_corrupt_blobs() {
+    use store::{DocInput, ScrubOptions};
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let db = DocStore::open(&root).expect("open db");
+    let pinned = write_pdf(&tmp, "pinned.pdf", "pinned");
+    let good = fs::read(&pinned).unwrap();
+    let pinned = db.store_pdf(&pinned, Some("bafy-pinned".into())).await.unwrap();
+    assert_eq!(fs::read(root.join("blobs").join(store::blob_key(&pinned.sha256))).unwrap(), good);
+    let local = db.store(&text_pdf(&["local only"])[..], DocInput::new("local.pdf", "application/pdf")).await.unwrap();
+
+    let opts = ScrubOptions { ipfs_url: Some(mock_ipfs(good.clone()).await), ..Default::default() };
+    let clean = db.scrub(opts.clone()).await.unwrap();
+    assert_eq!((clean.blobs_checked, clean.corrupt.len()), (2, 0));
+    assert!(db.get_by_hex(&local.id_hex).await.unwrap().unwrap().last_verified_at_unix_ms.is_some());
+
+    for meta in [&pinned, &local] {
+        db.blobs().put(&store::blob_key(&meta.sha256), &mut &b"%PDF-1.4\nbit rot"[..]).unwrap();
+    }
+    let report = db.scrub(opts).await.unwrap();
+    assert_eq!(report.corrupt.len(), 2);
+    assert_eq!(report.restored, [hex::encode(pinned.sha256)]);
+    assert_eq!(report.unrecoverable, [hex::encode(local.sha256)]);
+    assert_eq!(db.last_scrub().await.unwrap().as_ref(), Some(&report));
+
+    assert_eq!(db.read_blob(&pinned).await.unwrap(), good);
+    let local = db.get_by_hex(&local.id_hex).await.unwrap().unwrap();
+    assert!(local.quarantined_at_unix_ms.is_some());
+    assert_eq!(fs::read_dir(root.join("quarantine")).unwrap().count(), 2);
+}
+
+#[tokio::test]
+async fn queries_use_indexes_and_paginate() {
+    use store::{DocInput, DocQuery, SortOrder, StoreError};
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let db = DocStore::open(&root).expect("open db");
+    let mut ids = Vec::new();
+    for n in 0..7u8 {
+        let input = DocInput {
+            owner: Some(if n % 2 == 0 { "alice" } else { "bob" }.into()),
+            property_id: Some(format!("lot-{}", n % 3)),
+            ..DocInput::new(format!("deed-{n}.pdf"), "application/pdf")
+        };
+        let body = text_pdf(&[&format!("deed {n}")]);
+        ids.push(db.store(&body[..], input).await.unwrap().id_hex);
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    }
+    let pinned = write_pdf(&tmp, "pinned.pdf", "pinned");
+    let pinned = db.store_pdf(&pinned, Some("bafypinned".into())).await.unwrap();
+    db.delete_by_hex(&ids[6]).await.unwrap();
+
+    // Walk alice's documents oldest first, two per page.
+    let mut query = DocQuery { owner: Some("alice".into()), sort: SortOrder::Oldest, limit: Some(2), ..Default::default() };
+    let mut seen = Vec::new();
+    loop {
+        let page = db.query(query.clone()).await.unwrap();
+        seen.extend(page.documents.into_iter().map(|m| m.id_hex));
+        match page.next_cursor {
+            Some(next) => query.cursor = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(seen, [ids[0].clone(), ids[2].clone(), ids[4].clone()], "trashed records are skipped");
+
+    let mut query = DocQuery { sort: SortOrder::FilenameDesc, limit: Some(3), ..Default::default() };
+    query.filter("property_id", "lot-1").unwrap();
+    let page = db.query(query).await.unwrap();
+    let names: Vec<_> = page.documents.iter().map(|m| m.filename.as_str()).collect();
+    assert_eq!(names, ["deed-4.pdf", "deed-1.pdf"]);
+    assert!(page.next_cursor.is_none());
+
+    let by_cid = db.query(DocQuery { cid: Some("bafypinned".into()), ..Default::default() }).await.unwrap();
+    assert_eq!(by_cid.documents.len(), 1);
+    assert_eq!(by_cid.documents[0].id_hex, pinned.id_hex);
+
+    let window = DocQuery {
+        created_after: Some(db.get_by_hex(&ids[1]).await.unwrap().unwrap().created_at_unix_ms),
+        created_before: Some(db.get_by_hex(&ids[3]).await.unwrap().unwrap().created_at_unix_ms),
+        ..Default::default()
+    };
+    let ids_in_window: Vec<_> = db.query(window).await.unwrap().documents.into_iter().map(|m| m.id_hex).collect();
+    assert_eq!(ids_in_window, [ids[2].clone(), ids[1].clone()]);
+
+    let bad = DocQuery { cursor: Some("zz".into()), ..Default::default() };
+    let err = db.query(bad).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::InvalidQuery(_))));
+
+    // Indexes missing after an upgrade are rebuilt on open.
+    drop(db);
+    sled::open(root.join("kv")).unwrap().drop_tree("idx_created").unwrap();
+    let db = DocStore::open(&root).expect("reopen db");
+    assert_eq!(db.query(DocQuery::default()).await.unwrap().documents.len(), 7);
+}
+
+/// A real PDF with one page per entry of `pages`, each showing that text.
+fn text_pdf(pages: &[&str]) -> Vec<u8> {
+    save_pdf(pdf_doc(pages))
+}
+
+fn save_pdf(mut doc: lopdf::Document) -> Vec<u8> {
+    let mut out = Vec::new();
+    doc.save_to(&mut out).unwrap();
+    out
+}
+
+fn pdf_doc(pages: &[&str]) -> lopdf::Document {
+    use lopdf::content::{Content, Operation};
+    use lopdf::{dictionary, Document, Object, Stream};
+
+    let mut doc = Document::with_version("1.5");
+    let pages_id = doc.new_object_id();
+    let font_id = doc.add_object(dictionary! { "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Courier" });
+    let resources_id = doc.add_object(dictionary! { "Font" => dictionary! { "F1" => font_id } });
+    let mut kids = Vec::new();
+    for text in pages {
+        let content = Content {
+            operations: vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                Operation::new("Td", vec![72.into(), 720.into()]),
+                Operation::new("Tj", vec![Object::string_literal(*text)]),
+                Operation::new("ET", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+        let page_id = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id, "Contents" => content_id });
+        kids.push(page_id.into());
+    }
+    let count = kids.len() as i64;
+    doc.objects.insert(
+        pages_id,
+        Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Kids" => kids,
+            "Count" => count,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+        }),
+    );
+    let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+    doc.trailer.set("Root", catalog_id);
+    doc
+}
+
+#[tokio::test]
+async fn full_text_search_finds_pages_and_follows_deletes() {
+    use store::DocInput;
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let db = DocStore::open(&root).expect("open db");
+    let deed = text_pdf(&["Warranty deed", "Parcel 12-345-678 conveyed to Jane Doe, 4 Elm Street"]);
+    let deed = db.store(&deed[..], DocInput::new("deed.pdf", "application/pdf")).await.unwrap();
+    let lease = text_pdf(&["Lease for 9 Oak Avenue"]);
+    db.store(&lease[..], DocInput::new("lease.pdf", "application/pdf")).await.unwrap();
+
+    let hits = db.search("\"12-345-678\"", 10).await.unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!((hits[0].id_hex.as_str(), hits[0].page), (deed.id_hex.as_str(), Some(2)));
+    assert!(hits[0].snippet.contains("<b>12</b>"), "{}", hits[0].snippet);
+    assert_eq!(db.search("elm AND doe", 10).await.unwrap().len(), 1);
+
+    db.delete_by_hex(&deed.id_hex).await.unwrap();
+    assert!(db.search("doe", 10).await.unwrap().is_empty());
+    db.restore(&deed.id_hex).await.unwrap();
+    assert_eq!(db.search("doe", 10).await.unwrap().len(), 1);
+
+    // The index can be thrown away and rebuilt from the blobs.
+    drop(db);
+    fs::remove_dir_all(root.join("search")).unwrap();
+    let db = DocStore::open(&root).expect("reopen db");
+    assert!(db.search("doe", 10).await.unwrap().is_empty());
+    let report = db.reindex().await.unwrap();
+    assert_eq!((report.records, report.pages), (2, 3));
+    assert_eq!(db.search("oak", 10).await.unwrap()[0].page, Some(1));
+}
+
+#[tokio::test]
+async fn pdf_metadata_is_extracted_at_ingest() {
+    use lopdf::{dictionary, Object, Stream};
+    use store::DocInput;
+
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).expect("open db");
+
+    let mut doc = pdf_doc(&["Deed of trust", "Signatures"]);
+    let info_id = doc.add_object(dictionary! {
+        "Title" => Object::string_literal("Deed of Trust"),
+        "Author" => lopdf::text_string("Zoë Registrar"),
+        "Producer" => Object::string_literal("County Recorder 2.1"),
+        "CreationDate" => Object::string_literal("D:20240315093000+02'00'"),
+    });
+    doc.trailer.set("Info", info_id);
+    let xmp = doc.add_object(Stream::new(dictionary! { "Type" => "Metadata", "Subtype" => "XML" }, b"<x:xmpmeta/>".to_vec()));
+    let field = |name: &str| dictionary! { "FT" => "Tx", "T" => Object::string_literal(name) };
+    let fields = vec![doc.add_object(field("grantor")).into(), doc.add_object(field("grantee")).into()];
+    let acroform = doc.add_object(dictionary! { "Fields" => fields });
+    let catalog = doc.catalog_mut().unwrap();
+    catalog.set("Metadata", xmp);
+    catalog.set("AcroForm", acroform);
+    let bytes = save_pdf(doc);
+
+    let meta = db.store(&bytes[..], DocInput::new("trust.pdf", "application/pdf")).await.unwrap();
+    let got = db.get_by_hex(&meta.id_hex).await.unwrap().unwrap();
+    let pdf = got.pdf.expect("pdf metadata");
+    assert_eq!((pdf.version.as_str(), pdf.page_count), ("1.5", 2));
+    assert_eq!(pdf.title.as_deref(), Some("Deed of Trust"));
+    assert_eq!(pdf.author.as_deref(), Some("Zoë Registrar"));
+    assert_eq!(pdf.created.as_deref(), Some("2024-03-15T09:30:00+02:00"));
+    assert_eq!(pdf.xmp.as_deref(), Some("<x:xmpmeta/>"));
+    assert_eq!(pdf.form_fields, 2);
+    assert!(!pdf.encrypted && !pdf.linearized);
+}
+
+#[tokio::test]
+async fn pdf_validation_rejects_or_repairs_by_policy() {
+    use store::{DocInput, PdfDefect, PdfValidation, StoreConfig, StoreError};
+
+    let tmp = TempDir::new().unwrap();
+    let open = |name: &str, pdf_validation| {
+        DocStore::open_with_config(tmp.path().join(name), StoreConfig { pdf_validation, ..Default::default() }).unwrap()
+    };
+    let defects = |err: anyhow::Error| match err.downcast_ref::<StoreError>() {
+        Some(StoreError::InvalidPdf { defects }) => defects.clone(),
+        other => panic!("expected InvalidPdf, got {other:?}"),
+    };
+    let input = |name: &str| DocInput::new(name, "application/pdf");
+
+    // Cut off before the xref stream: repairable from the object headers.
+    let whole = text_pdf(&["first page", "second page"]);
+    let at = |hay: &[u8], needle: &[u8]| hay.windows(needle.len()).rposition(|w| w == needle).unwrap();
+    let xref_stream = at(&whole, b"/Type/XRef");
+    let truncated = whole[..at(&whole[..xref_stream], b"endobj") + 6].to_vec();
+    let lost_tail = [PdfDefect::MissingEof, PdfDefect::MissingStartxref, PdfDefect::MissingTrailer];
+
+    let strict = open("strict", PdfValidation::Strict);
+    assert_eq!(defects(strict.store(&truncated[..], input("cut.pdf")).await.unwrap_err()), lost_tail);
+    assert!(strict.list().await.unwrap().is_empty());
+    assert!(strict.store(&whole[..], input("whole.pdf")).await.unwrap().pdf.unwrap().defects.is_empty());
+
+    let lenient = open("lenient", PdfValidation::AllowRepairable);
+    let meta = lenient.store(&truncated[..], input("cut.pdf")).await.unwrap();
+    let pdf = meta.pdf.clone().expect("metadata of the repaired file");
+    assert_eq!((pdf.page_count, pdf.defects.as_slice()), (2, &lost_tail[..]));
+    assert_eq!(lenient.read_blob(&meta).await.unwrap(), truncated, "original bytes are kept");
+
+    // A missing page tree cannot be repaired.
+    let mut doc = pdf_doc(&["orphaned page"]);
+    doc.catalog_mut().unwrap().remove(b"Pages");
+    let err = lenient.store(&save_pdf(doc)[..], input("no-pages.pdf")).await.unwrap_err();
+    assert!(matches!(defects(err).as_slice(), [PdfDefect::PageTree { .. }]));
+
+    let junk = b"%PDF-1.4\nnot really";
+    let err = lenient.store(&junk[..], input("junk.pdf")).await.unwrap_err();
+    assert!(defects(err).contains(&PdfDefect::NoCatalog));
+    let stored = open("off", PdfValidation::Off).store(&junk[..], input("junk.pdf")).await.unwrap();
+    assert!(stored.pdf.is_none());
+}
+
+#[tokio::test]
+async fn active_content_is_flagged_rejected_or_sanitized() {
+    use lopdf::{dictionary, Object, Stream};
+    use sha2::{Digest, Sha256};
+    use store::{ActiveContent, ActiveContentPolicy, DocInput, StoreConfig, StoreError};
+
+    let mut doc = pdf_doc(&["deed with a payload"]);
+    let script = doc.add_object(dictionary! { "S" => "JavaScript", "JS" => Object::string_literal("app.alert(1)") });
+    let attachment = doc.add_object(Stream::new(dictionary! { "Type" => "EmbeddedFile" }, b"MZ".to_vec()));
+    let filespec = doc.add_object(dictionary! {
+        "Type" => "Filespec", "F" => Object::string_literal("setup.exe"), "EF" => dictionary! { "F" => attachment },
+    });
+    let link = doc.add_object(dictionary! {
+        "Type" => "Annot", "Subtype" => "Link", "Rect" => vec![0.into(), 0.into(), 10.into(), 10.into()],
+        "A" => dictionary! { "S" => "URI", "URI" => Object::string_literal("https://example.invalid/") },
+    });
+    let xfa = doc.add_object(Stream::new(dictionary! {}, b"<xdp:xdp/>".to_vec()));
+    let page = *doc.get_pages().values().next().unwrap();
+    doc.get_dictionary_mut(page).unwrap().set("Annots", vec![link.into()]);
+    let catalog = doc.catalog_mut().unwrap();
+    catalog.set("OpenAction", script);
+    catalog.set("Names", dictionary! { "EmbeddedFiles" => dictionary! { "Names" => vec![Object::string_literal("setup.exe"), filespec.into()] } });
+    catalog.set("AcroForm", dictionary! { "Fields" => Vec::<Object>::new(), "XFA" => xfa });
+    let bytes = save_pdf(doc);
+    let all = [ActiveContent::JavaScript, ActiveContent::OpenAction, ActiveContent::EmbeddedFile, ActiveContent::Xfa, ActiveContent::Uri];
+
+    let tmp = TempDir::new().unwrap();
+    let open = |name: &str, active_content| {
+        DocStore::open_with_config(tmp.path().join(name), StoreConfig { active_content, ..Default::default() }).unwrap()
+    };
+    let input = || DocInput::new("deed.pdf", "application/pdf");
+
+    let flagged = open("flag", ActiveContentPolicy::Flag).store(&bytes[..], input()).await.unwrap();
+    assert_eq!(flagged.pdf.unwrap().active_content, all);
+    assert!(flagged.sanitized.is_none());
+
+    let err = open("reject", ActiveContentPolicy::Reject).store(&bytes[..], input()).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::ActiveContent { found: all.to_vec() }));
+
+    let db = open("sanitize", ActiveContentPolicy::Sanitize);
+    let clean = db.store(&bytes[..], input()).await.unwrap();
+    let sanitized = clean.sanitized.clone().expect("derivative provenance");
+    assert_eq!(sanitized.original_sha256, <[u8; 32]>::from(Sha256::digest(&bytes)));
+    assert_eq!((sanitized.original_size_bytes, sanitized.removed.as_slice()), (bytes.len() as u64, &all[..]));
+    assert_ne!(clean.sha256, sanitized.original_sha256);
+    assert!(clean.pdf.as_ref().unwrap().active_content.is_empty());
+
+    let stored = db.read_blob(&clean).await.unwrap();
+    assert_eq!(<[u8; 32]>::from(Sha256::digest(&stored)), clean.sha256);
+    assert!(store::pdf::validate(&stored).is_empty());
+    assert!(!stored.windows(9).any(|w| w == b"setup.exe"));
+    assert_eq!(store::pdf::extract_pages(&stored).unwrap(), [(1, "deed with a payload".to_string())]);
+}
+
+#[tokio::test]
+async fn content_types_are_sniffed_and_allow_listed() {
+    use store::{DocInput, StoreConfig, StoreError};
+
+    let png = [
+        b"\x89PNG\r\n\x1a\n".as_slice(),
+        b"\0\0\0\x0dIHDR\0\0\0\x01\0\0\0\x01\x08\x02\0\0\0\x90\x77\x53\xde",
+        b"\0\0\0\0IEND\xae\x42\x60\x82",
+    ]
+    .concat();
+    let xlsx = [b"PK\x03\x04".as_slice(), &[0; 26], b"xl/workbook.xml", b"PK\x05\x06", &[0; 18]].concat();
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let config = StoreConfig {
+        allowed_types: vec!["application/pdf".into(), "image/*".into()],
+        max_pdf_inspect_bytes: 4096,
+        ..Default::default()
+    };
+    let db = DocStore::open_with_config(&root, config.clone()).unwrap();
+
+    let plan = db.store(&png[..], DocInput::new("floor-plan", "application/octet-stream")).await.unwrap();
+    assert_eq!((plan.content_type.as_str(), plan.mime.as_str()), ("image/png", "image/png"));
+    assert!(plan.pdf.is_none());
+    assert_eq!(fs::read(root.join("blobs").join(hex::encode(plan.sha256))).unwrap(), png);
+    let deed = db.store(&text_pdf(&["deed"])[..], DocInput::new("deed", "application/pdf")).await.unwrap();
+    assert_eq!(deed.content_type, "application/pdf");
+
+    let err = db.store(&xlsx[..], DocInput::new("rents.xlsx", "application/pdf")).await.unwrap_err();
+    let detected = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+    assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::UnsupportedType { detected: Some(detected.into()) }));
+    // the marker search reads in 64 KiB chunks; one straddling a boundary still counts
+    let wide = [b"PK\x03\x04".as_slice(), &[0; 65526], b"xl/workbook.xml", b"PK\x05\x06", &[0; 18]].concat();
+    let err = db.store(&wide[..], DocInput::new("rents.xlsx", "application/pdf")).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<StoreError>(), Some(&StoreError::UnsupportedType { detected: Some(detected.into()) }));
+    // PDFs are parsed in memory, so larger ones are stored unparsed
+    let big = [b"%PDF-1.4\n".as_slice(), &[b' '; 4096]].concat();
+    let stored = db.store(&big[..], DocInput::new("big.pdf", "application/pdf")).await.unwrap();
+    let info = stored.pdf.unwrap();
+    assert!(info.uninspected);
+    assert_eq!((info.version.as_str(), info.page_count), ("1.4", 0));
+    let err = db.store(&png[..png.len() - 12], DocInput::new("cut.png", "image/png")).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::InvalidContent { mime, .. }) if mime == "image/png"));
+    let bad = StoreConfig { allowed_types: vec!["text/csv".into()], ..Default::default() };
+    assert!(DocStore::open_with_config(tmp.path().join("csv"), bad).is_err());
+
+    // Stores from before the registry kept blobs at pdfs/<sha256>.pdf.
+    drop(db);
+    let key = store::blob_key(&deed.sha256);
+    fs::create_dir(root.join("pdfs")).unwrap();
+    fs::rename(root.join("blobs").join(&key), root.join("pdfs").join(format!("{key}.pdf"))).unwrap();
+    fs::rename(root.join("blobs").join(store::blob_key(&plan.sha256)), root.join("pdfs").join(store::blob_key(&plan.sha256))).unwrap();
+    // outboards came later too
+    fs::remove_dir_all(root.join("blobs")).unwrap();
+    sled::open(root.join("kv")).unwrap().drop_tree("meta").unwrap();
+    let db = DocStore::open_with_config(&root, config).unwrap();
+    assert!(root.join("blobs").join(&key).exists() && !root.join("pdfs").exists());
+    assert_eq!(db.read_blob(&deed).await.unwrap(), text_pdf(&["deed"]));
+    assert_eq!(db.read_blob(&plan).await.unwrap(), png);
+}
+
+/// Yields nothing but an error, like a client that disconnected.
+struct Disconnected;
+
+impl tokio::io::AsyncRead for Disconnected {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        _: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+    }
+}
+
+#[tokio::test]
+async fn resumable_uploads_survive_restarts_and_check_chunks() {
+    use sha2::{Digest, Sha256};
+    use store::{Checksum, ChecksumAlgorithm, DocInput, FsckOptions, StoreConfig, StoreError};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let db = DocStore::open(&root).unwrap();
+    let pdf = text_pdf(&["lease"]);
+    let metadata = [("filename", "lease.pdf"), ("tags", "lease, 2024")].map(|(k, v)| (k.to_string(), v.to_string()));
+    let upload = db.create_upload(pdf.len() as u64, metadata.into(), None).await.unwrap();
+    let (first, rest) = pdf.split_at(100);
+    let sha256 = |b: &[u8]| Checksum { algorithm: ChecksumAlgorithm::Sha256, digest: Sha256::digest(b).to_vec() };
+
+    let err = db.append_upload(&upload.id, 0, first, Some(sha256(b"other"))).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::ChecksumMismatch { .. })));
+    assert_eq!(db.upload(&upload.id).await.unwrap().unwrap().offset, 0);
+    assert_eq!(db.append_upload(&upload.id, 0, first, Some(sha256(first))).await.unwrap().offset, 100);
+    let err = db.append_upload(&upload.id, 0, first, None).await.unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&StoreError::UploadOffsetMismatch { expected: 100 }));
+    // a dropped connection keeps what arrived, unless a checksum has to vouch for it
+    db.append_upload(&upload.id, 100, (&rest[..50]).chain(Disconnected), None).await.unwrap_err();
+    db.append_upload(&upload.id, 150, (&rest[50..60]).chain(Disconnected), Some(sha256(&rest[50..60]))).await.unwrap_err();
+    let err = db.complete_upload(&upload.id).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::UploadIncomplete { offset: 150, .. })));
+
+    drop(db);
+    let db = DocStore::open(&root).unwrap();
+    assert_eq!(db.upload(&upload.id).await.unwrap().unwrap().offset, 150);
+    assert!(db.append_upload(&upload.id, 150, &rest[50..], None).await.unwrap().is_complete());
+    let meta = db.complete_upload(&upload.id).await.unwrap();
+    assert_eq!((meta.filename.as_str(), meta.content_type.as_str()), ("lease.pdf", "application/pdf"));
+    assert_eq!(meta.tags, ["lease", "2024"]);
+    assert_eq!(db.read_blob(&meta).await.unwrap(), pdf);
+    // a stored upload is gone
+    assert!(db.upload(&upload.id).await.unwrap().is_none());
+    let err = db.complete_upload(&upload.id).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::UnknownUpload { .. })));
+    assert!(!root.join("uploads").join(&upload.id).exists());
+
+    // losing a supersedes race is worth a retry, so the upload stays
+    let next = text_pdf(&["lease, amended"]);
+    let metadata = [("supersedes".to_string(), meta.id_hex.clone())];
+    let late = db.create_upload(next.len() as u64, metadata.into(), None).await.unwrap();
+    db.append_upload(&late.id, 0, &next[..], None).await.unwrap();
+    let first = DocInput { supersedes: Some(meta.id_hex.clone()), ..DocInput::new("lease.pdf", "application/pdf") };
+    db.store(&text_pdf(&["lease, renewed"])[..], first).await.unwrap();
+    let err = db.complete_upload(&late.id).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::AlreadySuperseded { .. })));
+    assert!(db.upload(&late.id).await.unwrap().unwrap().is_complete());
+    assert!(db.terminate_upload(&late.id).await.unwrap());
+
+    // a kept upload drops its bytes but answers retries with its record
+    let kept = db.create_upload(next.len() as u64, Default::default(), None).await.unwrap();
+    db.append_upload(&kept.id, 0, &next[..], None).await.unwrap();
+    let stored = db.store_upload(&kept.id).await.unwrap();
+    assert_eq!(db.upload(&kept.id).await.unwrap().unwrap().document, Some(stored.id_hex.clone()));
+    assert!(!root.join("uploads").join(&kept.id).exists());
+    let retried = db.append_upload(&kept.id, next.len() as u64, &b""[..], None).await.unwrap();
+    assert_eq!(retried.document, Some(stored.id_hex.clone()));
+    assert_eq!(db.store_upload(&kept.id).await.unwrap().id_hex, stored.id_hex);
+    assert!(db.terminate_upload(&kept.id).await.unwrap());
+
+    // rejected content is discarded; abandoned uploads can be terminated
+    let junk = db.create_upload(4, Default::default(), None).await.unwrap();
+    db.append_upload(&junk.id, 0, &b"junk"[..], None).await.unwrap();
+    let err = db.complete_upload(&junk.id).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(StoreError::UnsupportedType { .. })));
+    assert!(db.upload(&junk.id).await.unwrap().is_none());
+    let abandoned = db.create_upload(10, Default::default(), None).await.unwrap();
+    assert!(db.terminate_upload(&abandoned.id).await.unwrap());
+    assert!(!db.terminate_upload(&abandoned.id).await.unwrap());
+    assert!(db.create_upload(u64::MAX, Default::default(), None).await.is_err());
+
+    // idle uploads expire, and fsck finds files no upload owns
+    let config = StoreConfig { upload_expiry_secs: 1, ..Default::default() };
+    let db = DocStore::open_with_config(tmp.path().join("expiring"), config).unwrap();
+    let idle = db.create_upload(10, Default::default(), None).await.unwrap();
+    let expires = idle.expires_at_unix_ms(1).unwrap();
+    assert_eq!(expires, idle.created_at_unix_ms + 1000);
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert!(db.upload(&idle.id).await.unwrap().is_none());
+    assert_eq!(db.expire_uploads().await.unwrap(), 1);
+    assert!(!tmp.path().join("expiring/uploads").join(&idle.id).exists());
+    fs::write(tmp.path().join("expiring/uploads/leftover"), b"x").unwrap();
+    let opts = FsckOptions { temp_min_age: std::time::Duration::ZERO, ..Default::default() };
+    assert_eq!(db.fsck(opts.clone()).await.unwrap().stray_upload_files, ["leftover"]);
+    db.fsck(FsckOptions { repair: true, ..opts.clone() }).await.unwrap();
+    assert!(db.fsck(opts).await.unwrap().is_clean());
+}
+
+#[tokio::test]
+async fn blobs_stream_whole_or_in_ranges() {
+    use std::sync::Arc;
+    use store::{MemBlobStore, StoreError};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let pdf = text_pdf(&["one", "two", "three"]);
+    let fs_db = DocStore::open(tmp.path().join("fs")).unwrap();
+    let mem_db = DocStore::open_with_blobs(tmp.path().join("mem"), Arc::new(MemBlobStore::new())).unwrap();
+    for db in [fs_db, mem_db] {
+        let meta = db.store_stream(&pdf[..], "three.pdf", "application/pdf").await.unwrap();
+        let read = |range: std::ops::Range<u64>| {
+            let (db, meta) = (db.clone(), meta.clone());
+            async move {
+                let mut out = Vec::new();
+                db.stream_blob(&meta, range).await.unwrap().read_to_end(&mut out).await.unwrap();
+                out
+            }
+        };
+        assert_eq!(read(0..meta.size_bytes).await, pdf);
+        assert_eq!(read(5..105).await, &pdf[5..105]);
+        assert!(read(7..7).await.is_empty());
+        let end = meta.size_bytes;
+        assert_eq!(read(end - 6..end).await, &pdf[pdf.len() - 6..]);
+        let err = db.stream_blob(&meta, 0..end + 1).await.unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(StoreError::InvalidQuery(_))));
+    }
+}
+
+#[tokio::test]
+async fn uploads_are_checked_against_client_digests() {
+    use sha2::{Digest, Sha256};
+    use store::{DocInput, StoreError};
+
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).unwrap();
+    let pdf = text_pdf(&["survey"]);
+    let sha256: [u8; 32] = Sha256::digest(&pdf).into();
+
+    let wrong = DocInput { expected_sha256: Some([7; 32]), ..DocInput::new("survey.pdf", "application/pdf") };
+    let err = db.store(&pdf[..], wrong).await.unwrap_err();
+    let mismatch = StoreError::DigestMismatch { expected: hex::encode([7; 32]), actual: hex::encode(sha256) };
+    assert_eq!(err.downcast_ref(), Some(&mismatch));
+    assert!(db.list().await.unwrap().is_empty());
+    assert!(fs::read_dir(tmp.path().join("db/tmp")).unwrap().next().is_none());
+
+    let right = DocInput { expected_sha256: Some(sha256), ..DocInput::new("survey.pdf", "application/pdf") };
+    assert_eq!(db.store(&pdf[..], right).await.unwrap().sha256, sha256);
+
+    // a resumable upload is checked once it is complete
+    let upload = db.create_upload(pdf.len() as u64, Default::default(), Some([7; 32])).await.unwrap();
+    db.append_upload(&upload.id, 0, &pdf[..], None).await.unwrap();
+    let err = db.complete_upload(&upload.id).await.unwrap_err();
+    assert_eq!(err.downcast_ref(), Some(&mismatch));
+    assert!(db.upload(&upload.id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn blobs_are_sealed_at_rest_and_keks_rotate() {
+    use store::{Kek, ScrubOptions, StoreConfig};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let (old, new) = (Kek::from_bytes(&[1; 32]).unwrap(), Kek::from_bytes(hex::encode([2; 32]).as_bytes()).unwrap());
+    let open = |kek: &Kek| DocStore::open_with_config(&root, StoreConfig { kek: Some(kek.clone()), ..Default::default() });
+    // several 64 KiB chunks
+    let pdf = text_pdf(&[&"sealed ".repeat(30_000)]);
+    assert!(pdf.len() > 3 * 64 * 1024);
+
+    let db = open(&old).unwrap();
+    let meta = db.store_stream(&pdf[..], "sealed.pdf", "application/pdf").await.unwrap();
+    let key = store::blob_key(&meta.sha256);
+    let stored = db.blobs().get(&key).unwrap().unwrap();
+    assert!(stored.len() > pdf.len());
+    assert!(!stored.windows(14).any(|w| w == b"sealed sealed "));
+    assert_eq!(db.read_blob(&meta).await.unwrap(), pdf);
+    for range in [65_530..65_550, 0..1, 131_072..131_072 + 65_536, meta.size_bytes - 3..meta.size_bytes] {
+        let mut out = Vec::new();
+        db.stream_blob(&meta, range.clone()).await.unwrap().read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, &pdf[range.start as usize..range.end as usize]);
+    }
+    assert!(db.check().await.unwrap().is_clean());
+
+    let report = db.rotate_kek(new.clone()).await.unwrap();
+    assert_eq!((report.rewrapped, report.current), (1, 0));
+    assert_eq!(db.rotate_kek(new.clone()).await.unwrap().current, 1);
+    drop(db);
+
+    let db = open(&old).unwrap();
+    assert!(db.read_blob(&meta).await.is_err());
+    drop(db);
+    let db = open(&new).unwrap();
+    assert_eq!(db.read_blob(&meta).await.unwrap(), pdf);
+
+    // a flipped ciphertext bit fails authentication and is quarantined
+    let mut tampered = stored.clone();
+    tampered[70_000] ^= 1;
+    db.blobs().put(&key, &mut &tampered[..]).unwrap();
+    let mut out = Vec::new();
+    let mut reader = db.stream_blob(&meta, 0..meta.size_bytes).await.unwrap();
+    assert!(reader.read_to_end(&mut out).await.is_err());
+    let report = db.scrub(ScrubOptions { refetch: false, ..Default::default() }).await.unwrap();
+    assert_eq!(report.corrupt, vec![hex::encode(meta.sha256)]);
+}
+
+#[tokio::test]
+async fn blobs_compress_at_rest_and_recompress() {
+    use sha2::Digest;
+    use store::{Codec, Kek, ScrubOptions, StoreConfig};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let raw_pdf = text_pdf(&[&"raw ".repeat(40_000)]);
+    let packed_pdf = text_pdf(&[&"packed ".repeat(40_000)]);
+
+    // a store that predates compression
+    let db = DocStore::open(&root).unwrap();
+    let raw = db.store_stream(&raw_pdf[..], "raw.pdf", "application/pdf").await.unwrap();
+    let raw_key = store::blob_key(&raw.sha256);
+    assert_eq!(db.blobs().get(&raw_key).unwrap().unwrap(), raw_pdf);
+    drop(db);
+
+    let config = StoreConfig { compression: Codec::Zstd, kek: Some(Kek::from_bytes(&[3; 32]).unwrap()), ..Default::default() };
+    let db = DocStore::open_with_config(&root, config).unwrap();
+    let packed = db.store_stream(&packed_pdf[..], "packed.pdf", "application/pdf").await.unwrap();
+    // still addressed by the sha256 of the original bytes
+    assert_eq!(packed.sha256, <[u8; 32]>::from(sha2::Sha256::digest(&packed_pdf)));
+    let blob = db.get_blob(&packed.sha256).await.unwrap().unwrap();
+    assert_eq!(blob.encoding.codec, Codec::Zstd);
+    let stored = db.blobs().get(&store::blob_key(&packed.sha256)).unwrap().unwrap();
+    assert!((stored.len() as u64) < packed.size_bytes / 10);
+
+    // old and new blobs read back side by side, whole or in ranges
+    assert_eq!(db.read_blob(&raw).await.unwrap(), raw_pdf);
+    assert_eq!(db.read_blob(&packed).await.unwrap(), packed_pdf);
+    for range in [0..1, 70_000..140_000, packed.size_bytes - 5..packed.size_bytes] {
+        let mut out = Vec::new();
+        db.stream_blob(&packed, range.clone()).await.unwrap().read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, &packed_pdf[range.start as usize..range.end as usize]);
+    }
+    assert!(db.check().await.unwrap().is_clean());
+
+    let report = db.recompress(Codec::Zstd).await.unwrap();
+    assert_eq!((report.blobs_checked, report.recompressed, report.unchanged), (2, 1, 1));
+    assert!(report.errors.is_empty());
+    assert_eq!(report.bytes_before, raw_pdf.len() as u64);
+    assert!(report.bytes_saved() > raw_pdf.len() as i64 / 2);
+    assert!(db.blobs().get(&raw_key).unwrap().unwrap().len() < raw_pdf.len() / 10);
+    assert_eq!(db.read_blob(&raw).await.unwrap(), raw_pdf);
+    assert_eq!(db.recompress(Codec::Zstd).await.unwrap().unchanged, 2);
+
+    let scrub = db.scrub(ScrubOptions { refetch: false, ..Default::default() }).await.unwrap();
+    assert!(scrub.corrupt.is_empty() && scrub.errors.is_empty());
+    assert!(db.check().await.unwrap().is_clean());
+
+    // and back again
+    let report = db.recompress(Codec::None).await.unwrap();
+    assert_eq!(report.recompressed, 2);
+    assert!(report.bytes_saved() < 0);
+    assert_eq!(db.blobs().get(&raw_key).unwrap().unwrap(), raw_pdf);
+    assert_eq!(db.read_blob(&packed).await.unwrap(), packed_pdf);
+
+    // bytes compressed by a recompress that crashed before its index update
+    db.blobs().put(&raw_key, &mut &zstd::encode_all(&raw_pdf[..], 3).unwrap()[..]).unwrap();
+    assert_eq!(db.check().await.unwrap().size_mismatches.len(), 1);
+    assert!(db.repair().await.unwrap().unrepaired.is_empty());
+    assert_eq!(db.get_blob(&raw.sha256).await.unwrap().unwrap().encoding.codec, Codec::Zstd);
+    assert_eq!(db.read_blob(&raw).await.unwrap(), raw_pdf);
+    assert!(db.check().await.unwrap().is_clean());
+}
+
+/// `words` pseudo-random words, the same for the same `seed`.
+fn prose(seed: u64, words: usize) -> String {
+    let mut x = seed;
+    (0..words)
+        .map(|_| {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ["parcel", "deed", "survey", "lien", "title", "boundary", "easement", "escrow"][(x >> 61) as usize].to_string()
+                + &((x >> 40) % 1000).to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[tokio::test]
+async fn chunked_layout_dedups_across_versions_and_documents() {
+    use store::{DocInput, ScrubOptions, StorageLayout, StoreConfig};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let config = StoreConfig { layout: StorageLayout::Chunked, ..Default::default() };
+    let db = DocStore::open_with_config(tmp.path().join("db"), config).unwrap();
+    let text = prose(7, 120_000);
+    let v1_pdf = text_pdf(&[&text]);
+    // a revision that changes one word in the middle
+    let mid = text.len() / 2 + text[text.len() / 2..].find(' ').unwrap();
+    let v2_pdf = text_pdf(&[&format!("{} amended{}", &text[..mid], &text[mid..])]);
+    assert!(v1_pdf != v2_pdf);
+    assert!(v1_pdf.len() > 1_000_000);
+
+    let v1 = db.store(&v1_pdf[..], DocInput::new("title.pdf", "application/pdf")).await.unwrap();
+    let input = DocInput { supersedes: Some(v1.id_hex.clone()), ..DocInput::new("title.pdf", "application/pdf") };
+    let v2 = db.store(&v2_pdf[..], input).await.unwrap();
+    // a different document embedding most of the same content
+    let other_pdf = text_pdf(&[&text, "appendix"]);
+    let other = db.store(&other_pdf[..], DocInput::new("appendix.pdf", "application/pdf")).await.unwrap();
+
+    let manifest = db.blobs().get(&store::blob_key(&v1.sha256)).unwrap().unwrap();
+    assert!(manifest.len() < 4096);
+    for (meta, pdf) in [(&v1, &v1_pdf), (&v2, &v2_pdf), (&other, &other_pdf)] {
+        assert_eq!(&db.read_blob(meta).await.unwrap(), pdf);
+    }
+    for range in [0..1, 100_000..400_000, v2.size_bytes - 10..v2.size_bytes] {
+        let mut out = Vec::new();
+        db.stream_blob(&v2, range.clone()).await.unwrap().read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, &v2_pdf[range.start as usize..range.end as usize]);
+    }
+
+    let stats = db.stats().await.unwrap();
+    assert_eq!((stats.records, stats.blobs, stats.chunked_blobs), (3, 3, 3));
+    assert!(stats.chunk_dedup_ratio > 2.0, "{stats:?}");
+    assert!(stats.dedup_ratio > 2.0);
+    assert!(db.check().await.unwrap().is_clean());
+
+    // purging a version keeps the chunks the others still use
+    db.trash(&v1.id_hex, None, None).await.unwrap();
+    db.purge(&v1.id_hex).await.unwrap();
+    assert!(db.stats().await.unwrap().chunks < stats.chunks);
+    assert_eq!(db.read_blob(&v2).await.unwrap(), v2_pdf);
+    assert_eq!(db.read_blob(&other).await.unwrap(), other_pdf);
+    assert!(db.check().await.unwrap().is_clean());
+
+    // a damaged chunk fails its own sha256 and the blobs using it are quarantined
+    let chunk = db.blobs().list().unwrap().into_iter().find(|b| b.key.starts_with("chunks/")).unwrap().key;
+    db.blobs().put(&chunk, &mut &b"garbage"[..]).unwrap();
+    assert!(!db.check().await.unwrap().missing_chunks.is_empty());
+    let report = db.scrub(ScrubOptions { refetch: false, ..Default::default() }).await.unwrap();
+    assert!(!report.corrupt.is_empty());
+}
+
+#[tokio::test]
+async fn range_reads_verify_against_the_merkle_root() {
+    use store::{merkle, verify_range, DocInput, MerkleProof, MERKLE_CHUNK_BYTES as CHUNK};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let db = DocStore::open(tmp.path().join("db")).unwrap();
+    let pdf = text_pdf(&[&prose(3, 60_000)]);
+    assert!(pdf.len() as u64 > 4 * CHUNK && !(pdf.len() as u64).is_multiple_of(CHUNK));
+    let meta = db.store(&pdf[..], DocInput::new("deed.pdf", "application/pdf")).await.unwrap();
+    let root = meta.merkle_root.unwrap();
+    assert_eq!(root, merkle::root(&merkle::leaves(&pdf)));
+
+    let size = meta.size_bytes;
+    let tail = size / CHUNK * CHUNK;
+    for range in [0..CHUNK, CHUNK..3 * CHUNK, tail..size, 2 * CHUNK..size, 0..size] {
+        let mut bytes = Vec::new();
+        db.stream_blob(&meta, range.clone()).await.unwrap().read_to_end(&mut bytes).await.unwrap();
+        let proof = db.range_proof(&meta, range.clone()).await.unwrap().unwrap();
+        let proof = MerkleProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert!(verify_range(&root, range.start, &bytes, &proof), "{range:?}");
+        // wrong place, wrong bytes or a truncated read
+        assert!(!verify_range(&root, range.start + CHUNK, &bytes, &proof));
+        let mut tampered = bytes.clone();
+        tampered[bytes.len() / 2] ^= 1;
+        assert!(!verify_range(&root, range.start, &tampered, &proof));
+        assert!(!verify_range(&root, range.start, &bytes[..bytes.len() - 1], &proof));
+    }
+    // an unaligned range is proven by the whole chunks around it
+    let proof = db.range_proof(&meta, 100..CHUNK + 100).await.unwrap().unwrap();
+    assert!(!verify_range(&root, 100, &pdf[100..CHUNK as usize + 100], &proof));
+    assert!(verify_range(&root, 0, &pdf[..2 * CHUNK as usize], &proof));
+    // an offset that would wrap around is rejected, not a panic
+    assert!(!verify_range(&root, u64::MAX / CHUNK * CHUNK, &pdf[..2 * CHUNK as usize], &proof));
+
+    // the root survives a reindex and is shared by identical uploads
+    db.reindex().await.unwrap();
+    let again = db.store(&pdf[..], DocInput::new("copy.pdf", "application/pdf")).await.unwrap();
+    assert_eq!(again.merkle_root, Some(root));
+    assert_eq!(db.get_by_hex(&meta.id_hex).await.unwrap().unwrap().merkle_root, Some(root));
+}
+
+#[tokio::test]
+async fn blake3_ids_and_bao_streams_verify_and_backfill() {
+    use std::io::Read;
+    use store::{bao, BaoDecoder, ContentId, DocInput, Kek, StoreConfig};
+    use tokio::io::AsyncReadExt;
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    // sealed blobs get sealed outboards
+    let config = || StoreConfig { kek: Some(Kek::from_bytes(&[5; 32]).unwrap()), ..Default::default() };
+    let db = DocStore::open_with_config(&root, config()).unwrap();
+    let pdf = text_pdf(&[&prose(11, 20_000)]);
+    let meta = db.store(&pdf[..], DocInput::new("plan.pdf", "application/pdf")).await.unwrap();
+    let blake3 = *blake3::hash(&pdf).as_bytes();
+    assert_eq!(meta.blake3, Some(blake3));
+
+    // either digest finds the document
+    for id in [ContentId::Blake3(blake3), ContentId::Sha256(meta.sha256)] {
+        assert_eq!(id.to_string().parse::<ContentId>().unwrap(), id);
+        assert_eq!(db.get_by_hex(&id.to_string()).await.unwrap().unwrap().id_hex, meta.id_hex);
+    }
+    assert!(ContentId::Blake3(blake3).to_string().starts_with("1e20"));
+    assert!(db.get_by_hex(&ContentId::Blake3([9; 32]).to_string()).await.unwrap().is_none());
+
+    let mut encoded = Vec::new();
+    db.stream_bao(&meta).await.unwrap().unwrap().read_to_end(&mut encoded).await.unwrap();
+    assert_eq!(encoded.len() as u64, bao::encoded_len(pdf.len() as u64));
+    let mut decoded = Vec::new();
+    BaoDecoder::new(&encoded[..], &blake3).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, pdf);
+    // a flipped bit is caught at the chunk holding it
+    let mid = encoded.len() / 2;
+    encoded[mid] ^= 1;
+    let mut decoder = BaoDecoder::new(&encoded[..], &blake3);
+    let err = decoder.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    // fsck notices a lost outboard and repair rebuilds it
+    db.blobs().delete(&bao::bao_key(&meta.sha256)).unwrap();
+    assert_eq!(db.check().await.unwrap().missing_outboards, [hex::encode(meta.sha256)]);
+    db.repair().await.unwrap();
+    assert!(db.check().await.unwrap().is_clean());
+
+    // a store from before BLAKE3 has neither digest nor outboard until backfilled
+    db.blobs().delete(&bao::bao_key(&meta.sha256)).unwrap();
+    drop(db);
+    let kv = sled::open(root.join("kv")).unwrap();
+    let blobs = kv.open_tree("blobs").unwrap();
+    let mut blob: serde_json::Value = serde_json::from_slice(&blobs.get(meta.sha256).unwrap().unwrap()).unwrap();
+    blob.as_object_mut().unwrap().remove("blake3");
+    blobs.insert(meta.sha256, serde_json::to_vec(&blob).unwrap()).unwrap();
+    kv.drop_tree("idx_blake3").unwrap();
+    drop((blobs, kv));
+    let db = DocStore::open_with_config(&root, config()).unwrap();
+    let old = db.get_by_hex(&meta.id_hex).await.unwrap().unwrap();
+    assert_eq!(old.blake3, None);
+    assert!(db.stream_bao(&old).await.unwrap().is_none());
+    assert!(db.check().await.unwrap().is_clean());
+    let report = db.backfill().await.unwrap();
+    assert_eq!((report.blobs_checked, report.backfilled, report.errors.len()), (1, 1, 0));
+    assert_eq!(db.backfill().await.unwrap().backfilled, 0);
+    let found = db.get_by_hex(&ContentId::Blake3(blake3).to_string()).await.unwrap().unwrap();
+    assert_eq!(found.blake3, Some(blake3));
+    let mut encoded = Vec::new();
+    db.stream_bao(&found).await.unwrap().unwrap().read_to_end(&mut encoded).await.unwrap();
+    let mut decoded = Vec::new();
+    BaoDecoder::new(&encoded[..], &blake3).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, pdf);
+
+    // content ids reach the record for trash, restore, anchor and purge too
+    let (by_blake3, by_sha256) = (ContentId::Blake3(blake3).to_string(), ContentId::Sha256(meta.sha256).to_string());
+    assert!(db.trash(&by_blake3, None, None).await.unwrap());
+    assert!(db.get_by_hex(&meta.id_hex).await.unwrap().unwrap().deleted.is_some());
+    assert_eq!(db.restore(&hex::encode(meta.sha256)).await.unwrap().unwrap().id_hex, meta.id_hex);
+    assert_eq!(db.set_anchor(&by_sha256, "0xabc").await.unwrap().unwrap().anchor.as_deref(), Some("0xabc"));
+    assert!(db.trash(&by_sha256, None, None).await.unwrap());
+    assert!(db.purge(&by_blake3).await.unwrap());
+    assert!(db.get_by_hex(&meta.id_hex).await.unwrap().is_none());
+    assert!(!db.trash(&by_blake3, None, None).await.unwrap());
+}
+
+#[tokio::test]
+async fn tenants_keep_records_and_blobs_apart() {
+    use store::{DocInput, StoreConfig, StoreError, Tenants};
+
+    let tmp = TempDir::new().unwrap();
+    let root = tmp.path().join("db");
+    let tenants = Tenants::open(&root, StoreConfig::default()).unwrap();
+    let (staging, production) = (tenants.store("staging").unwrap(), tenants.store("production").unwrap());
+    assert_eq!(staging.tenant(), Some("staging"));
+    let pdf = text_pdf(&["the same deed in both"]);
+    let a = staging.store(&pdf[..], DocInput::new("deed.pdf", "application/pdf")).await.unwrap();
+
+    // ids, digests and listings only resolve in the tenant that stored them
+    for id in [a.id_hex.clone(), hex::encode(a.sha256)] {
+        assert!(production.get_by_hex(&id).await.unwrap().is_none());
+    }
+    assert!(production.list().await.unwrap().is_empty());
+    let b = production.store(&pdf[..], DocInput::new("deed.pdf", "application/pdf")).await.unwrap();
+    assert_ne!(a.id_hex, b.id_hex);
+    assert_eq!(production.get_by_hex(&hex::encode(a.sha256)).await.unwrap().unwrap().id_hex, b.id_hex);
+
+    // each keeps its own copy under its prefix, so purging one leaves the other
+    let key = store::blob_key(&a.sha256);
+    assert!(root.join("blobs/tenants/staging").join(&key).is_file());
+    assert!(staging.trash(&a.id_hex, None, None).await.unwrap());
+    assert!(staging.purge(&a.id_hex).await.unwrap());
+    assert!(!staging.blobs().exists(&key).unwrap());
+    assert_eq!(production.read_blob(&b).await.unwrap(), pdf);
+    assert!(staging.check().await.unwrap().is_clean());
+    assert!(production.check().await.unwrap().is_clean());
+
+    assert!(matches!(
+        tenants.store("../prod").unwrap_err().downcast_ref::<StoreError>(),
+        Some(StoreError::InvalidTenant { .. })
+    ));
+
+    // the unscoped store at the same root neither sees nor repairs tenant blobs
+    drop((staging, production, tenants));
+    let unscoped = DocStore::open(&root).unwrap();
+    assert!(unscoped.list().await.unwrap().is_empty());
+    let report = unscoped.repair().await.unwrap();
+    assert!(report.orphan_blobs.is_empty(), "{report:?}");
+    drop(unscoped);
+    let production = Tenants::open(&root, StoreConfig::default()).unwrap().store("production").unwrap();
+    assert_eq!(production.read_blob(&b).await.unwrap(), pdf);
+}
+
+/// Answer every `ipfs add` with `cid`, keeping the request bodies.
+async fn mock_ipfs_add(cid: &'static str) -> (String, std::sync::Arc<std::sync::Mutex<Vec<Vec<u8>>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let bodies = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let seen = bodies.clone();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let mut req = Vec::new();
+            let mut buf = [0u8; 8192];
+            let head_end = loop {
+                if let Some(i) = req.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+                let n = conn.read(&mut buf).await.unwrap();
+                req.extend_from_slice(&buf[..n]);
+            };
+            let head = String::from_utf8_lossy(&req[..head_end]).to_ascii_lowercase();
+            let len: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length:"))
+                .expect("multipart upload with a length")
+                .trim()
+                .parse()
+                .unwrap();
+            while req.len() < head_end + len {
+                let n = conn.read(&mut buf).await.unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+            seen.lock().unwrap().push(req[head_end..].to_vec());
+            let body = format!(r#"{{"Hash":"{cid}"}}"#);
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            conn.write_all(head.as_bytes()).await.unwrap();
+            conn.write_all(body.as_bytes()).await.unwrap();
+        }
+    });
+    (format!("http://{addr}"), bodies)
+}
+
+#[tokio::test]
+async fn encrypted_pins_keep_plaintext_off_ipfs() {
+    use store::{DocInput, DocQuery, Kek, ScrubOptions, StoreConfig};
+
+    let tmp = TempDir::new().unwrap();
+    let no_kek = StoreConfig { encrypt_pins: true, ..Default::default() };
+    assert!(DocStore::open_with_config(tmp.path().join("no-kek"), no_kek).is_err());
+    let config = StoreConfig { kek: Some(Kek::from_bytes(&[3; 32]).unwrap()), encrypt_pins: true, ..Default::default() };
+    let db = DocStore::open_with_config(tmp.path().join("db"), config).unwrap();
+    let (url, added) = mock_ipfs_add("bafy-sealed").await;
+    let pdf = text_pdf(&["private deed"]);
+    let deed = || DocInput::new("deed.pdf", "application/pdf");
+
+    let meta = db.store_with_ipfs(&pdf[..], deed(), Some(&url)).await.unwrap();
+    assert_eq!((meta.cid.as_deref(), meta.encrypted_cid.as_deref()), (None, Some("bafy-sealed")));
+    let key = store::blob_key(&meta.sha256);
+    let sealed = db.blobs().get(&key).unwrap().unwrap();
+    let pinned = added.lock().unwrap().clone();
+    assert_eq!(pinned.len(), 1);
+    assert!(pinned[0].windows(sealed.len()).any(|w| w == sealed));
+    assert!(!pinned[0].windows(5).any(|w| w == b"%PDF-"));
+
+    // the same content is not pinned again, and either record is found by the CID
+    let again = db.store_with_ipfs(&pdf[..], deed(), Some(&url)).await.unwrap();
+    assert_eq!(again.encrypted_cid.as_deref(), Some("bafy-sealed"));
+    assert_eq!(added.lock().unwrap().len(), 1);
+    let query = DocQuery { cid: Some("bafy-sealed".into()), ..Default::default() };
+    assert_eq!(db.query(query).await.unwrap().documents.len(), 2);
+
+    let ipfs = mock_ipfs(sealed.clone()).await;
+    assert_eq!(db.fetch_from_ipfs(&meta, Some(&ipfs)).await.unwrap(), pdf);
+    let mut tampered = sealed.clone();
+    tampered[40] ^= 1;
+    assert!(db.fetch_from_ipfs(&meta, Some(&mock_ipfs(tampered).await)).await.is_err());
+
+    db.blobs().put(&key, &mut &b"bit rot"[..]).unwrap();
+    let report = db.scrub(ScrubOptions { ipfs_url: Some(ipfs), ..Default::default() }).await.unwrap();
+    assert_eq!(report.restored, [hex::encode(meta.sha256)]);
+    assert_eq!(db.read_blob(&meta).await.unwrap(), pdf);
+}
+
+#[tokio::test]
+async fn reuploading_a_quarantined_sealed_blob_keeps_its_data_key() {
+    use tokio::io::AsyncReadExt;
+    use store::{BaoDecoder, DocInput, Kek, ScrubOptions, StoreConfig};
+
+    let tmp = TempDir::new().unwrap();
+    let config = StoreConfig { kek: Some(Kek::from_bytes(&[4; 32]).unwrap()), encrypt_pins: true, ..Default::default() };
+    let db = DocStore::open_with_config(tmp.path().join("db"), config).unwrap();
+    let (url, _) = mock_ipfs_add("bafy-sealed").await;
+    let pdf = text_pdf(&[&prose(5, 2_000)]);
+    let deed = || DocInput::new("deed.pdf", "application/pdf");
+    let meta = db.store_with_ipfs(&pdf[..], deed(), Some(&url)).await.unwrap();
+    let key = store::blob_key(&meta.sha256);
+    let pinned = db.blobs().get(&key).unwrap().unwrap();
+
+    db.blobs().put(&key, &mut &b"bit rot"[..]).unwrap();
+    let report = db.scrub(ScrubOptions { refetch: false, ..Default::default() }).await.unwrap();
+    assert_eq!(report.unrecoverable, [hex::encode(meta.sha256)]);
+
+    // the re-upload is sealed under the blob's data key again, so the
+    // outboard and the pin kept from before still open
+    db.store_with_ipfs(&pdf[..], deed(), Some(&url)).await.unwrap();
+    let meta = db.get_by_hex(&meta.id_hex).await.unwrap().unwrap();
+    assert!(meta.quarantined_at_unix_ms.is_none());
+    assert_eq!(db.read_blob(&meta).await.unwrap(), pdf);
+    let mut encoded = Vec::new();
+    db.stream_bao(&meta).await.unwrap().unwrap().read_to_end(&mut encoded).await.unwrap();
+    let mut decoded = Vec::new();
+    std::io::Read::read_to_end(&mut BaoDecoder::new(&encoded[..], &meta.blake3.unwrap()), &mut decoded).unwrap();
+    assert_eq!(decoded, pdf);
+    assert_eq!(meta.encrypted_cid.as_deref(), Some("bafy-sealed"));
+    assert_eq!(db.fetch_from_ipfs(&meta, Some(&mock_ipfs(pinned).await)).await.unwrap(), pdf);
+    assert!(db.check().await.unwrap().is_clean());
+}
@@ -5,55 +5,124 @@ use std::io::Write;
 use std::process::Command;
 use tempfile::TempDir;
 
-fn write_fake_pdf(dir: &TempDir, name: &str, body: &[u8]) -> std::path::PathBuf {
+/// Minimal well-formed PDF: one blank page, with `body` as its title.
+fn write_pdf(dir: &TempDir, name: &str, body: &str) -> std::path::PathBuf {
+    use lopdf::{dictionary, Document, Object};
+
+    let mut doc = Document::with_version("1.4");
+    let pages_id = doc.new_object_id();
+    let page_id = doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id });
+    doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+        "Type" => "Pages", "Kids" => vec![page_id.into()], "Count" => 1, "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+    }));
+    let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+    let info_id = doc.add_object(dictionary! { "Title" => Object::string_literal(body) });
+    doc.trailer.set("Root", catalog_id);
+    doc.trailer.set("Info", info_id);
+
     let path = dir.path().join(name);
     let mut f = fs::File::create(&path).unwrap();
-    f.write_all(b"%PDF-1.4\n%").unwrap();
-    f.write_all(body).unwrap();
+    doc.save_to(&mut f).unwrap();
     f.flush().unwrap();
     path
 }
 
+/// `store` always pins to IPFS and publishes on chain. Run with
+/// `cargo test -- --ignored cli_store` against a local IPFS daemon and dev node.
 #[test]
-fn cli_store_get_list_delete() {
+#[ignore = "requires IPFS on 127.0.0.1:5001 and a node on ws://localhost:9944"]
+fn cli_store_prints_the_record_id() {
     let tmp = TempDir::new().unwrap();
     let db_dir = tmp.path().join("db");
-    let pdf = write_fake_pdf(&tmp, "doc.pdf", b"CLI test");
+    let pdf = write_pdf(&tmp, "doc.pdf", "CLI test");
 
-    // store
     let output = Command::new(assert_cmd::cargo::cargo_bin!("store-cli"))
         .args(["--db", db_dir.to_str().unwrap(), "store", pdf.to_str().unwrap()])
         .output()
         .unwrap();
     assert!(output.status.success());
     let id = String::from_utf8(output.stdout).unwrap();
-    let id = id.trim();
-    assert_eq!(id.len(), 64);
+    assert_eq!(id.trim().len(), 64);
+}
+
+#[test]
+fn cli_get_list_delete() {
+    let tmp = TempDir::new().unwrap();
+    let db_dir = tmp.path().join("db");
+    let pdf = write_pdf(&tmp, "doc.pdf", "CLI test");
+
+    // stored through the library, which needs no IPFS; the store is closed
+    // again before the CLI opens it
+    let id = tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let db = store::DocStore::open(&db_dir).unwrap();
+        db.store_pdf(&pdf, None).await.unwrap().id_hex
+    });
 
     // get
     Command::new(assert_cmd::cargo::cargo_bin!("store-cli"))
-        .args(["--db", db_dir.to_str().unwrap(), "get", id])
+        .args(["--db", db_dir.to_str().unwrap(), "get", &id])
         .assert()
         .success()
-        .stdout(predicate::str::contains(id));
+        .stdout(predicate::str::contains(&id));
 
     // list
     Command::new(assert_cmd::cargo::cargo_bin!("store-cli"))
-        .args(["--db", db_dir.to_str().unwrap(), "list"]) 
+        .args(["--db", db_dir.to_str().unwrap(), "list"])
         .assert()
         .success()
-        .stdout(predicate::str::contains(id));
+        .stdout(predicate::str::contains(&id));
 
     // delete
     Command::new(assert_cmd::cargo::cargo_bin!("store-cli"))
-        .args(["--db", db_dir.to_str().unwrap(), "delete", id])
+        .args(["--db", db_dir.to_str().unwrap(), "delete", &id])
         .assert()
         .success()
         .stdout(predicate::str::contains("deleted"));
 
     // list empty
     Command::new(assert_cmd::cargo::cargo_bin!("store-cli"))
-        .args(["--db", db_dir.to_str().unwrap(), "list"]) 
+        .args(["--db", db_dir.to_str().unwrap(), "list"])
         .assert()
-        .success();
+        .success()
+        .stdout(predicate::str::contains(&id).not());
+}
+
+#[cfg(feature = "server")]
+/// Status code of `GET path` against a server on localhost:`port`.
+fn get_status(port: u16, path: &str) -> std::io::Result<u16> {
+    use std::io::Read;
+
+    let mut conn = std::net::TcpStream::connect(("127.0.0.1", port))?;
+    write!(conn, "GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    conn.read_to_string(&mut response)?;
+    Ok(response.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or(0))
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn server_answers_404_for_unknown_documents() {
+    let tmp = TempDir::new().unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut server = Command::new(assert_cmd::cargo::cargo_bin!("store-server"))
+        .args([tmp.path().join("db").to_str().unwrap(), &port.to_string()])
+        .env("PURGE_INTERVAL_SECS", "0")
+        .env("SCRUB_INTERVAL_SECS", "0")
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let started = std::time::Instant::now();
+    while get_status(port, "/health").is_err() {
+        assert!(started.elapsed().as_secs() < 30, "server did not start");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    let id = "00".repeat(32);
+    let statuses: Vec<_> = ["download", "bao", "export"]
+        .iter()
+        .map(|route| get_status(port, &format!("/api/docs/{id}/{route}")).unwrap())
+        .collect();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    assert_eq!(statuses, [404, 404, 404]);
 }